@@ -1,275 +1,3133 @@
 use std::{
-    cmp,
-    collections::{BTreeMap, BTreeSet, HashMap},
-
+    borrow::Cow,
+    cmp::{self, Ordering},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt,
+    io::{BufRead, Write},
+    sync::Mutex,
 };
 
 use dna::Location;
-use crate::loctogene::{GenesResult, GenomicFeature, Level, LoctogeneDb, TSSRegion};
-use serde::Serialize;
+use crate::loctogene::{
+    GenesError, GenesResult, GenomicFeature, Level, LoctogeneDb, Strand, TSSRegion,
+};
+use crate::loctogene::LocationKey;
+use serde::{Serialize, Serializer};
 
 
 
 pub const NA: &str = "n/a";
 pub const PROMOTER: &str = "promoter";
 pub const EXONIC: &str = "exonic";
+pub const FIVE_PRIME_UTR: &str = "5'utr";
+pub const CDS: &str = "cds";
+pub const THREE_PRIME_UTR: &str = "3'utr";
 pub const INTRONIC: &str = "intronic";
 pub const INTERGENIC: &str = "intergenic";
+pub const UPSTREAM: &str = "upstream";
+pub const DOWNSTREAM: &str = "downstream";
+pub const OVERLAPPING: &str = "overlapping";
+
+// How many locations make_gene_table annotates at once before writing
+// their rows
+#[cfg(feature = "table")]
+const TABLE_BATCH_SIZE: usize = 1024;
+
+// Biotypes of genes that code for proteins
+pub const CODING_BIOTYPES: [&str; 9] = [
+    "protein_coding",
+    "IG_C_gene",
+    "IG_D_gene",
+    "IG_J_gene",
+    "IG_V_gene",
+    "TR_C_gene",
+    "TR_D_gene",
+    "TR_J_gene",
+    "TR_V_gene",
+];
+
+// Biotypes of functional non-coding RNAs, i.e. excluding pseudogenes
+pub const NONCODING_BIOTYPES: [&str; 20] = [
+    "lncRNA",
+    "lincRNA",
+    "antisense",
+    "bidirectional_promoter_lncRNA",
+    "macro_lncRNA",
+    "sense_intronic",
+    "sense_overlapping",
+    "3prime_overlapping_ncRNA",
+    "processed_transcript",
+    "non_coding",
+    "miRNA",
+    "misc_RNA",
+    "rRNA",
+    "ribozyme",
+    "scaRNA",
+    "snRNA",
+    "snoRNA",
+    "sRNA",
+    "vaultRNA",
+    "Mt_rRNA",
+];
 
 //const ERROR_FEATURES:Features= Features{location: dna::EMPTY_STRING, level: dna::EMPTY_STRING, features: [].to_vec()};
 
-#[derive(Serialize, Clone)]
+// The parts of the genome relative to a gene that a location can be in.
+// Exonic locations are split into the UTRs and CDS when the annotator knows
+// the coding features of genes, see Annotate::with_coding_level.
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RegionClass {
+    Promoter,
+    Exonic,
+    FivePrimeUtr,
+    Cds,
+    ThreePrimeUtr,
+    Intronic,
+    Intergenic,
+}
+
+impl RegionClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RegionClass::Promoter => PROMOTER,
+            RegionClass::Exonic => EXONIC,
+            RegionClass::FivePrimeUtr => FIVE_PRIME_UTR,
+            RegionClass::Cds => CDS,
+            RegionClass::ThreePrimeUtr => THREE_PRIME_UTR,
+            RegionClass::Intronic => INTRONIC,
+            RegionClass::Intergenic => INTERGENIC,
+        }
+    }
+}
+
+impl fmt::Display for RegionClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// How a location relates to a gene, e.g. promoter and exonic, followed by
+// the labels of any classifiers. It displays and serializes as the comma
+// separated label used in tables, e.g. promoter,exonic,cpg_island, and
+// compares equal to that string.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct PromoterLabel {
+    pub classes: Vec<RegionClass>,
+    pub custom_labels: Vec<String>,
+}
+
+impl PromoterLabel {
+    pub fn new(classes: Vec<RegionClass>, custom_labels: Vec<String>) -> Self {
+        PromoterLabel {
+            classes,
+            custom_labels,
+        }
+    }
+
+    pub fn contains(&self, class: RegionClass) -> bool {
+        self.classes.contains(&class)
+    }
+
+    // The classes and then the custom labels
+    pub fn parts(&self) -> impl Iterator<Item = &str> {
+        self
+            .classes
+            .iter()
+            .map(|c| c.as_str())
+            .chain(self.custom_labels.iter().map(|label| label.as_str()))
+    }
+
+    // The label with its parts joined by separator rather than a comma
+    pub fn join(&self, separator: &str) -> String {
+        let labels: Vec<&str> = self.parts().collect();
+
+        labels.join(separator)
+    }
+}
+
+impl fmt::Display for PromoterLabel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.join(","))
+    }
+}
+
+impl Serialize for PromoterLabel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl PartialEq<&str> for PromoterLabel {
+    // compares part by part rather than building the joined label
+    fn eq(&self, other: &&str) -> bool {
+        if other.is_empty() {
+            return self.parts().next().is_none();
+        }
+
+        self.parts().eq(other.split(','))
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct ClosestGene {
     pub gene_id: String,
     pub gene_symbol: String,
-    pub prom_label: String,
+    pub strand: String,
+    pub prom_label: PromoterLabel,
+    // distance from the TSS to the location midpoint reading 5' to 3' along
+    // the gene's strand, so negative when the location is upstream of the
+    // TSS and positive when it is downstream, whichever strand the gene is on
     pub tss_dist: i32,
+    // fraction of the location's span inside the gene body and fraction of
+    // the gene body inside the location, using the whole interval rather
+    // than the midpoint, so a peak clipping the end of a gene has a small
+    // location_overlap_frac and one inside the gene has 1
+    pub location_overlap_frac: f64,
+    pub gene_overlap_frac: f64,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct GeneAnnotation {
     pub gene_ids: String,
     pub gene_symbols: String,
     pub prom_labels: String,
     pub tss_dists: String,
+    pub strands: String,
+    pub loci: String,
+    // the TSS distances without their sign and whether each TSS is
+    // upstream of, downstream of or overlapping the location
+    pub abs_tss_dists: String,
+    pub tss_directions: String,
+    // how many features of each gene, e.g. transcripts, the location is
+    // within, see GeneClassification::feature_count
+    pub feature_counts: String,
+    // the antisense flags of the genes joined in the same order, only
+    // present for locations annotated with a strand
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub antisense: Option<String>,
+    pub genes: Vec<GeneClassification>,
     pub closest_genes: Vec<ClosestGene>,
+    // whether the location is within any gene body or promoter
+    pub is_genic: bool,
+    // for an intergenic location, the TSS distance of the closest gene,
+    // signed as for ClosestGene::tss_dist, so how far away the nearest gene
+    // is even when no closest genes are reported. None if the location is
+    // genic or its chromosome has no genes.
+    pub nearest_distance: Option<i32>,
+    // The region searched for the genes a location is within, in database
+    // coordinates. This is the mid point of the location padded by the
+    // larger of the promoter offsets, or the whole location padded by it
+    // when annotating by interval overlap, see
+    // Annotate::with_interval_overlap.
+    pub search_window: String,
 }
 
-struct GeneProm {
-    is_promoter: bool,
-    is_intronic: bool,
-    is_exon: bool,
-    abs_d: i32,
-    d: i32,
+// How a location relates to one of the genes it falls within
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct GeneClassification {
+    pub gene_id: String,
+    pub gene_symbol: String,
+    pub strand: String,
+    // gene locus spanning all of its transcripts
+    pub start: u32,
+    pub end: u32,
+    pub is_promoter: bool,
+    pub is_exon: bool,
+    // for exonic locations, the UTR or CDS if the annotator has a coding
+    // level and exonic otherwise
+    pub exon_class: Option<RegionClass>,
+    pub is_intronic: bool,
+    // whether the location interval, rather than its midpoint, contains the
+    // TSS of any of the gene's transcripts, e.g. a peak starting in the
+    // promoter and ending in the gene body
+    pub overlaps_tss: bool,
+    // signed as for ClosestGene::tss_dist, see stranded_distance
+    pub tss_dist: i32,
+    // the TSS distance of the transcript furthest from the location, which
+    // differs from tss_dist for genes with several TSSs
+    pub max_tss_dist: i32,
+    // the TSS the distance is measured from, i.e. the stranded start of
+    // the closest transcript
+    pub tss_coord: u32,
+    // fraction of the gene body covered by the location, so 1 means the
+    // whole gene is covered and a promoter only overlap gives 0
+    pub gene_covered_frac: f64,
+    // for a midpoint inside the gene body, the nearest gene boundary minus
+    // the midpoint, so how deep into the gene the location is
+    pub body_edge_dist: Option<i32>,
+    // labels added by the classifiers of the annotator, see Classifier
+    pub custom_labels: Vec<String>,
+    // how many of the gene's transcripts place the location in their
+    // promoter, an exon or an intron
+    pub transcript_support: u32,
+    // how many of the gene's features, e.g. transcripts, overlap the
+    // location, so 1 for a gene hit by a single marginal transcript and 0
+    // for one only in range through its promoter
+    pub feature_count: u32,
+    // whether the gene is on the opposite strand to the location, if the
+    // location was annotated with a strand, see Annotate::annotate_stranded
+    pub antisense: Option<bool>,
 }
 
-pub struct Annotate {
-    genesdb:  LoctogeneDb,
-    tss_region: TSSRegion,
-    n: u16,
+// The per gene classification annotate builds its joined fields from, under
+// the name used by annotate_detailed
+pub type PromoterOverlap = GeneClassification;
+
+// Which parts of two annotations of the same location agree
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct AnnotationDiff {
+    pub genes: bool,
+    pub labels: bool,
+    pub tss_dists: bool,
 }
 
-impl Annotate {
-    pub fn new(genesdb: LoctogeneDb, tss_region: TSSRegion, n: u16) -> Self {
-        return Annotate {
-            genesdb,
-            tss_region,
-            n,
-        };
+impl AnnotationDiff {
+    pub fn agrees(&self) -> bool {
+        self.genes && self.labels && self.tss_dists
     }
+}
 
-    pub fn annotate(&self, location: &Location) -> GenesResult<GeneAnnotation> {
-        let mid: u32 = location.mid();
+// A one line summary for logs and command line output: the genes, symbols,
+// labels and TSS distances followed by how many closest genes there are,
+// e.g. G1;G2  GENE1;GENE2  promoter;intronic  120;-4300  closest=5. The
+// annotation does not hold its location, so prefix it where needed.
+impl fmt::Display for GeneAnnotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}  {}  {}  {}  closest={}",
+            self.gene_ids,
+            self.gene_symbols,
+            self.prom_labels,
+            self.tss_dists,
+            self.closest_genes.len()
+        )
+    }
+}
 
-        // extend search area to account  for promoter
-        // let search_loc: Location = Location::new(
-        //     &location.chr,
-        //     location.start - self.tss_region.offset_5p.abs(),
-        //     location.end + self.tss_region.offset_5p.abs(),
-        // )?;
+impl GeneAnnotation {
+    // The gene ids, symbols, labels and TSS distances one entry per gene,
+    // taken from genes rather than by splitting the joined fields, so they
+    // are neither truncated by the join limit nor broken up by symbols
+    // containing a semicolon
+    pub fn gene_ids_vec(&self) -> Vec<&str> {
+        self.genes.iter().map(|g| g.gene_id.as_str()).collect()
+    }
 
-        let genes_within: Vec<GenomicFeature> = self.genesdb.get_genes_within_promoter(
-            &location,
-            &Level::Transcript,
-            cmp::max(self.tss_region.offset_5p(), self.tss_region.offset_3p()),
-        )?;
+    pub fn gene_symbols_vec(&self) -> Vec<&str> {
+        self.genes.iter().map(|g| g.gene_symbol.as_str()).collect()
+    }
 
-        // we need the unique ids to symbols
-        let mut id_map: HashMap<String, String> = HashMap::new();
-        let mut promoter_map: HashMap<String, GeneProm> = HashMap::new();
-        //let mut dist_map: HashMap<&str, bool> = HashMap::new();
+    pub fn prom_labels_vec(&self) -> Vec<PromoterLabel> {
+        self
+            .genes
+            .iter()
+            .map(|g| make_label(g.is_promoter, g.exon_class, g.is_intronic, &g.custom_labels))
+            .collect()
+    }
 
-        for gene in genes_within.iter() {
-            let id = gene.gene_id.to_owned();
+    pub fn tss_dists_vec(&self) -> Vec<i32> {
+        self.genes.iter().map(|g| g.tss_dist).collect()
+    }
 
-            // println!(
-            //     "{} {} {} {} {}",
-            //     gene.gene_id, gene.gene_symbol, gene.start, gene.end, gene.strand
-            // );
+    // Compares this annotation to another, e.g. one from a reference tool
+    // or a golden file. Genes are compared as a set, and labels and TSS
+    // distances gene by gene, with distances allowed to differ by up to
+    // tolerance bp.
+    pub fn agrees_with(&self, other: &GeneAnnotation, tolerance: u32) -> AnnotationDiff {
+        let genes: HashMap<&str, (PromoterLabel, i32)> = self.gene_map();
+        let other_genes: HashMap<&str, (PromoterLabel, i32)> = other.gene_map();
 
-            id_map.insert(id.to_owned(), gene.gene_symbol.to_owned());
+        let same_genes: bool = genes.len() == other_genes.len()
+            && genes.keys().all(|id| other_genes.contains_key(id));
 
-            //let labels = self.classify_location(location, gene);
+        let mut labels: bool = same_genes;
+        let mut tss_dists: bool = same_genes;
 
-            let exons: Vec<GenomicFeature> = self.genesdb.in_exon(&location, &id)?;
+        if same_genes {
+            for (id, (label, d)) in genes.iter() {
+                let (other_label, other_d) = other_genes.get(id).unwrap();
 
-            let is_exon: bool = exons.len() > 0;
+                labels = labels && label == other_label;
+                tss_dists = tss_dists && d.abs_diff(*other_d) <= tolerance;
+            }
+        }
 
-            let is_promoter: bool = (gene.strand == "+"
-                && mid >= gene.start - self.tss_region.offset_5p()
-                && mid <= gene.start + self.tss_region.offset_3p())
-                || (gene.strand == "-"
-                    && mid >= gene.end - self.tss_region.offset_3p()
-                    && mid <= gene.end + self.tss_region.offset_5p());
+        AnnotationDiff {
+            genes: same_genes,
+            labels,
+            tss_dists,
+        }
+    }
 
-            let is_intronic = mid >= gene.start && mid <= gene.end;
+    // The cells make_gene_table writes for a location with this annotation
+    // using the default columns, i.e. those of an annotator without locus
+    // or boolean columns
+    pub fn to_row(&self, location: &Location, closest_n: u16) -> Vec<String> {
+        self.to_row_with_columns(
+            location,
+            closest_n,
+            false,
+            false,
+            false,
+            &Separators::default(),
+        )
+    }
 
-            let d: i32 = if gene.strand == "+" {
-                (gene.start as i32) - (mid as i32)
-            } else {
-                (gene.end as i32) - (mid as i32)
-            };
+    // Same as to_row, but with the optional columns of make_gene_table, see
+    // Annotate::with_locus_columns, Annotate::with_boolean_columns and
+    // Annotate::with_direction_columns, and the separators of the annotator
+    // for the labels of the closest genes
+    pub fn to_row_with_columns(
+        &self,
+        location: &Location,
+        closest_n: u16,
+        locus_columns: bool,
+        boolean_columns: bool,
+        direction_columns: bool,
+        separators: &Separators,
+    ) -> Vec<String> {
+        let mut row: Vec<String> = Vec::with_capacity(11 + 4 * closest_n as usize);
 
-            //println!("{} {} {}", gene.end - mid, gene.end, mid);
+        row.push(location.to_string());
+        row.push(self.gene_ids.to_owned());
+        row.push(self.gene_symbols.to_owned());
+        row.push(self.prom_labels.to_owned());
 
-            // update by inserting default case and then updating
-            promoter_map
-                .entry(id)
-                .and_modify(|v: &mut GeneProm| {
-                    v.is_intronic = v.is_intronic || is_intronic;
-                    v.is_promoter = v.is_promoter || is_promoter;
-                    v.is_exon = v.is_exon || exons.len() > 0;
+        if direction_columns {
+            row.push(self.abs_tss_dists.to_owned());
+            row.push(self.tss_directions.to_owned());
+        } else {
+            row.push(self.tss_dists.to_owned());
+        }
 
-                    let abs_d: i32 = d.abs();
+        if locus_columns {
+            row.push(self.strands.to_owned());
+            row.push(self.loci.to_owned());
+        }
 
-                    if abs_d < v.abs_d {
-                        v.d = d;
-                        v.abs_d = abs_d;
-                    }
-                })
-                .or_insert(GeneProm {
-                    is_promoter,
-                    is_intronic,
-                    is_exon,
-                    d,
-                    abs_d: d.abs(),
-                });
+        if boolean_columns {
+            let (is_promoter, is_exon, is_intronic): (bool, bool, bool) = match self.genes.first()
+            {
+                Some(gene) => (gene.is_promoter, gene.is_exon, gene.is_intronic),
+                None => (false, false, false),
+            };
+
+            row.push(is_promoter.to_string());
+            row.push(is_exon.to_string());
+            row.push(is_intronic.to_string());
         }
 
-        // sort the ids by distance
-        let mut dist_map: BTreeMap<i32, BTreeSet<String>> = BTreeMap::new();
+        for closest_gene in self.closest_genes.iter().take(closest_n as usize) {
+            row.push(closest_gene.gene_id.to_owned());
+            row.push(closest_gene.gene_symbol.to_owned());
+            row.push(closest_gene.prom_label.join(&separators.labels));
+            row.push(closest_gene.tss_dist.to_string());
 
-        for id in id_map.keys() {
-            dist_map
-                .entry(promoter_map.get(id).unwrap().abs_d)
-                .and_modify(|v| {
-                    v.insert(id.to_owned());
-                })
-                .or_insert({
-                    let mut dids: BTreeSet<String> = BTreeSet::new();
-                    dids.insert(id.to_owned());
-                    dids
-                });
+            if locus_columns {
+                row.push(closest_gene.strand.to_owned());
+            }
         }
 
-        // now put the ids in distance order
-        let mut ids: Vec<String> = Vec::with_capacity(id_map.len());
+        let closest_columns: usize = if locus_columns { 5 } else { 4 };
 
-        for d in dist_map.keys() {
-            for id in dist_map.get(d).unwrap() {
-                ids.push(id.to_string());
+        // small contigs may have fewer genes than requested, so pad the
+        // missing closest genes so every row matches the header
+        for _ in self.closest_genes.len()..(closest_n as usize) {
+            for _ in 0..closest_columns {
+                row.push(NA.to_owned());
             }
         }
 
-        println!("within d {}", ids.len());
+        row
+    }
 
-        // make a list of the symbols in distance order
-        let mut gene_symbols: Vec<String> = ids
+    // maps each gene id to its label and TSS distance, taken from genes
+    // rather than the joined fields, whose separators and truncation
+    // depend on the annotator
+    fn gene_map(&self) -> HashMap<&str, (PromoterLabel, i32)> {
+        self
+            .genes
             .iter()
-            .map(|id: &String| id_map.get(id).unwrap().to_owned())
-            .collect::<Vec<String>>();
+            .map(|g| {
+                let label: PromoterLabel =
+                    make_label(g.is_promoter, g.exon_class, g.is_intronic, &g.custom_labels);
 
-        let prom_labels: Vec<String> = ids
-            .iter()
-            .map(|id| {
-                let p = promoter_map.get(id).unwrap();
-                make_label(p.is_promoter, p.is_exon, p.is_intronic)
+                (g.gene_id.as_str(), (label, g.tss_dist))
             })
-            .collect::<Vec<String>>();
+            .collect()
+    }
+}
 
-        let mut tss_dists: Vec<String> = ids
-            .iter()
-            .map(|id| promoter_map.get(id).unwrap().d.to_string())
-            .collect::<Vec<String>>();
+// The annotation of a single transcript, i.e. before transcripts are
+// collapsed to genes
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct TranscriptAnnotation {
+    pub id: u32,
+    pub gene_id: String,
+    pub gene_symbol: String,
+    pub strand: String,
+    pub start: u32,
+    pub end: u32,
+    pub prom_label: PromoterLabel,
+    pub tss_dist: i32,
+}
+
+// How many locations hit a gene in each category. Locations inside no gene
+// count as intergenic hits of the gene they are assigned to.
+#[derive(Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct GeneHitRow {
+    pub gene_id: String,
+    pub gene_symbol: String,
+    pub peaks: u32,
+    pub promoter: u32,
+    pub exonic: u32,
+    pub intronic: u32,
+    pub intergenic: u32,
+}
 
-        if ids.len() == 0 {
-            ids.push(NA.to_owned());
-            gene_symbols.push(NA.to_owned());
-            tss_dists.push(NA.to_owned());
+impl GeneHitRow {
+    fn new(gene_id: &str, gene_symbol: &str) -> Self {
+        GeneHitRow {
+            gene_id: gene_id.to_owned(),
+            gene_symbol: gene_symbol.to_owned(),
+            peaks: 0,
+            promoter: 0,
+            exonic: 0,
+            intronic: 0,
+            intergenic: 0,
         }
+    }
+}
 
-        println!("{} geneids", ids.join(";"));
-        println!("{}", gene_symbols.join(";"));
-        println!("{}", prom_labels.join(";"));
-        println!("{}", tss_dists.join(";"));
+// Annotations of many locations as parallel columns, one entry per location
+// in input order, e.g. for handing to Python or R as arrays. The per gene
+// fields are joined as in GeneAnnotation and the closest gene columns are
+// for the single closest gene, n/a or None if there is none.
+#[derive(Serialize, Debug, PartialEq, Clone, Default)]
+pub struct AnnotationColumns {
+    pub locations: Vec<String>,
+    pub gene_ids: Vec<String>,
+    pub gene_symbols: Vec<String>,
+    pub prom_labels: Vec<String>,
+    pub tss_dists: Vec<String>,
+    pub closest_gene_ids: Vec<String>,
+    pub closest_gene_symbols: Vec<String>,
+    pub closest_prom_labels: Vec<String>,
+    pub closest_tss_dists: Vec<Option<i32>>,
+}
 
-        let closest_genes: Vec<GenomicFeature> =
-            self.genesdb
-                .get_closest_genes(&location, self.n, Level::Gene)?;
+// Summary of how much of a set of locations is genic. Peaks are genic if
+// they are within a gene or its promoter, as for GeneAnnotation::is_genic.
+// The bp totals are of the locations merged where they overlap, so no base
+// is counted twice, and genic_bp only counts bases inside gene bodies.
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct CoverageStats {
+    pub peaks: u32,
+    pub genic_peaks: u32,
+    pub intergenic_peaks: u32,
+    pub total_bp: u64,
+    pub genic_bp: u64,
+}
 
-        let annotation: GeneAnnotation = GeneAnnotation {
-            gene_ids: ids.join(";"),
-            gene_symbols: gene_symbols.join(";"),
-            prom_labels: prom_labels.join(";"),
-            tss_dists: tss_dists.join(";"),
-            closest_genes: closest_genes
-                .iter()
-                .map(|cg| ClosestGene {
-                    gene_id: cg.gene_id.to_owned(),
-                    gene_symbol: cg.gene_symbol.to_owned(),
-                    tss_dist: cg.dist,
-                    prom_label: self.classify_location(location, cg),
-                })
-                .collect(),
-        };
+// A location's annotation as written out one per line in JSONL output
+#[derive(Serialize)]
+pub struct LocationAnnotation {
+    pub location: String,
+    #[serde(flatten)]
+    pub annotation: GeneAnnotation,
+}
 
-        Ok(annotation)
+struct GeneProm {
+    is_promoter: bool,
+    is_intronic: bool,
+    is_exon: bool,
+    overlaps_tss: bool,
+    abs_d: i32,
+    d: i32,
+    // distance to the TSS of the transcript furthest from the location
+    max_abs_d: i32,
+    max_d: i32,
+    // TSS of the transcript closest to the location
+    tss: u32,
+    strand: String,
+    // gene locus spanning all of its transcripts
+    start: u32,
+    end: u32,
+    custom_labels: Vec<String>,
+    transcript_support: u32,
+    feature_count: u32,
+    antisense: Option<bool>,
+}
+
+// Converts locations from the assembly of the input into the assembly of
+// the database, e.g. hg19 to hg38. Returns None if a location cannot be
+// lifted over.
+pub trait Liftover: Send + Sync {
+    fn lift(&self, location: &Location) -> Option<Location>;
+}
+
+// Adds custom labels, e.g. from a user's own CpG island data, to the
+// promoter/exonic/intronic labels the annotator assigns to each feature.
+// Classifiers run on every feature a location is labelled against and their
+// labels are appended to the built in ones in the order the classifiers
+// were added.
+pub trait Classifier: Send + Sync {
+    fn classify(&self, location: &Location, feature: &GenomicFeature) -> Vec<String>;
+}
+
+// The coordinate system of input locations. The database uses 1-based
+// inclusive coordinates, so Zero is for 0-based half-open coordinates as
+// found in BED files, which are converted before querying.
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CoordBase {
+    One,
+    Zero,
+}
+
+// Whether to look up exons to tell exonic and intronic locations apart.
+// Skipping them saves a query per gene when only promoter assignment is
+// needed, but locations are then never labelled exonic.
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ExonDetail {
+    Skip,
+    Compute,
+}
+
+// What to report for features that have no gene symbol
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MissingSymbol {
+    GeneId,
+    Na,
+}
+
+// Whether a location that only touches the edge of a feature counts as
+// overlapping it when classifying closest genes. Inclusive matches the
+// overlap queries of the database, which compare with <= and >=.
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Boundary {
+    Inclusive,
+    Exclusive,
+}
+
+// Which gene assign_gene picks when a location is in the body of one gene
+// and the promoter of another, e.g. an intronic peak that is also just
+// upstream of a neighbouring gene.
+//
+// ClosestTss: the gene with the closest TSS wins whatever the location
+// overlaps. This is the default.
+//
+// GeneBody: a gene whose body contains the location midpoint outranks one
+// whose promoter does, falling back to promoter genes and then to the
+// closest TSS.
+//
+// Promoter: a gene whose promoter contains the midpoint outranks one whose
+// body does, falling back to body genes and then to the closest TSS.
+//
+// Among several genes of the winning kind, the closest TSS wins.
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AssignPolicy {
+    ClosestTss,
+    GeneBody,
+    Promoter,
+}
+
+// The delimiters of joined output: genes between the entries of the per
+// gene fields, e.g. G1;G2, and labels between the parts of a label, e.g.
+// promoter,exonic. The defaults are ; and , as above.
+#[derive(Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct Separators {
+    pub genes: String,
+    pub labels: String,
+}
+
+impl Separators {
+    pub fn new(genes: &str, labels: &str) -> Self {
+        Separators {
+            genes: genes.to_owned(),
+            labels: labels.to_owned(),
+        }
     }
+}
 
-    fn classify_location(&self, location: &Location, feature: &GenomicFeature) -> String {
-        let mid: u32 = location.mid();
+impl Default for Separators {
+    fn default() -> Self {
+        Separators::new(";", ",")
+    }
+}
 
-        let s: u32 = if feature.strand == "+" {
-            feature.start - self.tss_region.offset_5p()
-        } else {
-            feature.start
-        };
+// A location with the name given to it in the input, e.g. the name column
+// of a BED file
+#[derive(Debug, Clone)]
+pub struct NamedLocation {
+    pub location: Location,
+    pub name: Option<String>,
+}
 
-        let e: u32 = if feature.strand == "-" {
-            feature.end + self.tss_region.offset_5p()
-        } else {
-            feature.end
-        };
+// Formats of text input with one location per line. Blank lines and lines
+// starting with # are skipped.
+//
+// Bed: tab separated chr, start and end columns followed by any others.
+// track and browser lines are skipped. The coordinates are used as given,
+// so combine with CoordBase::Zero to treat them as 0-based.
+//
+// Locations: a location such as chr1:1000-2000 in the first tab separated
+// column, with any other columns ignored.
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LocationFormat {
+    Bed,
+    Locations,
+}
+
+// Upper limit of the default number of threads, see Annotate::with_threads
+const MAX_DEFAULT_THREADS: usize = 8;
+
+// The most recently used annotations of an annotator, see
+// Annotate::with_cache. Recency is kept in a queue that is searched on each
+// hit, which is cheap next to a database query for the few thousand
+// entries a cache is likely to hold.
+struct AnnotationCache {
+    capacity: usize,
+    annotations: HashMap<LocationKey, GeneAnnotation>,
+    // least recently used first
+    order: VecDeque<LocationKey>,
+}
 
-        if location.start > e || location.end < s {
-            return INTERGENIC.to_string();
+impl AnnotationCache {
+    fn new(capacity: usize) -> Self {
+        AnnotationCache {
+            capacity,
+            annotations: HashMap::new(),
+            order: VecDeque::new(),
         }
+    }
 
-        let is_promoter: bool = (feature.strand == "+"
-            && mid >= s
-            && mid <= feature.start + self.tss_region.offset_3p())
-            || (feature.strand == "-"
-                && mid >= feature.end - self.tss_region.offset_3p()
-                && mid <= e);
+    fn get(&mut self, key: &LocationKey) -> Option<GeneAnnotation> {
+        let annotation: GeneAnnotation = self.annotations.get(key)?.clone();
 
-        let exons: Vec<GenomicFeature> =
-            match self.genesdb.in_exon(&location, &feature.gene_id) {
-                Ok(exons) => exons,
-                Err(_) => vec![],
-            };
+        if let Some(i) = self.order.iter().position(|k| k == key) {
+            self.order.remove(i);
+        }
 
-        let is_exon = exons.len() > 0;
+        self.order.push_back(key.clone());
 
-        let is_intronic = mid >= feature.start && mid <= feature.end;
+        Some(annotation)
+    }
+
+    fn insert(&mut self, key: LocationKey, annotation: GeneAnnotation) {
+        if self.capacity == 0 || self.annotations.contains_key(&key) {
+            return;
+        }
+
+        if self.annotations.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.annotations.remove(&oldest);
+            }
+        }
+
+        self.order.push_back(key.clone());
+        self.annotations.insert(key, annotation);
+    }
+}
+
+// Closest genes reported when the builder is not given a number
+pub const DEFAULT_CLOSEST_N: u16 = 5;
+
+// Builds an Annotate from named options, any left unset taking their
+// defaults: the default TSS region, DEFAULT_CLOSEST_N closest genes,
+// transcript level and no biotype filter. Building fails if biotypes are
+// given but the database has no biotype column.
+//
+// let annotatedb = AnnotateBuilder::new().closest_n(2).level(Level::Gene).build(genesdb)?;
+pub struct AnnotateBuilder {
+    tss_region: TSSRegion,
+    closest_n: u16,
+    level: Level,
+    biotypes: Option<Vec<String>>,
+}
+
+impl AnnotateBuilder {
+    pub fn new() -> Self {
+        AnnotateBuilder {
+            tss_region: TSSRegion::default(),
+            closest_n: DEFAULT_CLOSEST_N,
+            level: Level::Transcript,
+            biotypes: None,
+        }
+    }
+
+    pub fn tss_region(mut self, tss_region: TSSRegion) -> Self {
+        self.tss_region = tss_region;
+        self
+    }
+
+    pub fn closest_n(mut self, closest_n: u16) -> Self {
+        self.closest_n = closest_n;
+        self
+    }
 
-        return make_label(is_promoter, is_exon, is_intronic);
+    pub fn level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
     }
+
+    pub fn biotypes(mut self, biotypes: Option<Vec<String>>) -> Self {
+        self.biotypes = biotypes;
+        self
+    }
+
+    pub fn build(self, genesdb: LoctogeneDb) -> GenesResult<Annotate> {
+        Annotate::new(genesdb, self.tss_region, self.closest_n)
+            .with_level(self.level)
+            .with_biotypes(self.biotypes)
+    }
+}
+
+impl Default for AnnotateBuilder {
+    fn default() -> Self {
+        AnnotateBuilder::new()
+    }
+}
+
+pub struct Annotate {
+    genesdb:  LoctogeneDb,
+    tss_region: TSSRegion,
+    n: u16,
+    level: Level,
+    score_tie_break: bool,
+    locus_columns: bool,
+    boolean_columns: bool,
+    direction_columns: bool,
+    use_interval_overlap: bool,
+    dedupe_locations: bool,
+    transcript_promoters: bool,
+    join_limit: Option<usize>,
+    threads: usize,
+    liftover: Option<Box<dyn Liftover>>,
+    classifiers: Vec<Box<dyn Classifier>>,
+    missing_symbol: MissingSymbol,
+    coord_base: CoordBase,
+    exon_detail: ExonDetail,
+    coding_level: Option<u8>,
+    separators: Separators,
+    table_delimiter: u8,
+    cache: Option<Mutex<AnnotationCache>>,
+    boundary: Boundary,
+    assign_policy: AssignPolicy,
+    canonical: Option<bool>,
 }
 
-fn make_label(is_promoter: bool, is_exon: bool, is_intronic: bool) -> String {
-    let mut labels: Vec<&str> = Vec::with_capacity(2);
+impl Annotate {
+    pub fn new(genesdb: LoctogeneDb, tss_region: TSSRegion, n: u16) -> Self {
+        Annotate {
+            genesdb,
+            tss_region,
+            n,
+            level: Level::Transcript,
+            score_tie_break: false,
+            locus_columns: false,
+            boolean_columns: false,
+            direction_columns: false,
+            use_interval_overlap: false,
+            dedupe_locations: false,
+            transcript_promoters: false,
+            join_limit: None,
+            threads: std::thread::available_parallelism()
+                .map(|n| cmp::min(n.get(), MAX_DEFAULT_THREADS))
+                .unwrap_or(1),
+            liftover: None,
+            classifiers: vec![],
+            missing_symbol: MissingSymbol::GeneId,
+            coord_base: CoordBase::One,
+            exon_detail: ExonDetail::Compute,
+            coding_level: None,
+            separators: Separators::default(),
+            table_delimiter: b'\t',
+            cache: None,
+            boundary: Boundary::Inclusive,
+            assign_policy: AssignPolicy::ClosestTss,
+            canonical: None,
+        }
+    }
+
+    // The level of the features a location is annotated against. The
+    // default, Transcript, labels a location against every transcript of a
+    // gene, so it is a promoter if it is near any of their TSSs. Gene only
+    // uses the gene itself, which has the TSS of its first transcript.
+    // Closest genes are always found at gene level.
+    pub fn with_level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    // Adds Gene Strand and Gene Locus columns to the primary gene section,
+    // and a Closest Gene Strand column to each closest gene section, of
+    // make_gene_table. Off by default so the column layout is unchanged.
+    pub fn with_locus_columns(mut self, locus_columns: bool) -> Self {
+        self.locus_columns = locus_columns;
+        self
+    }
+
+    // Adds Is Promoter, Is Exonic and Is Intronic columns of true/false to
+    // make_gene_table for the nearest gene the location is within, so
+    // tables can be filtered without splitting the joined labels. Locations
+    // within no gene are false for all three.
+    pub fn with_boolean_columns(mut self, boolean_columns: bool) -> Self {
+        self.boolean_columns = boolean_columns;
+        self
+    }
+
+    // Splits the signed TSS Distance column of make_gene_table into TSS
+    // Distance (bp), which is always positive, and Direction, which is
+    // upstream, downstream or overlapping relative to each gene's strand.
+    // The closest gene distances stay signed.
+    pub fn with_direction_columns(mut self, direction_columns: bool) -> Self {
+        self.direction_columns = direction_columns;
+        self
+    }
+
+    // By default a location is within the genes whose body or promoter
+    // contains its midpoint, so a wide location overlapping a gene away from
+    // its middle is not within it, unlike with LoctogeneDb::get_genes_within.
+    // This also keeps genes whose body overlaps any part of the location and
+    // labels them intronic (or exonic) on that overlap.
+    pub fn with_interval_overlap(mut self, use_interval_overlap: bool) -> Self {
+        self.use_interval_overlap = use_interval_overlap;
+        self
+    }
+
+    // Annotates each distinct location once in make_gene_table and repeats
+    // its row for every time it occurs, for inputs with duplicate peaks.
+    // annotate_reader only dedupes within each batch it reads so its memory
+    // use does not grow with the input.
+    pub fn with_dedupe_locations(mut self, dedupe_locations: bool) -> Self {
+        self.dedupe_locations = dedupe_locations;
+        self
+    }
+
+    // Closest genes are found at gene level, where a gene's TSS is that of
+    // its first transcript. This labels a closest gene promoter using the
+    // TSS of its transcript nearest the location instead, at the cost of a
+    // query per closest gene.
+    pub fn with_transcript_promoters(mut self, transcript_promoters: bool) -> Self {
+        self.transcript_promoters = transcript_promoters;
+        self
+    }
+
+    // Caps how many genes are listed in the joined per gene fields of an
+    // annotation, e.g. gene_symbols, adding (+K more) for the rest, to keep
+    // tables readable in gene dense regions. The genes field always has
+    // every gene.
+    pub fn with_join_limit(mut self, join_limit: Option<usize>) -> Self {
+        self.join_limit = join_limit;
+        self
+    }
+
+    // Keeps the annotations of up to capacity locations, dropping the least
+    // recently used, so annotate can return a copy for a location it has
+    // already seen without querying the database, e.g. for overlapping
+    // sliding windows. None, the default, turns caching off. Only annotate
+    // itself uses the cache, since the other methods may use a different
+    // promoter region or number of closest genes.
+    pub fn with_cache(mut self, capacity: Option<usize>) -> Self {
+        self.cache = capacity.map(|capacity| Mutex::new(AnnotationCache::new(capacity)));
+        self
+    }
+
+    // The delimiter between the columns of tables, tab by default. With a
+    // comma the tables are CSV, and cells containing a comma, such as some
+    // gene symbols, are quoted.
+    pub fn with_table_delimiter(mut self, table_delimiter: u8) -> Self {
+        self.table_delimiter = table_delimiter;
+        self
+    }
+
+    // The delimiters used between genes and between the parts of labels in
+    // annotations and tables, e.g. | for parsers that cannot take ;
+    pub fn with_separators(mut self, separators: Separators) -> Self {
+        self.separators = separators;
+        self
+    }
+
+    // How many threads annotate_many and make_gene_table annotate with.
+    // Defaults to the number of cores, up to 8. Each thread holds a pooled
+    // connection while it works, so more threads than the pool has
+    // connections (10) only wait on each other.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = cmp::max(threads, 1);
+        self
+    }
+
+    // When several genes are equally close, prefer the one with the highest
+    // score (see LoctogeneDb::with_score_column) in assign_gene.
+    pub fn with_score_tie_break(mut self, score_tie_break: bool) -> Self {
+        self.score_tie_break = score_tie_break;
+        self
+    }
+
+    // How assign_gene ranks a gene body overlap against a promoter overlap,
+    // see AssignPolicy
+    pub fn with_assign_policy(mut self, assign_policy: AssignPolicy) -> Self {
+        self.assign_policy = assign_policy;
+        self
+    }
+
+    // Lifts every input location over before querying the database
+    pub fn with_liftover(mut self, liftover: Box<dyn Liftover>) -> Self {
+        self.liftover = Some(liftover);
+        self
+    }
+
+    // Adds a classifier after any already added
+    pub fn with_classifier(mut self, classifier: Box<dyn Classifier>) -> Self {
+        self.classifiers.push(classifier);
+        self
+    }
+
+    // Features without a symbol report their gene id by default so joined
+    // symbols never contain blank entries
+    pub fn with_missing_symbol(mut self, missing_symbol: MissingSymbol) -> Self {
+        self.missing_symbol = missing_symbol;
+        self
+    }
+
+    // How to interpret the coordinates of input locations, 1-based by
+    // default
+    pub fn with_coord_base(mut self, coord_base: CoordBase) -> Self {
+        self.coord_base = coord_base;
+        self
+    }
+
+    pub fn with_exon_detail(mut self, exon_detail: ExonDetail) -> Self {
+        self.exon_detail = exon_detail;
+        self
+    }
+
+    // Labels exonic locations as 5' UTR, CDS or 3' UTR using the features
+    // of each gene at the given level, e.g. CDS features stored at their own
+    // level as for LoctogeneDb::with_cds_level. A location overlapping one
+    // of them is CDS. Genes with no features at that level are still
+    // labelled exonic. None, the default, labels every exon exonic.
+    pub fn with_coding_level(mut self, coding_level: Option<u8>) -> Self {
+        self.coding_level = coding_level;
+        self
+    }
+
+    // At transcript level, annotates each gene against its canonical
+    // transcript only so the reported distance does not jump between
+    // isoforms. The canonical transcript is the one flagged in the
+    // database's canonical column, or the longest if there is no such
+    // column. Genes without a flagged transcript, or whose canonical
+    // transcript is out of range of the location, use their nearest.
+    pub fn with_canonical_only(mut self, canonical_only: bool) -> GenesResult<Self> {
+        self.canonical = if canonical_only {
+            Some(self.genesdb.has_canonical()?)
+        } else {
+            None
+        };
+
+        Ok(self)
+    }
+
+    pub fn with_boundary(mut self, boundary: Boundary) -> Self {
+        self.boundary = boundary;
+        self
+    }
+
+    // Only annotates with genes of the given biotypes, e.g. protein_coding,
+    // using the biotype column of the database. None annotates with every
+    // gene. An error if the database has no biotype column.
+    pub fn with_biotypes(mut self, biotypes: Option<Vec<String>>) -> GenesResult<Self> {
+        self.genesdb = self.genesdb.with_biotypes(biotypes)?;
+        Ok(self)
+    }
+
+    // Only annotates with protein coding genes
+    pub fn coding_only(mut self) -> GenesResult<Self> {
+        let biotypes: Vec<String> = CODING_BIOTYPES.iter().map(|b| b.to_string()).collect();
+
+        self.genesdb = self.genesdb.with_biotypes(Some(biotypes))?;
+        Ok(self)
+    }
+
+    // Annotates with protein coding genes and non-coding RNAs such as
+    // lncRNAs and miRNAs, but not pseudogenes
+    pub fn include_noncoding(mut self) -> GenesResult<Self> {
+        let biotypes: Vec<String> = CODING_BIOTYPES
+            .iter()
+            .chain(NONCODING_BIOTYPES.iter())
+            .map(|b| b.to_string())
+            .collect();
+
+        self.genesdb = self.genesdb.with_biotypes(Some(biotypes))?;
+        Ok(self)
+    }
+
+    pub fn genesdb(&self) -> &LoctogeneDb {
+        &self.genesdb
+    }
+
+    // Annotates a location with the annotator's promoter region and number
+    // of closest genes. Use annotate_with_tss to classify with a different
+    // promoter region for a single call.
+    pub fn annotate(&self, location: &Location) -> GenesResult<GeneAnnotation> {
+        let cache: &Mutex<AnnotationCache> = match &self.cache {
+            Some(cache) => cache,
+            None => return self.annotate_n(location, &self.tss_region, self.n, true),
+        };
+
+        let key: LocationKey = LocationKey::from(location);
+
+        let cached: Option<GeneAnnotation> = match cache.lock() {
+            Ok(mut cache) => cache.get(&key),
+            Err(_) => {
+                return Err(GenesError::DatabaseError("annotation cache unavailable".to_string()))
+            }
+        };
+
+        if let Some(annotation) = cached {
+            return Ok(annotation);
+        }
+
+        // annotate without holding the lock so other threads are not held up
+        let annotation: GeneAnnotation =
+            self.annotate_n(location, &self.tss_region, self.n, true)?;
+
+        match cache.lock() {
+            Ok(mut cache) => cache.insert(key, annotation.clone()),
+            Err(_) => {
+                return Err(GenesError::DatabaseError("annotation cache unavailable".to_string()))
+            }
+        };
+
+        Ok(annotation)
+    }
+
+    // Same as annotate for a location on a known strand, e.g. a stranded
+    // read cluster, additionally flagging each gene it is within as
+    // antisense if the gene is on the other strand
+    pub fn annotate_stranded(
+        &self,
+        location: &Location,
+        strand: Strand,
+    ) -> GenesResult<GeneAnnotation> {
+        self.annotate_n_stranded(location, &self.tss_region, self.n, Some(strand), true)
+    }
+
+    // Same as annotate, but classifying promoters with the given region
+    // rather than the one the annotator was made with, so the same
+    // locations can be compared under several promoter definitions without
+    // reopening the database. The cache is not used since it holds
+    // annotations made with the annotator's own region.
+    pub fn annotate_with_tss(
+        &self,
+        location: &Location,
+        tss_region: &TSSRegion,
+    ) -> GenesResult<GeneAnnotation> {
+        self.annotate_n(location, tss_region, self.n, true)
+    }
+
+    // Annotates many locations on several threads (see with_threads). The
+    // annotations are in the same order as the locations.
+    pub fn annotate_many(&self, locations: &[Location]) -> GenesResult<Vec<GeneAnnotation>> {
+        self.annotate_many_n(locations, &self.tss_region, self.n, true)
+    }
+
+    // Lazily annotates locations one at a time as the iterator is consumed,
+    // so a genome-wide tiling can be annotated and written out without
+    // holding every location or annotation in memory. The annotations are
+    // in the same order as the locations and, unlike annotate_many, run on
+    // the calling thread.
+    pub fn annotate_iter<'a, I>(
+        &'a self,
+        locations: I,
+    ) -> impl Iterator<Item = GenesResult<GeneAnnotation>> + 'a
+    where
+        I: IntoIterator<Item = Location>,
+        I::IntoIter: 'a,
+    {
+        locations
+            .into_iter()
+            .map(move |location| self.annotate(&location))
+    }
+
+    // annotate_many using the given promoter region and number of closest
+    // genes, see annotate_n. Each thread annotates a contiguous slice of the
+    // locations so joining their results in turn keeps the input order.
+    fn annotate_many_n(
+        &self,
+        locations: &[Location],
+        tss_region: &TSSRegion,
+        n: u16,
+        nearest: bool,
+    ) -> GenesResult<Vec<GeneAnnotation>> {
+        let threads: usize = cmp::min(self.threads, locations.len());
+
+        if threads <= 1 {
+            return locations
+                .iter()
+                .map(|location| self.annotate_n(location, tss_region, n, nearest))
+                .collect();
+        }
+
+        let slice_size: usize = locations.len().div_ceil(threads);
+
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = locations
+                .chunks(slice_size)
+                .map(|slice| {
+                    scope.spawn(move || {
+                        slice
+                            .iter()
+                            .map(|location| self.annotate_n(location, tss_region, n, nearest))
+                            .collect::<GenesResult<Vec<GeneAnnotation>>>()
+                    })
+                })
+                .collect();
+
+            let mut annotations: Vec<GeneAnnotation> = Vec::with_capacity(locations.len());
+
+            for handle in handles {
+                match handle.join() {
+                    Ok(slice_annotations) => annotations.extend(slice_annotations?),
+                    Err(err) => std::panic::resume_unwind(err),
+                }
+            }
+
+            Ok(annotations)
+        })
+    }
+
+    // The classification of each gene a location is within, nearest TSS
+    // first, without joining them into strings or finding the closest genes
+    pub fn annotate_detailed(&self, location: &Location) -> GenesResult<Vec<PromoterOverlap>> {
+        Ok(self.annotate_n(location, &self.tss_region, 0, false)?.genes)
+    }
+
+    // The locations whose dominant category differs between two promoter
+    // regions, with the category under each, to help pick a promoter
+    // window. The dominant category of a location is the first of
+    // promoter, exonic, intronic and intergenic that applies to any gene
+    // it is within. Genes are fetched once per location using the larger
+    // of the two regions and then classified under each.
+    pub fn category_diff(
+        &self,
+        locations: &[Location],
+        tss_a: &TSSRegion,
+        tss_b: &TSSRegion,
+    ) -> GenesResult<Vec<(Location, String, String)>> {
+        let pad: u32 = [tss_a, tss_b]
+            .iter()
+            .map(|ts| cmp::max(ts.offset_5p(), ts.offset_3p()))
+            .max()
+            .unwrap_or(0);
+
+        let mut diffs: Vec<(Location, String, String)> = Vec::new();
+
+        for location in locations {
+            let lifted: Cow<Location> = self.db_location(location)?;
+
+            let features: Vec<GenomicFeature> =
+                self.genesdb
+                    .get_genes_within_promoter_mid(&lifted, &self.level, pad)?;
+
+            // the exons are the same under either region
+            let exon_classes: HashMap<String, RegionClass> =
+                self.features_exon_classes(&lifted, &features)?;
+
+            let category_a: &str =
+                self.dominant_category(&lifted, &features, &exon_classes, tss_a)?;
+            let category_b: &str =
+                self.dominant_category(&lifted, &features, &exon_classes, tss_b)?;
+
+            if category_a != category_b {
+                diffs.push((location.clone(), category_a.to_owned(), category_b.to_owned()));
+            }
+        }
+
+        Ok(diffs)
+    }
+
+    // The dominant category of a location given the features around it and
+    // the ids of the genes with an exon under it, see category_diff
+    fn dominant_category(
+        &self,
+        location: &Location,
+        features: &[GenomicFeature],
+        exon_classes: &HashMap<String, RegionClass>,
+        tss_region: &TSSRegion,
+    ) -> GenesResult<&'static str> {
+        let mid: u32 = location.mid();
+
+        if features.iter().any(|f| is_promoter(mid, f, tss_region)) {
+            return Ok(PROMOTER);
+        }
+
+        let body_features: Vec<&GenomicFeature> = features
+            .iter()
+            .filter(|f| mid >= f.start && mid <= f.end)
+            .collect();
+
+        if body_features.iter().any(|f| exon_classes.contains_key(&f.gene_id)) {
+            return Ok(EXONIC);
+        }
+
+        if !body_features.is_empty() {
+            return Ok(INTRONIC);
+        }
+
+        Ok(INTERGENIC)
+    }
+
+    // Annotates a location using the given promoter region and reporting n
+    // closest genes rather than the defaults of this annotator. Callers that
+    // never read nearest_distance can skip it, which saves a closest gene
+    // query for intergenic locations when n is 0.
+    fn annotate_n(
+        &self,
+        location: &Location,
+        tss_region: &TSSRegion,
+        n: u16,
+        nearest: bool,
+    ) -> GenesResult<GeneAnnotation> {
+        self.annotate_n_stranded(location, tss_region, n, None, nearest)
+    }
+
+    // Keeps only the canonical transcript of each gene, see
+    // with_canonical_only. Genes whose canonical transcript is not among the
+    // transcripts, e.g. because it is out of range of the location, or that
+    // have none keep their nearest transcript instead so they are still
+    // annotated. The canonical transcripts of all the genes are looked up
+    // with a single query.
+    fn canonical_transcripts(
+        &self,
+        transcripts: Vec<GenomicFeature>,
+        flagged: bool,
+    ) -> GenesResult<Vec<GenomicFeature>> {
+        // the transcripts of each gene, in the order the genes are first seen
+        let mut genes: Vec<(&str, Vec<&GenomicFeature>)> = Vec::new();
+        let mut gene_index: HashMap<&str, usize> = HashMap::new();
+
+        for transcript in transcripts.iter() {
+            let i: usize = *gene_index.entry(&transcript.gene_id).or_insert_with(|| {
+                genes.push((&transcript.gene_id, Vec::new()));
+                genes.len() - 1
+            });
+
+            genes[i].1.push(transcript);
+        }
+
+        let gene_ids: Vec<&str> = genes.iter().map(|(gene_id, _)| *gene_id).collect();
+
+        // all the transcripts are on the chromosome of the location
+        let canonical_ids: HashMap<String, u32> = match transcripts.first() {
+            Some(transcript) => {
+                self.genesdb.get_canonical_transcript_ids(&transcript.chr, &gene_ids, flagged)?
+            }
+            None => HashMap::new(),
+        };
+
+        let mut kept: HashSet<u32> = HashSet::new();
+
+        for (gene_id, gene_transcripts) in genes.iter() {
+            let canonical: Option<&&GenomicFeature> = gene_transcripts
+                .iter()
+                .find(|t| canonical_ids.get(*gene_id) == Some(&t.id));
+
+            let transcript: Option<&&GenomicFeature> = match canonical {
+                Some(transcript) => Some(transcript),
+                None => gene_transcripts.iter().min_by_key(|t| t.dist.abs()),
+            };
+
+            if let Some(transcript) = transcript {
+                kept.insert(transcript.id);
+            }
+        }
+
+        let transcripts: Vec<GenomicFeature> =
+            transcripts.into_iter().filter(|t| kept.contains(&t.id)).collect();
+
+        Ok(transcripts)
+    }
+
+    // Same as annotate_n, with antisense flags if the strand of the location
+    // is known
+    fn annotate_n_stranded(
+        &self,
+        location: &Location,
+        tss_region: &TSSRegion,
+        n: u16,
+        strand: Option<Strand>,
+        nearest: bool,
+    ) -> GenesResult<GeneAnnotation> {
+        let lifted: Cow<Location> = self.db_location(location)?;
+        let location: &Location = &lifted;
+
+        let mid: u32 = location.mid();
+
+        // extend search area to account  for promoter
+        // let search_loc: Location = Location::new(
+        //     &location.chr,
+        //     location.start - self.tss_region.offset_5p.abs(),
+        //     location.end + self.tss_region.offset_5p.abs(),
+        // )?;
+
+        let pad: u32 = cmp::max(tss_region.offset_5p(), tss_region.offset_3p());
+
+        // the bounds the genes are searched for within, reported as the
+        // search window
+        let (window_start, window_end): (u32, u32) = if self.use_interval_overlap {
+            (location.start.saturating_sub(pad), location.end.saturating_add(pad))
+        } else {
+            (mid.saturating_sub(pad), mid.saturating_add(pad))
+        };
+
+        let genes_within: Vec<GenomicFeature> = if self.use_interval_overlap {
+            self.genesdb
+                .get_genes_within_promoter(location, &self.level, pad)?
+                .into_iter()
+                .filter(|g| {
+                    (g.start.saturating_sub(pad) <= mid && g.end + pad >= mid)
+                        || overlaps(location, g.start, g.end)
+                })
+                .collect()
+        } else {
+            self.genesdb.get_genes_within_promoter_mid(location, &self.level, pad)?
+        };
+
+        let genes_within: Vec<GenomicFeature> = match self.canonical {
+            Some(flagged) if self.level == Level::Transcript => {
+                self.canonical_transcripts(genes_within, flagged)?
+            }
+            _ => genes_within,
+        };
+
+        // we need the unique ids to symbols
+        let mut id_map: HashMap<String, String> = HashMap::new();
+        let mut promoter_map: HashMap<String, GeneProm> = HashMap::new();
+        //let mut dist_map: HashMap<&str, bool> = HashMap::new();
+
+        // no genes means no exons, so save the query
+        let exon_classes: HashMap<String, RegionClass> = if genes_within.is_empty() {
+            HashMap::new()
+        } else {
+            self.exon_classes(location)?
+        };
+
+        for gene in genes_within.iter() {
+            let id = gene.gene_id.to_owned();
+
+            // println!(
+            //     "{} {} {} {} {}",
+            //     gene.gene_id, gene.gene_symbol, gene.start, gene.end, gene.strand
+            // );
+
+            id_map.insert(id.to_owned(), self.gene_symbol(gene));
+
+            //let labels = self.classify_location(location, gene);
+
+            let is_exon: bool = exon_classes.contains_key(&id);
+
+            let is_promoter: bool = is_promoter(mid, gene, tss_region);
+
+            let is_intronic = if self.use_interval_overlap {
+                overlaps(location, gene.start, gene.end)
+            } else {
+                mid >= gene.start && mid <= gene.end
+            };
+
+            let d: i32 = self.genesdb.wrap_dist(&gene.chr, stranded_distance(location, gene));
+
+            let tss: u32 = if gene.strand == "-" { gene.end } else { gene.start };
+
+            let overlaps_tss: bool = tss >= location.start && tss <= location.end;
+
+            let custom_labels: Vec<String> = self.custom_labels(location, gene);
+
+            let support: u32 = if is_promoter || is_exon || is_intronic { 1 } else { 0 };
+
+            let overlapping: u32 = if overlaps(location, gene.start, gene.end) { 1 } else { 0 };
+
+            let antisense: Option<bool> = strand.map(|s| Strand::from(gene.strand.as_str()) != s);
+
+            //println!("{} {} {}", gene.end - mid, gene.end, mid);
+
+            // update by inserting default case and then updating
+            promoter_map
+                .entry(id)
+                .and_modify(|v: &mut GeneProm| {
+                    v.is_intronic = v.is_intronic || is_intronic;
+                    v.is_promoter = v.is_promoter || is_promoter;
+                    v.is_exon = v.is_exon || is_exon;
+                    v.overlaps_tss = v.overlaps_tss || overlaps_tss;
+                    v.transcript_support += support;
+                    v.feature_count += overlapping;
+
+                    let abs_d: i32 = d.abs();
+
+                    if abs_d < v.abs_d {
+                        v.d = d;
+                        v.abs_d = abs_d;
+                        v.tss = tss;
+                    }
+
+                    if abs_d > v.max_abs_d {
+                        v.max_d = d;
+                        v.max_abs_d = abs_d;
+                    }
+
+                    v.start = cmp::min(v.start, gene.start);
+                    v.end = cmp::max(v.end, gene.end);
+
+                    for label in custom_labels.iter() {
+                        if !v.custom_labels.contains(label) {
+                            v.custom_labels.push(label.to_owned());
+                        }
+                    }
+                })
+                .or_insert(GeneProm {
+                    is_promoter,
+                    is_intronic,
+                    is_exon,
+                    overlaps_tss,
+                    d,
+                    abs_d: d.abs(),
+                    max_d: d,
+                    max_abs_d: d.abs(),
+                    tss,
+                    strand: gene.strand.to_owned(),
+                    start: gene.start,
+                    end: gene.end,
+                    custom_labels,
+                    transcript_support: support,
+                    feature_count: overlapping,
+                    antisense,
+                });
+        }
+
+        // sort the ids by distance
+        let mut dist_map: BTreeMap<i32, BTreeSet<String>> = BTreeMap::new();
+
+        for id in id_map.keys() {
+            dist_map
+                .entry(promoter_map.get(id).unwrap().abs_d)
+                .and_modify(|v| {
+                    v.insert(id.to_owned());
+                })
+                .or_insert({
+                    let mut dids: BTreeSet<String> = BTreeSet::new();
+                    dids.insert(id.to_owned());
+                    dids
+                });
+        }
+
+        // now put the ids in distance order
+        let mut ids: Vec<String> = Vec::with_capacity(id_map.len());
+
+        for d in dist_map.keys() {
+            for id in dist_map.get(d).unwrap() {
+                ids.push(id.to_string());
+            }
+        }
+
+        log::debug!("{} is within {} genes", location, ids.len());
+
+        // the classification of each gene in distance order
+        let genes: Vec<GeneClassification> = ids
+            .iter()
+            .map(|id| {
+                let p = promoter_map.get(id).unwrap();
+
+                Ok(GeneClassification {
+                    gene_id: id.to_owned(),
+                    gene_symbol: id_map.get(id).unwrap().to_owned(),
+                    strand: p.strand.to_owned(),
+                    start: p.start,
+                    end: p.end,
+                    is_promoter: p.is_promoter,
+                    is_exon: p.is_exon,
+                    exon_class: exon_classes.get(id).copied(),
+                    is_intronic: p.is_intronic,
+                    overlaps_tss: p.overlaps_tss,
+                    tss_dist: p.d,
+                    max_tss_dist: p.max_d,
+                    tss_coord: p.tss,
+                    gene_covered_frac: covered_frac(location, p.start, p.end),
+                    body_edge_dist: body_edge_dist(mid, p.start, p.end),
+                    custom_labels: p.custom_labels.to_owned(),
+                    transcript_support: p.transcript_support,
+                    feature_count: p.feature_count,
+                    antisense: p.antisense,
+                })
+            })
+            .collect::<GenesResult<Vec<GeneClassification>>>()?;
+
+        // make a list of the symbols in distance order
+        let gene_symbols: Vec<String> = genes
+            .iter()
+            .map(|g| g.gene_symbol.to_owned())
+            .collect::<Vec<String>>();
+
+        let prom_labels: Vec<String> = genes
+            .iter()
+            .map(|g| {
+                make_label(g.is_promoter, g.exon_class, g.is_intronic, &g.custom_labels)
+                    .join(&self.separators.labels)
+            })
+            .collect::<Vec<String>>();
+
+        let tss_dists: Vec<String> = genes
+            .iter()
+            .map(|g| g.tss_dist.to_string())
+            .collect::<Vec<String>>();
+
+        let strands: Vec<String> = genes
+            .iter()
+            .map(|g| g.strand.to_owned())
+            .collect::<Vec<String>>();
+
+        let loci: Vec<String> = genes
+            .iter()
+            .map(|g| format!("{}:{}-{}", location.chr, g.start, g.end))
+            .collect::<Vec<String>>();
+
+        let abs_tss_dists: Vec<String> = genes
+            .iter()
+            .map(|g| g.tss_dist.unsigned_abs().to_string())
+            .collect::<Vec<String>>();
+
+        let tss_directions: Vec<String> = genes
+            .iter()
+            .map(|g| tss_direction(location, &g.strand, g.tss_coord).to_owned())
+            .collect::<Vec<String>>();
+
+        let feature_counts: Vec<String> = genes
+            .iter()
+            .map(|g| g.feature_count.to_string())
+            .collect::<Vec<String>>();
+
+        let antisense: Option<Vec<String>> = strand.map(|_| {
+            genes
+                .iter()
+                .map(|g| g.antisense.unwrap_or(false).to_string())
+                .collect::<Vec<String>>()
+        });
+
+        log::trace!(
+            "{} genes {} symbols {} labels {} tss dists {}",
+            location,
+            ids.join(";"),
+            gene_symbols.join(";"),
+            prom_labels.join(";"),
+            tss_dists.join(";")
+        );
+
+        // no point running the closest query if no closest genes are wanted
+        let closest_genes: Vec<GenomicFeature> = if n > 0 {
+            self.genesdb.get_closest_genes(location, n, Level::Gene)?
+        } else {
+            vec![]
+        };
+
+        let nearest_distance: Option<i32> = if !ids.is_empty() {
+            None
+        } else {
+            let nearest: Option<GenomicFeature> = if n > 0 {
+                closest_genes.first().cloned()
+            } else if nearest {
+                self.genesdb.get_closest_genes(location, 1, Level::Gene)?.into_iter().next()
+            } else {
+                None
+            };
+
+            nearest.map(|f| self.genesdb.wrap_dist(&f.chr, stranded_distance(location, &f)))
+        };
+
+        // the exons under the location were already looked up if there are
+        // any genes within it, otherwise a closest gene can still overlap a
+        // location too wide for its midpoint to be near the gene
+        let closest_exon_classes: HashMap<String, RegionClass> = if genes_within.is_empty() {
+            self.features_exon_classes(location, &closest_genes)?
+        } else {
+            exon_classes
+        };
+
+        let annotation: GeneAnnotation = GeneAnnotation {
+            gene_ids: self.join(&ids),
+            gene_symbols: self.join(&gene_symbols),
+            prom_labels: self.join(&prom_labels),
+            tss_dists: self.join(&tss_dists),
+            strands: self.join(&strands),
+            loci: self.join(&loci),
+            abs_tss_dists: self.join(&abs_tss_dists),
+            tss_directions: self.join(&tss_directions),
+            feature_counts: self.join(&feature_counts),
+            antisense: antisense.map(|antisense| self.join(&antisense)),
+            genes,
+            closest_genes: closest_genes
+                .iter()
+                .map(|cg| self.closest_gene(location, cg, &closest_exon_classes, tss_region))
+                .collect::<GenesResult<Vec<ClosestGene>>>()?,
+            is_genic: !ids.is_empty(),
+            nearest_distance,
+            search_window: format!("{}:{}-{}", location.chr, window_start, window_end),
+        };
+
+        Ok(annotation)
+    }
+
+    // Annotates each location and returns the results as a tab separated
+    // table, or as set by with_table_delimiter. Each row has the location,
+    // the genes it falls within and then closest_n groups of columns
+    // describing the closest genes. The closest gene count is independent
+    // of the n used by annotate and if it is zero, the table has just the 5
+    // primary columns. Locations are classified using ts so the promoter
+    // region in the headers always matches the one used for the labels.
+    #[cfg(feature = "table")]
+    pub fn make_gene_table(
+        &self,
+        locations: &[Location],
+        closest_n: u16,
+        ts: &TSSRegion,
+    ) -> GenesResult<String> {
+        let mut buffer: Vec<u8> = vec![];
+
+        self.write_gene_table(locations, closest_n, ts, &mut buffer)?;
+
+        let data: String = String::from_utf8(buffer)?;
+
+        Ok(data)
+    }
+
+    // Same as make_gene_table, but with a leading Name column holding the
+    // name of each location so the table can be joined back to named peaks.
+    // Locations without a name have their location in the Name column.
+    #[cfg(feature = "table")]
+    pub fn make_named_gene_table(
+        &self,
+        locations: &[NamedLocation],
+        closest_n: u16,
+        ts: &TSSRegion,
+    ) -> GenesResult<String> {
+        let names: Vec<String> = locations
+            .iter()
+            .map(|named| match &named.name {
+                Some(name) => name.to_owned(),
+                None => named.location.to_string(),
+            })
+            .collect();
+
+        let locations: Vec<Location> =
+            locations.iter().map(|named| named.location.clone()).collect();
+
+        let mut buffer: Vec<u8> = vec![];
+
+        self.write_table(&locations, Some(&names), closest_n, ts, &mut buffer)?;
+
+        let data: String = String::from_utf8(buffer)?;
+
+        Ok(data)
+    }
+
+    // Same as make_gene_table, but each row is written as soon as its
+    // location is annotated rather than building the whole table in memory
+    #[cfg(feature = "table")]
+    pub fn write_gene_table<W: Write>(
+        &self,
+        locations: &[Location],
+        closest_n: u16,
+        ts: &TSSRegion,
+        writer: &mut W,
+    ) -> GenesResult<()> {
+        self.write_table(locations, None, closest_n, ts, writer)
+    }
+
+    // Writes the table of make_gene_table, with a leading Name column if
+    // there are names, which must be one per location
+    #[cfg(feature = "table")]
+    fn write_table<W: Write>(
+        &self,
+        locations: &[Location],
+        names: Option<&[String]>,
+        closest_n: u16,
+        ts: &TSSRegion,
+        writer: &mut W,
+    ) -> GenesResult<()> {
+        // fail before doing any work if some locations could never match
+        self.validate_chromosomes(locations)?;
+
+        let mut wtr: csv::Writer<&mut W> =
+            self.table_writer(names.is_some(), closest_n, ts, writer)?;
+
+        // rows of locations already seen when deduplicating
+        let mut rows: HashMap<LocationKey, Vec<String>> = HashMap::new();
+
+        // annotate a batch at a time so rows are still written as we go
+        for (b, batch) in locations.chunks(TABLE_BATCH_SIZE).enumerate() {
+            let batch_names: Option<&[String]> = names
+                .map(|names| &names[b * TABLE_BATCH_SIZE..b * TABLE_BATCH_SIZE + batch.len()]);
+
+            self.write_table_batch(&mut wtr, batch, batch_names, closest_n, ts, &mut rows)?;
+        }
+
+        wtr.flush()?;
+
+        Ok(())
+    }
+
+    // A writer for a table that has had its headers written, see
+    // make_gene_table
+    #[cfg(feature = "table")]
+    fn table_writer<'a, W: Write>(
+        &self,
+        named: bool,
+        closest_n: u16,
+        ts: &TSSRegion,
+        writer: &'a mut W,
+    ) -> GenesResult<csv::Writer<&'a mut W>> {
+        let mut wtr: csv::Writer<&mut W> = csv::WriterBuilder::new()
+            .delimiter(self.table_delimiter)
+            .from_writer(writer);
+
+        self.validate_tss_region(ts)?;
+
+        let mut headers: Vec<String> = self.table_columns(closest_n, ts);
+
+        if named {
+            headers.insert(0, "Name".to_owned());
+        }
+
+        wtr.write_record(&headers)?;
+
+        Ok(wtr)
+    }
+
+    // Annotates a batch of locations and writes their rows, reusing the
+    // rows of locations seen in earlier batches when deduplicating
+    #[cfg(feature = "table")]
+    fn write_table_batch<W: Write>(
+        &self,
+        wtr: &mut csv::Writer<W>,
+        batch: &[Location],
+        names: Option<&[String]>,
+        closest_n: u16,
+        ts: &TSSRegion,
+        rows: &mut HashMap<LocationKey, Vec<String>>,
+    ) -> GenesResult<()> {
+        let mut todo: Vec<Location> = Vec::with_capacity(batch.len());
+        let mut todo_keys: HashSet<LocationKey> = HashSet::new();
+
+        for location in batch {
+            let key: LocationKey = LocationKey::from(location);
+
+            if !self.dedupe_locations || (!rows.contains_key(&key) && todo_keys.insert(key)) {
+                todo.push(location.clone());
+            }
+        }
+
+        // the table has no nearest distance column
+        let annotations: Vec<GeneAnnotation> = self.annotate_many_n(&todo, ts, closest_n, false)?;
+
+        let mut batch_rows = todo.iter().zip(annotations).map(|(location, annotation)| {
+            annotation.to_row_with_columns(
+                location,
+                closest_n,
+                self.locus_columns,
+                self.boolean_columns,
+                self.direction_columns,
+                &self.separators,
+            )
+        });
+
+        for (j, location) in batch.iter().enumerate() {
+            let key: LocationKey = LocationKey::from(location);
+
+            // rows come out in the order their locations were added to
+            // todo, so the first time a location is seen its row is the
+            // next one
+            let row: Vec<String> = match rows.get(&key) {
+                Some(row) if self.dedupe_locations => row.clone(),
+                _ => batch_rows.next().unwrap(),
+            };
+
+            match names {
+                Some(names) => {
+                    wtr.write_field(&names[j])?;
+                    wtr.write_record(&row)?;
+                }
+                None => wtr.write_record(&row)?,
+            }
+
+            if self.dedupe_locations {
+                rows.insert(key, row);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Reads locations from standard input and writes their table, as made
+    // by make_gene_table using the promoter region of this annotator, to
+    // standard output so annotation can be part of a pipe, e.g.
+    // cat peaks.bed | annotator. See LocationFormat for the input formats.
+    #[cfg(feature = "table")]
+    pub fn annotate_stdin(&self, format: LocationFormat, closest_n: u16) -> GenesResult<()> {
+        return self.annotate_reader(
+            std::io::stdin().lock(),
+            format,
+            closest_n,
+            &mut std::io::stdout().lock(),
+        );
+    }
+
+    // Same as annotate_stdin, but for any reader and writer. Locations are
+    // read TABLE_BATCH_SIZE at a time and the rows of each batch are
+    // written and flushed once it is annotated, so a long running input
+    // gives output as it goes and only one batch is held in memory at a
+    // time. A last line without a newline is read like any other.
+    #[cfg(feature = "table")]
+    pub fn annotate_reader<R: BufRead, W: Write>(
+        &self,
+        reader: R,
+        format: LocationFormat,
+        closest_n: u16,
+        writer: &mut W,
+    ) -> GenesResult<()> {
+        let mut wtr: csv::Writer<&mut W> =
+            self.table_writer(false, closest_n, &self.tss_region, writer)?;
+
+        let mut batch: Vec<Location> = Vec::with_capacity(TABLE_BATCH_SIZE);
+
+        for (i, line) in reader.lines().enumerate() {
+            if let Some(named) = parse_named_location(&line?, i + 1, format)? {
+                batch.push(named.location);
+            }
+
+            if batch.len() == TABLE_BATCH_SIZE {
+                self.write_stream_batch(&mut wtr, &batch, closest_n)?;
+                batch.clear();
+            }
+        }
+
+        // whatever is left when the input ends
+        if !batch.is_empty() {
+            self.write_stream_batch(&mut wtr, &batch, closest_n)?;
+        }
+
+        wtr.flush()?;
+
+        Ok(())
+    }
+
+    // Writes a batch of annotate_reader and flushes it so its rows are not
+    // held back waiting on the rest of the input. Duplicates are only
+    // looked for within the batch.
+    #[cfg(feature = "table")]
+    fn write_stream_batch<W: Write>(
+        &self,
+        wtr: &mut csv::Writer<W>,
+        batch: &[Location],
+        closest_n: u16,
+    ) -> GenesResult<()> {
+        self.validate_chromosomes(batch)?;
+
+        let mut rows: HashMap<LocationKey, Vec<String>> = HashMap::new();
+
+        self.write_table_batch(wtr, batch, None, closest_n, &self.tss_region, &mut rows)?;
+
+        wtr.flush()?;
+
+        Ok(())
+    }
+
+    // Checks neither offset of a promoter region is longer than the genes
+    // of even the longest chromosome in the database span (see
+    // LoctogeneDb::max_chr_extent), which means the offsets were given in
+    // the wrong units, e.g. kb as Mb. Every location would then be a
+    // promoter of every gene on its chromosome. Short contigs such as chrM
+    // do not count, so ordinary promoter regions always pass. make_gene_table
+    // fails with this error before annotating anything.
+    pub fn validate_tss_region(&self, tss_region: &TSSRegion) -> GenesResult<()> {
+        let offset: u32 = cmp::max(tss_region.offset_5p(), tss_region.offset_3p());
+
+        match self.genesdb.max_chr_extent()? {
+            Some(extent) if offset > extent => Err(GenesError::FormatError(format!(
+                "promoter offset of {} bp exceeds the largest chromosome extent of {} bp",
+                offset, extent
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    // Checks every chromosome used by the locations is in the database so a
+    // misnamed chromosome is reported up front rather than giving n/a for
+    // all of its locations. The error lists every missing chromosome along
+    // with a likely match from the database, e.g. chrM for chrMT. The
+    // locations are checked as they are annotated, i.e. after any coordinate
+    // base conversion and liftover, so a liftover that renames chromosomes
+    // is allowed and one that cannot lift a location is an error.
+    pub fn validate_chromosomes(&self, locations: &[Location]) -> GenesResult<()> {
+        let mut chrs: Vec<String> = Vec::new();
+        let mut seen: HashSet<String> = HashSet::new();
+
+        for location in locations {
+            let lifted: Cow<Location> = self.db_location(location)?;
+
+            if !seen.contains(&lifted.chr) {
+                seen.insert(lifted.chr.clone());
+                chrs.push(lifted.chr.clone());
+            }
+        }
+
+        let mut missing: Vec<&str> = Vec::new();
+
+        for chr in chrs.iter() {
+            if !self.genesdb.has_chr(chr)? {
+                missing.push(chr);
+            }
+        }
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let db_chrs: Vec<String> = self.genesdb.chromosomes()?;
+
+        let errors: Vec<String> = missing
+            .iter()
+            .map(|chr| match suggest_chr(chr, &db_chrs) {
+                Some(suggestion) => format!("{} (did you mean {}?)", chr, suggestion),
+                None => chr.to_string(),
+            })
+            .collect::<Vec<String>>();
+
+        Err(GenesError::FormatError(format!(
+            "chromosomes not in database: {}",
+            errors.join(", ")
+        )))
+    }
+
+    // The header of the table produced by make_gene_table
+    #[cfg(feature = "table")]
+    pub fn table_columns(&self, closest_n: u16, tss_region: &TSSRegion) -> Vec<String> {
+        let prom: String = format!(
+            "prom=-{}/+{}kb",
+            tss_region.offset_5p() as f64 / 1000.0,
+            tss_region.offset_3p() as f64 / 1000.0
+        );
+
+        let mut headers: Vec<String> = Vec::with_capacity(10 + 4 * closest_n as usize);
+
+        headers.push("Location".to_owned());
+        headers.push("ID".to_owned());
+        headers.push("Gene Symbol".to_owned());
+        headers.push(format!("Relative To Gene ({})", prom));
+
+        if self.direction_columns {
+            headers.push("TSS Distance (bp)".to_owned());
+            headers.push("Direction".to_owned());
+        } else {
+            headers.push("TSS Distance".to_owned());
+        }
+
+        if self.locus_columns {
+            headers.push("Gene Strand".to_owned());
+            headers.push("Gene Locus".to_owned());
+        }
+
+        if self.boolean_columns {
+            headers.push("Is Promoter".to_owned());
+            headers.push("Is Exonic".to_owned());
+            headers.push("Is Intronic".to_owned());
+        }
+
+        for i in 1..(closest_n + 1) {
+            headers.push(format!("#{} Closest ID", i));
+            headers.push(format!("#{} Closest Gene Symbols", i));
+            headers.push(format!("#{} Relative To Closest Gene ({})", i, prom));
+            headers.push(format!("#{} TSS Closest Distance", i));
+
+            if self.locus_columns {
+                headers.push(format!("#{} Closest Gene Strand", i));
+            }
+        }
+
+        headers
+    }
+
+    // Labels a location against features the caller already has, e.g. from
+    // their own query or a cache, rather than querying for them. Returns the
+    // gene id and label of each feature in order. The location must use the
+    // same coordinates as the features since no conversion or liftover is
+    // applied. Exon membership is still looked up in the database unless
+    // exon detail is skipped.
+    pub fn classify_features(
+        &self,
+        location: &Location,
+        features: &[GenomicFeature],
+    ) -> GenesResult<Vec<(String, String)>> {
+        let exon_classes: HashMap<String, RegionClass> =
+            self.features_exon_classes(location, features)?;
+
+        features
+            .iter()
+            .map(|feature| {
+                let label: PromoterLabel =
+                    self.classify_location(location, feature, &exon_classes, &self.tss_region)?;
+
+                Ok((feature.gene_id.to_owned(), label.to_string()))
+            })
+            .collect()
+    }
+
+    // How a location relates to a single feature, as labelled for closest
+    // genes, using the promoter region of the annotator. The location is
+    // intergenic if it does not overlap the feature or its promoter at all.
+    // Otherwise it is promoter if its midpoint is in the promoter, and
+    // exonic if it is in an exon of the feature's gene, or else intronic if
+    // its midpoint is in the feature, so it can be both promoter and exonic
+    // or intronic. Labels from any classifiers are added after these. As
+    // with classify_features, the location must already be in database
+    // coordinates.
+    pub fn classify(
+        &self,
+        location: &Location,
+        feature: &GenomicFeature,
+    ) -> GenesResult<PromoterLabel> {
+        let exon_classes: HashMap<String, RegionClass> =
+            self.features_exon_classes(location, std::slice::from_ref(feature))?;
+
+        self.classify_location(location, feature, &exon_classes, &self.tss_region)
+    }
+
+    // Whether a location is in the promoter shared by two divergent genes,
+    // i.e. the closest - strand gene and the closest + strand gene are head
+    // to head with their TSSs no more than max_tss_gap bp apart. Returns the
+    // - strand gene and then the + strand gene if so.
+    pub fn is_bidirectional_promoter(
+        &self,
+        location: &Location,
+        max_tss_gap: u32,
+    ) -> GenesResult<Option<(GenomicFeature, GenomicFeature)>> {
+        let lifted: Cow<Location> = self.db_location(location)?;
+        let location: &Location = &lifted;
+
+        let mid: u32 = location.mid();
+
+        let minus: GenomicFeature =
+            match self.genesdb.get_closest_gene_on_strand(location, "-", Level::Gene)? {
+                Some(gene) => gene,
+                None => return Ok(None),
+            };
+
+        let plus: GenomicFeature =
+            match self.genesdb.get_closest_gene_on_strand(location, "+", Level::Gene)? {
+                Some(gene) => gene,
+                None => return Ok(None),
+            };
+
+        // the - strand TSS is its end, so for the genes to be divergent it
+        // must come before the + strand TSS
+        if minus.end > plus.start || plus.start - minus.end > max_tss_gap {
+            return Ok(None);
+        }
+
+        if !is_promoter(mid, &minus, &self.tss_region)
+            && !is_promoter(mid, &plus, &self.tss_region)
+        {
+            return Ok(None);
+        }
+
+        Ok(Some((minus, plus)))
+    }
+
+    // The genes a location is assigned to under the basal plus extension
+    // model of GREAT. Each gene has a basal domain of basal around its TSS,
+    // which is extended in both directions up to the basal domains of the
+    // genes with the neighbouring TSSs, but by no more than max_ext bp from
+    // the TSS. The gene ids whose domains contain the location midpoint are
+    // returned, closest TSS first. GREAT's defaults are a basal region of
+    // 5 kb upstream and 1 kb downstream and a 1 Mb extension.
+    pub fn great_assignment(
+        &self,
+        location: &Location,
+        basal: TSSRegion,
+        max_ext: u32,
+    ) -> GenesResult<Vec<String>> {
+        let lifted: Cow<Location> = self.db_location(location)?;
+        let location: &Location = &lifted;
+
+        let mid: u32 = location.mid();
+
+        // a domain reaches at most reach bp from its TSS, so genes whose
+        // domains could contain mid have TSSs within reach of it and their
+        // neighbours have TSSs within twice that
+        let reach: u32 = max_ext.saturating_add(cmp::max(basal.offset_5p(), basal.offset_3p()));
+
+        let mut genes: Vec<(u32, (u32, u32), GenomicFeature)> = self
+            .genesdb
+            .get_genes_within_promoter(location, &Level::Gene, reach.saturating_mul(2))?
+            .into_iter()
+            .map(|gene| {
+                let tss: u32 = if gene.strand == "-" { gene.end } else { gene.start };
+
+                let domain: (u32, u32) = if gene.strand == "-" {
+                    (
+                        tss.saturating_sub(basal.offset_3p()),
+                        tss.saturating_add(basal.offset_5p()),
+                    )
+                } else {
+                    (
+                        tss.saturating_sub(basal.offset_5p()),
+                        tss.saturating_add(basal.offset_3p()),
+                    )
+                };
+
+                (tss, domain, gene)
+            })
+            .collect();
+
+        genes.sort_by_key(|(tss, _, _)| *tss);
+
+        let mut assigned: Vec<(u32, String)> = Vec::new();
+
+        for (i, (tss, (basal_start, basal_end), gene)) in genes.iter().enumerate() {
+            let mut start: u32 = tss.saturating_sub(max_ext);
+            let mut end: u32 = tss.saturating_add(max_ext);
+
+            if i > 0 {
+                start = cmp::max(start, genes[i - 1].1 .1);
+            }
+
+            if i + 1 < genes.len() {
+                end = cmp::min(end, genes[i + 1].1 .0);
+            }
+
+            // the basal domain is always kept, even where it overlaps
+            // another gene's
+            start = cmp::min(start, *basal_start);
+            end = cmp::max(end, *basal_end);
+
+            if mid >= start && mid <= end {
+                assigned.push((tss.abs_diff(mid), gene.gene_id.to_owned()));
+            }
+        }
+
+        assigned.sort();
+
+        Ok(assigned.into_iter().map(|(_, id)| id).collect())
+    }
+
+    // Annotates each transcript near a location individually rather than
+    // collapsing them to genes as annotate does. Exon status comes from the
+    // exons of the transcript's gene.
+    pub fn annotate_transcripts(
+        &self,
+        location: &Location,
+    ) -> GenesResult<Vec<TranscriptAnnotation>> {
+        let lifted: Cow<Location> = self.db_location(location)?;
+        let location: &Location = &lifted;
+
+        let mid: u32 = location.mid();
+
+        let transcripts: Vec<GenomicFeature> = self.genesdb.get_genes_within_promoter_mid(
+            location,
+            &Level::Transcript,
+            cmp::max(self.tss_region.offset_5p(), self.tss_region.offset_3p()),
+        )?;
+
+        let exon_classes: HashMap<String, RegionClass> =
+            self.features_exon_classes(location, &transcripts)?;
+
+        let mut annotations: Vec<TranscriptAnnotation> = Vec::with_capacity(transcripts.len());
+
+        for transcript in transcripts.iter() {
+            let is_intronic: bool = mid >= transcript.start && mid <= transcript.end;
+
+            annotations.push(TranscriptAnnotation {
+                id: transcript.id,
+                gene_id: transcript.gene_id.to_owned(),
+                gene_symbol: self.gene_symbol(transcript),
+                strand: transcript.strand.to_owned(),
+                start: transcript.start,
+                end: transcript.end,
+                tss_dist: self.genesdb.wrap_dist(
+                    &transcript.chr,
+                    stranded_distance(location, transcript),
+                ),
+                prom_label: make_label(
+                    is_promoter(mid, transcript, &self.tss_region),
+                    exon_classes.get(&transcript.gene_id).copied(),
+                    is_intronic,
+                    &self.custom_labels(location, transcript),
+                ),
+            });
+        }
+
+        Ok(annotations)
+    }
+
+    // The annotations of the locations as a JSON array with an object per
+    // location, as written by write_gene_jsonl, using the promoter region
+    // and number of closest genes of this annotator. The fields match the
+    // cells of make_gene_table with the same settings.
+    pub fn make_gene_json(&self, locations: &[Location]) -> GenesResult<String> {
+        let rows: Vec<LocationAnnotation> = locations
+            .iter()
+            .zip(self.annotate_many(locations)?)
+            .map(|(location, annotation)| LocationAnnotation {
+                location: location.to_string(),
+                annotation,
+            })
+            .collect();
+
+        Ok(serde_json::to_string(&rows)?)
+    }
+
+    // Writes one JSON object per line (JSONL) for each location as soon as it
+    // is annotated so memory use stays bounded for large inputs.
+    pub fn write_gene_jsonl<W: Write>(
+        &self,
+        locations: &[Location],
+        closest_n: u16,
+        writer: &mut W,
+    ) -> GenesResult<()> {
+        for location in locations {
+            let row: LocationAnnotation = LocationAnnotation {
+                location: location.to_string(),
+                annotation: self.annotate_n(location, &self.tss_region, closest_n, true)?,
+            };
+
+            serde_json::to_writer(&mut *writer, &row)?;
+
+            writer.write_all(b"\n")?;
+        }
+
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    // Counts, for every gene hit by at least one location, how many
+    // locations fall in its promoter, exons, introns or, for locations in no
+    // gene, are assigned to it as intergenic. Rows are ordered by the number
+    // of locations, most first, then by symbol.
+    pub fn gene_hit_report(&self, locations: &[Location]) -> GenesResult<Vec<GeneHitRow>> {
+        let mut hits: HashMap<String, GeneHitRow> = HashMap::new();
+
+        for location in locations {
+            let annotation: GeneAnnotation =
+                self.annotate_n(location, &self.tss_region, 0, false)?;
+
+            if !annotation.genes.is_empty() {
+                for gene in annotation.genes.iter() {
+                    let row: &mut GeneHitRow = hits
+                        .entry(gene.gene_id.to_owned())
+                        .or_insert(GeneHitRow::new(&gene.gene_id, &gene.gene_symbol));
+
+                    row.peaks += 1;
+
+                    if gene.is_promoter {
+                        row.promoter += 1;
+                    }
+
+                    if gene.is_exon {
+                        row.exonic += 1;
+                    } else if gene.is_intronic {
+                        row.intronic += 1;
+                    }
+                }
+            } else if let Some(gene) = self.assign_gene(location)? {
+                let row: &mut GeneHitRow = hits
+                    .entry(gene.gene_id.to_owned())
+                    .or_insert(GeneHitRow::new(&gene.gene_id, &self.gene_symbol(&gene)));
+
+                row.peaks += 1;
+                row.intergenic += 1;
+            }
+        }
+
+        let mut rows: Vec<GeneHitRow> = hits.into_values().collect();
+
+        rows.sort_by(|a, b| {
+            b.peaks
+                .cmp(&a.peaks)
+                .then_with(|| a.gene_symbol.cmp(&b.gene_symbol))
+                .then_with(|| a.gene_id.cmp(&b.gene_id))
+        });
+
+        Ok(rows)
+    }
+
+    // Annotates the locations into columns rather than rows
+    pub fn annotate_columns(&self, locations: &[Location]) -> GenesResult<AnnotationColumns> {
+        let mut columns: AnnotationColumns = AnnotationColumns::default();
+
+        for location in locations {
+            let annotation: GeneAnnotation =
+                self.annotate_n(location, &self.tss_region, 1, true)?;
+
+            columns.locations.push(location.to_string());
+            columns.gene_ids.push(annotation.gene_ids);
+            columns.gene_symbols.push(annotation.gene_symbols);
+            columns.prom_labels.push(annotation.prom_labels);
+            columns.tss_dists.push(annotation.tss_dists);
+
+            match annotation.closest_genes.first() {
+                Some(closest_gene) => {
+                    columns.closest_gene_ids.push(closest_gene.gene_id.to_owned());
+                    columns.closest_gene_symbols.push(closest_gene.gene_symbol.to_owned());
+                    columns
+                        .closest_prom_labels
+                        .push(closest_gene.prom_label.join(&self.separators.labels));
+                    columns.closest_tss_dists.push(Some(closest_gene.tss_dist));
+                }
+                None => {
+                    columns.closest_gene_ids.push(NA.to_owned());
+                    columns.closest_gene_symbols.push(NA.to_owned());
+                    columns.closest_prom_labels.push(NA.to_owned());
+                    columns.closest_tss_dists.push(None);
+                }
+            }
+        }
+
+        Ok(columns)
+    }
+
+    // Annotates the locations and returns them with their annotations in
+    // genome order, i.e. by chromosome (see compare_chrs) then start and
+    // end, whatever order they were given in
+    pub fn annotate_sorted(
+        &self,
+        locations: &[Location],
+    ) -> GenesResult<Vec<(Location, GeneAnnotation)>> {
+        let mut annotations: Vec<(Location, GeneAnnotation)> = Vec::with_capacity(locations.len());
+
+        for location in locations {
+            annotations.push((location.clone(), self.annotate(location)?));
+        }
+
+        annotations.sort_by(|(a, _), (b, _)| {
+            compare_chrs(&a.chr, &b.chr)
+                .then_with(|| a.start.cmp(&b.start))
+                .then_with(|| a.end.cmp(&b.end))
+        });
+
+        Ok(annotations)
+    }
+
+    // Counts genic and intergenic locations and how many of the bases they
+    // cover are in genes
+    pub fn coverage_stats(&self, locations: &[Location]) -> GenesResult<CoverageStats> {
+        let mut stats: CoverageStats = CoverageStats {
+            peaks: 0,
+            genic_peaks: 0,
+            intergenic_peaks: 0,
+            total_bp: 0,
+            genic_bp: 0,
+        };
+
+        let mut chr_map: BTreeMap<String, Vec<(u32, u32)>> = BTreeMap::new();
+
+        for location in locations {
+            let annotation: GeneAnnotation =
+                self.annotate_n(location, &self.tss_region, 0, false)?;
+
+            stats.peaks += 1;
+
+            if annotation.is_genic {
+                stats.genic_peaks += 1;
+            } else {
+                stats.intergenic_peaks += 1;
+            }
+
+            let lifted: Cow<Location> = self.db_location(location)?;
+
+            chr_map
+                .entry(lifted.chr.to_owned())
+                .or_default()
+                .push((lifted.start, lifted.end));
+        }
+
+        for (chr, intervals) in chr_map.into_iter() {
+            for (start, end) in merge_intervals(intervals) {
+                stats.total_bp += (end - start + 1) as u64;
+
+                let region: Location = match Location::new(&chr, start, end) {
+                    Ok(region) => region,
+                    Err(_) => {
+                        return Err(GenesError::FormatError(format!(
+                            "invalid region {}:{}-{}",
+                            chr, start, end
+                        )))
+                    }
+                };
+
+                // genes can overlap each other, so merge the parts of them
+                // inside the region too
+                let overlaps: Vec<(u32, u32)> = self
+                    .genesdb
+                    .get_genes_within(&region, &Level::Gene)?
+                    .iter()
+                    .map(|gene| (cmp::max(gene.start, start), cmp::min(gene.end, end)))
+                    .collect();
+
+                for (s, e) in merge_intervals(overlaps) {
+                    stats.genic_bp += (e - s + 1) as u64;
+                }
+            }
+        }
+
+        Ok(stats)
+    }
+
+    // For each peak, returns its single nearest gene with distance and label,
+    // similar to running bedtools closest against the genes. Peaks on a
+    // chromosome with no genes have nothing to join to and are left out.
+    pub fn nearest_gene_join(
+        &self,
+        peaks: &[Location],
+    ) -> GenesResult<Vec<(Location, ClosestGene)>> {
+        let mut joined: Vec<(Location, ClosestGene)> = Vec::with_capacity(peaks.len());
+
+        for peak in peaks {
+            let lifted: Cow<Location> = self.db_location(peak)?;
+
+            let closest_genes: Vec<GenomicFeature> =
+                self.genesdb.get_closest_genes(&lifted, 1, Level::Gene)?;
+
+            if let Some(cg) = closest_genes.first() {
+                let exon_classes: HashMap<String, RegionClass> =
+                    self.features_exon_classes(&lifted, &closest_genes)?;
+
+                joined.push((
+                    peak.clone(),
+                    self.closest_gene(&lifted, cg, &exon_classes, &self.tss_region)?,
+                ));
+            }
+        }
+
+        Ok(joined)
+    }
+
+    // Picks the single gene a location is assigned to, which is the gene
+    // with the closest TSS unless the assign policy prefers genes the
+    // location is in the body or promoter of. Ties are kept in query order
+    // unless score tie breaking is enabled.
+    pub fn assign_gene(&self, location: &Location) -> GenesResult<Option<GenomicFeature>> {
+        let lifted: Cow<Location> = self.db_location(location)?;
+        let location: &Location = &lifted;
+
+        if self.assign_policy != AssignPolicy::ClosestTss {
+            if let Some(gene) = self.assign_overlapping_gene(location)? {
+                return Ok(Some(gene));
+            }
+        }
+
+        let closest_genes: Vec<GenomicFeature> =
+            self.genesdb
+                .get_closest_genes(location, cmp::max(self.n, 2), Level::Gene)?;
+
+        let min_d: i32 = match closest_genes.iter().map(|cg| cg.dist.abs()).min() {
+            Some(d) => d,
+            None => return Ok(None),
+        };
+
+        let mut tied = closest_genes.into_iter().filter(|cg| cg.dist.abs() == min_d);
+
+        let gene: Option<GenomicFeature> = if self.score_tie_break {
+            // first feature with the highest score wins, missing scores lose
+            tied.fold(None, |best: Option<GenomicFeature>, cg| match &best {
+                Some(b) if b.score.unwrap_or(f64::MIN) >= cg.score.unwrap_or(f64::MIN) => best,
+                _ => Some(cg),
+            })
+        } else {
+            tied.next()
+        };
+
+        Ok(gene)
+    }
+
+    // The gene assign_gene picks from those whose body or promoter contains
+    // the midpoint of a location, ranked by the assign policy, or None if
+    // there are none
+    fn assign_overlapping_gene(&self, location: &Location) -> GenesResult<Option<GenomicFeature>> {
+        let mid: u32 = location.mid();
+
+        let pad: u32 = cmp::max(self.tss_region.offset_5p(), self.tss_region.offset_3p());
+
+        let genes: Vec<GenomicFeature> =
+            self.genesdb
+                .get_genes_within_promoter_mid(location, &Level::Gene, pad)?;
+
+        let (body_genes, promoter_genes): (Vec<GenomicFeature>, Vec<GenomicFeature>) = genes
+            .into_iter()
+            .filter(|g| (mid >= g.start && mid <= g.end) || is_promoter(mid, g, &self.tss_region))
+            .partition(|g| mid >= g.start && mid <= g.end);
+
+        let (first, second) = match self.assign_policy {
+            AssignPolicy::Promoter => {
+                // body genes whose own promoter contains the midpoint count
+                // as promoter genes
+                let (promoter_body_genes, body_genes): (Vec<GenomicFeature>, Vec<GenomicFeature>) =
+                    body_genes
+                        .into_iter()
+                        .partition(|g| is_promoter(mid, g, &self.tss_region));
+
+                let mut promoter_genes: Vec<GenomicFeature> = promoter_genes;
+                promoter_genes.extend(promoter_body_genes);
+
+                (promoter_genes, body_genes)
+            }
+            _ => (body_genes, promoter_genes),
+        };
+
+        let ranked: Vec<GenomicFeature> = if first.is_empty() { second } else { first };
+
+        let gene: Option<GenomicFeature> = ranked.into_iter().fold(None, |best, g| match &best {
+            Some(b) if b.dist.abs() <= g.dist.abs() => best,
+            _ => Some(g),
+        });
+
+        Ok(gene)
+    }
+
+    // Converts an input location into the coordinates of the database,
+    // first making it 1-based and then lifting it over. Failing to lift is
+    // an error rather than silently annotating in the wrong coordinate
+    // space.
+    fn db_location<'a>(&self, location: &'a Location) -> GenesResult<Cow<'a, Location>> {
+        let location: Cow<Location> = match self.coord_base {
+            CoordBase::One => Cow::Borrowed(location),
+            CoordBase::Zero => {
+                // half-open [start, end) becomes [start + 1, end]
+                match Location::new(&location.chr, location.start + 1, location.end) {
+                    Ok(location) => Cow::Owned(location),
+                    Err(err) => return Err(GenesError::FormatError(err.to_string())),
+                }
+            }
+        };
+
+        match &self.liftover {
+            Some(liftover) => match liftover.lift(&location) {
+                Some(lifted) => Ok(Cow::Owned(lifted)),
+                None => Err(GenesError::FormatError(format!(
+                    "{} could not be lifted over",
+                    location
+                ))),
+            },
+            None => Ok(location),
+        }
+    }
+
+    // Joins per gene values with the join limit and gene separator of this
+    // annotator
+    fn join(&self, values: &[String]) -> String {
+        join_or_na(values, self.join_limit, &self.separators.genes)
+    }
+
+    // The part of its exons each gene with an exon under a location has
+    // there, from one query for all genes rather than an in_exon query per
+    // gene. With a coding level, genes whose coding features overlap the
+    // location are CDS, the coding features being looked up the same way as
+    // the exons, see LoctogeneDb::in_features. Other genes are a UTR when
+    // the midpoint is outside their coding span and exonic otherwise, as are
+    // genes without any coding features.
+    fn exon_classes(&self, location: &Location) -> GenesResult<HashMap<String, RegionClass>> {
+        if self.exon_detail == ExonDetail::Skip {
+            return Ok(HashMap::new());
+        }
+
+        let exons: HashMap<String, Vec<GenomicFeature>> = self.genesdb.in_exons(location)?;
+
+        let level: u8 = match self.coding_level {
+            Some(level) => level,
+            None => {
+                return Ok(exons
+                    .into_keys()
+                    .map(|gene_id| (gene_id, RegionClass::Exonic))
+                    .collect())
+            }
+        };
+
+        let coding: HashMap<String, Vec<GenomicFeature>> = if exons.is_empty() {
+            HashMap::new()
+        } else {
+            self.genesdb.in_features(location, level)?
+        };
+
+        let mut classes: HashMap<String, RegionClass> = HashMap::with_capacity(exons.len());
+
+        for (gene_id, gene_exons) in exons {
+            let class: RegionClass = if coding.contains_key(&gene_id) {
+                RegionClass::Cds
+            } else {
+                match self.genesdb.get_coding_bounds(location, &gene_id, level)? {
+                    Some((start, end)) => {
+                        utr_class(location.mid(), &gene_exons[0].strand, start, end)
+                    }
+                    None => RegionClass::Exonic,
+                }
+            };
+
+            classes.insert(gene_id, class);
+        }
+
+        Ok(classes)
+    }
+
+    // Same as exon_classes, but skips the query when none of the features
+    // overlap the location, since their exons cannot either
+    fn features_exon_classes(
+        &self,
+        location: &Location,
+        features: &[GenomicFeature],
+    ) -> GenesResult<HashMap<String, RegionClass>> {
+        if !features.iter().any(|f| overlaps(location, f.start, f.end)) {
+            return Ok(HashMap::new());
+        }
+
+        self.exon_classes(location)
+    }
+
+    // The symbol of a feature, falling back when it is missing
+    fn gene_symbol(&self, feature: &GenomicFeature) -> String {
+        if !feature.gene_symbol.trim().is_empty() {
+            return feature.gene_symbol.to_owned();
+        }
+
+        match self.missing_symbol {
+            MissingSymbol::GeneId => feature.gene_id.to_owned(),
+            MissingSymbol::Na => NA.to_owned(),
+        }
+    }
+
+    // A closest gene of a location given the ids of the genes with an exon
+    // under it
+    fn closest_gene(
+        &self,
+        location: &Location,
+        feature: &GenomicFeature,
+        exon_classes: &HashMap<String, RegionClass>,
+        tss_region: &TSSRegion,
+    ) -> GenesResult<ClosestGene> {
+        Ok(ClosestGene {
+            gene_id: feature.gene_id.to_owned(),
+            gene_symbol: self.gene_symbol(feature),
+            strand: feature.strand.to_owned(),
+            tss_dist: self.genesdb.wrap_dist(&feature.chr, stranded_distance(location, feature)),
+            prom_label: self.classify_location(location, feature, exon_classes, tss_region)?,
+            location_overlap_frac: location_frac(location, feature.start, feature.end),
+            gene_overlap_frac: covered_frac(location, feature.start, feature.end),
+        })
+    }
+
+    fn classify_location(
+        &self,
+        location: &Location,
+        feature: &GenomicFeature,
+        exon_classes: &HashMap<String, RegionClass>,
+        tss_region: &TSSRegion,
+    ) -> GenesResult<PromoterLabel> {
+        let mid: u32 = location.mid();
+
+        // genes near the start of a chromosome can have promoters that would
+        // begin before it
+        let s: u32 = if feature.strand == "+" {
+            feature.start.saturating_sub(tss_region.offset_5p())
+        } else {
+            feature.start
+        };
+
+        let e: u32 = if feature.strand == "-" {
+            feature.end.saturating_add(tss_region.offset_5p())
+        } else {
+            feature.end
+        };
+
+        let outside: bool = match self.boundary {
+            Boundary::Inclusive => location.start > e || location.end < s,
+            Boundary::Exclusive => location.start >= e || location.end <= s,
+        };
+
+        if outside {
+            return Ok(PromoterLabel::new(
+                vec![RegionClass::Intergenic],
+                self.custom_labels(location, feature),
+            ));
+        }
+
+        let transcript: Option<GenomicFeature> = if self.transcript_promoters {
+            self.genesdb.get_closest_transcript(location, &feature.gene_id)?
+        } else {
+            None
+        };
+
+        let is_promoter: bool = match &transcript {
+            Some(transcript) => is_promoter(mid, transcript, tss_region),
+            None => is_promoter(mid, feature, tss_region),
+        };
+
+        let is_intronic = mid >= feature.start && mid <= feature.end;
+
+        Ok(make_label(
+            is_promoter,
+            exon_classes.get(&feature.gene_id).copied(),
+            is_intronic,
+            &self.custom_labels(location, feature),
+        ))
+    }
+
+    // The labels of every classifier for a feature, without duplicates
+    fn custom_labels(&self, location: &Location, feature: &GenomicFeature) -> Vec<String> {
+        let mut labels: Vec<String> = Vec::new();
+
+        for classifier in self.classifiers.iter() {
+            for label in classifier.classify(location, feature) {
+                if !labels.contains(&label) {
+                    labels.push(label);
+                }
+            }
+        }
+
+        labels
+    }
+}
+
+// Parses one location per line of a reader. A last line without a newline
+// is read like any other.
+pub fn read_locations<R: BufRead>(reader: R, format: LocationFormat) -> GenesResult<Vec<Location>> {
+    let locations: Vec<Location> = read_named_locations(reader, format)?
+        .into_iter()
+        .map(|named| named.location)
+        .collect();
+
+    Ok(locations)
+}
+
+// Parses a BED file into 1-based locations, so BED's 0-based half-open
+// start is moved on by one, e.g. chr1 99 200 becomes chr1:100-200. Do not
+// also annotate them with CoordBase::Zero. Empty intervals become the
+// single base after their start.
+pub fn read_bed<R: BufRead>(reader: R) -> GenesResult<Vec<Location>> {
+    let mut locations: Vec<Location> = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line: String = line?;
+
+        // shifted before making the location, which may not start at 0
+        if let Some((chr, start, end)) = parse_bed_interval(&line, i + 1)? {
+            let start: u32 = start + 1;
+
+            match Location::new(chr, start, cmp::max(end, start)) {
+                Ok(location) => locations.push(location),
+                Err(_) => {
+                    return Err(GenesError::FormatError(format!(
+                        "line {}: invalid location {}",
+                        i + 1,
+                        line
+                    )))
+                }
+            }
+        }
+    }
+
+    Ok(locations)
+}
+
+// Same as read_locations, but keeps the name of each location, which is the
+// 4th column of BED and the 2nd column otherwise
+pub fn read_named_locations<R: BufRead>(
+    reader: R,
+    format: LocationFormat,
+) -> GenesResult<Vec<NamedLocation>> {
+    let mut locations: Vec<NamedLocation> = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        if let Some(location) = parse_named_location(&line?, i + 1, format)? {
+            locations.push(location);
+        }
+    }
+
+    Ok(locations)
+}
+
+// Parses line line_no of a locations file, or None if the line holds no
+// location, e.g. it is blank, a comment or a BED header
+fn parse_named_location(
+    line: &str,
+    line_no: usize,
+    format: LocationFormat,
+) -> GenesResult<Option<NamedLocation>> {
+    let line: &str = line.trim_end_matches('\r');
+
+    if line.trim().is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let columns: Vec<&str> = line.split('\t').collect();
+
+    let location = match format {
+        LocationFormat::Bed => match parse_bed_interval(line, line_no)? {
+            Some((chr, start, end)) => Location::new(chr, start, end),
+            None => return Ok(None),
+        },
+        LocationFormat::Locations => Location::parse(columns[0].trim()),
+    };
+
+    let name_column: usize = match format {
+        LocationFormat::Bed => 3,
+        LocationFormat::Locations => 1,
+    };
+
+    let name: Option<String> = columns
+        .get(name_column)
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_owned());
+
+    match location {
+        Ok(location) => Ok(Some(NamedLocation { location, name })),
+        Err(_) => Err(GenesError::FormatError(format!(
+            "line {}: invalid location {}",
+            line_no, line
+        ))),
+    }
+}
+
+// The chr, start and end columns of line line_no of a BED file as written,
+// or None if the line holds no interval, e.g. it is blank, a comment or a
+// header
+fn parse_bed_interval(line: &str, line_no: usize) -> GenesResult<Option<(&str, u32, u32)>> {
+    let line: &str = line.trim_end_matches('\r');
+
+    if line.trim().is_empty()
+        || line.starts_with('#')
+        || line.starts_with("track")
+        || line.starts_with("browser")
+    {
+        return Ok(None);
+    }
+
+    let columns: Vec<&str> = line.split('\t').collect();
+
+    if columns.len() < 3 {
+        return Err(GenesError::FormatError(format!(
+            "line {}: expected chr, start and end columns",
+            line_no
+        )));
+    }
+
+    match (columns[1].trim().parse::<u32>(), columns[2].trim().parse::<u32>()) {
+        (Ok(start), Ok(end)) => Ok(Some((columns[0].trim(), start, end))),
+        _ => Err(GenesError::FormatError(format!(
+            "line {}: invalid coordinates",
+            line_no
+        ))),
+    }
+}
+
+// Orders chromosomes naturally, so numbered chromosomes come first in
+// numeric order (chr2 before chr10), then X, Y and M, then any others such
+// as unplaced contigs by name. The chr prefix and case are ignored.
+pub fn compare_chrs(a: &str, b: &str) -> Ordering {
+    let rank = |chr: &str| -> (u8, u32, String) {
+        let chr: String = chr.to_lowercase();
+        let chr: &str = chr.strip_prefix("chr").unwrap_or(&chr);
+
+        if let Ok(n) = chr.parse::<u32>() {
+            return (0, n, String::new());
+        }
+
+        match chr {
+            "x" => (1, 0, String::new()),
+            "y" => (2, 0, String::new()),
+            "m" | "mt" => (3, 0, String::new()),
+            _ => (4, 0, chr.to_owned()),
+        }
+    };
+
+    rank(a).cmp(&rank(b)).then_with(|| a.cmp(b))
+}
+
+// Finds the database chromosome a missing chromosome was probably meant to
+// be, ignoring case, a chr prefix and the M/MT naming of the mitochondrion
+fn suggest_chr<'a>(chr: &str, db_chrs: &'a [String]) -> Option<&'a str> {
+    let key = |chr: &str| -> String {
+        let chr: String = chr.to_lowercase();
+        let chr: &str = chr.strip_prefix("chr").unwrap_or(&chr);
+
+        match chr {
+            "mt" => "m".to_owned(),
+            _ => chr.to_owned(),
+        }
+    };
+
+    let k: String = key(chr);
+
+    db_chrs.iter().find(|c| key(c) == k).map(|c| c.as_str())
+}
+
+// Whether a position lies in the promoter of a feature
+fn is_promoter(mid: u32, feature: &GenomicFeature, tss_region: &TSSRegion) -> bool {
+    (feature.strand == "+"
+        && mid >= feature.start.saturating_sub(tss_region.offset_5p())
+        && mid <= feature.start.saturating_add(tss_region.offset_3p()))
+        || (feature.strand == "-"
+            && mid >= feature.end.saturating_sub(tss_region.offset_3p())
+            && mid <= feature.end.saturating_add(tss_region.offset_5p()))
+}
+
+// Distance from the TSS of a feature, the start of + strand features and
+// the end of - strand features, to the mid point of a location reading 5'
+// to 3' along the feature's strand. It is negative when the location is
+// upstream of the TSS and positive when it is downstream, whatever the
+// strand. Every TSS distance reported by Annotate uses this.
+pub fn stranded_distance(from: &Location, to_feature: &GenomicFeature) -> i32 {
+    let mid: i32 = from.mid() as i32;
+
+    if to_feature.strand == "-" {
+        (to_feature.end as i32) - mid
+    } else {
+        mid - (to_feature.start as i32)
+    }
+}
+
+// Signed distance from a position to the nearest end of start..=end if the
+// position is inside it
+fn body_edge_dist(mid: u32, start: u32, end: u32) -> Option<i32> {
+    if mid < start || mid > end {
+        return None;
+    }
+
+    let to_start: i32 = (start as i32) - (mid as i32);
+    let to_end: i32 = (end as i32) - (mid as i32);
+
+    if to_start.abs() <= to_end.abs() {
+        Some(to_start)
+    } else {
+        Some(to_end)
+    }
+}
+
+// Where a TSS lies relative to a location, reading along the strand of its
+// gene, so a location before a + strand TSS or after a - strand TSS is
+// upstream of it
+fn tss_direction(location: &Location, strand: &str, tss: u32) -> &'static str {
+    if tss >= location.start && tss <= location.end {
+        return OVERLAPPING;
+    }
+
+    let before_tss: bool = location.mid() < tss;
+
+    if before_tss == (strand == "-") {
+        return DOWNSTREAM;
+    }
+
+    UPSTREAM
+}
+
+// Number of bases a location shares with the region start..=end
+fn overlap_len(location: &Location, start: u32, end: u32) -> u32 {
+    let s: u32 = cmp::max(location.start, start);
+    let e: u32 = cmp::min(location.end, end);
+
+    if e < s {
+        return 0;
+    }
+
+    e - s + 1
+}
+
+// Which UTR a position in a non-coding exon is in given the coding span
+// start..=end of its gene, reading along the gene's strand. An exon inside
+// the span, e.g. one that only some transcripts use, is just exonic.
+fn utr_class(mid: u32, strand: &str, start: u32, end: u32) -> RegionClass {
+    if mid >= start && mid <= end {
+        return RegionClass::Exonic;
+    }
+
+    if (mid < start) == (strand == "-") {
+        return RegionClass::ThreePrimeUtr;
+    }
+
+    RegionClass::FivePrimeUtr
+}
+
+// Fraction of the region start..=end that a location covers
+fn covered_frac(location: &Location, start: u32, end: u32) -> f64 {
+    overlap_len(location, start, end) as f64 / (end - start + 1) as f64
+}
+
+// Fraction of a location that lies in the region start..=end
+fn location_frac(location: &Location, start: u32, end: u32) -> f64 {
+    overlap_len(location, start, end) as f64
+        / (location.end - location.start + 1) as f64
+}
+
+// Whether a location overlaps start..=end at all
+fn overlaps(location: &Location, start: u32, end: u32) -> bool {
+    location.start <= end && location.end >= start
+}
+
+// Merges overlapping or touching inclusive intervals, returning them in
+// start order
+fn merge_intervals(mut intervals: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    intervals.sort();
+
+    let mut merged: Vec<(u32, u32)> = Vec::with_capacity(intervals.len());
+
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = cmp::max(last.1, end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    merged
+}
+
+// Joins the per gene values of an annotation. NA is only ever used on its
+// own for a location with no genes so it can never be sorted in amongst
+// real values. If there are more values than the limit, the rest are
+// counted rather than listed.
+fn join_or_na(values: &[String], limit: Option<usize>, separator: &str) -> String {
+    if values.is_empty() {
+        return NA.to_owned();
+    }
+
+    match limit {
+        Some(limit) if values.len() > limit => format!(
+            "{} (+{} more)",
+            values[..limit].join(separator),
+            values.len() - limit
+        ),
+        _ => values.join(separator),
+    }
+}
+
+// The label of a location given the parts of a gene it is in, where
+// exon_class is the part of an exon, if any, the location is in. Exonic
+// locations are not also labelled intronic.
+fn make_label(
+    is_promoter: bool,
+    exon_class: Option<RegionClass>,
+    is_intronic: bool,
+    custom_labels: &[String],
+) -> PromoterLabel {
+    let mut classes: Vec<RegionClass> = Vec::with_capacity(2);
 
     if is_promoter {
-        labels.push(PROMOTER);
+        classes.push(RegionClass::Promoter);
     }
 
-    if is_exon {
-        labels.push(EXONIC);
+    if let Some(exon_class) = exon_class {
+        classes.push(exon_class);
     } else {
         if is_intronic {
-            labels.push(INTRONIC);
+            classes.push(RegionClass::Intronic);
         }
     }
 
-    return labels.join(",");
+    PromoterLabel::new(classes, custom_labels.to_vec())
 }
@@ -1,52 +1,206 @@
 use std::{
+    collections::{BTreeMap, HashMap, HashSet},
     error::Error,
     fmt::{self, Display},
     string::FromUtf8Error,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 
+#[cfg(feature = "table")]
 use csv::IntoInnerError;
 use dna::Location;
 use r2d2::PooledConnection;
 use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::OpenFlags;
 
 use serde::Serialize;
 
-const WITHIN_GENE_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start 
+// Features overlapping a location at all, including features lying
+// entirely inside it such as single base annotations where start == end
+const WITHIN_GENE_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start, {score} 
     FROM genes 
-    WHERE level = ? AND chr = ? AND ((start <= ? AND end >= ?) OR (start <= ? AND end >= ?)) 
+    WHERE level = ? AND chr = ? AND start <= ? AND end >= ?{biotype} 
     ORDER BY start ASC"#;
 
-const WITHIN_GENE_AND_PROMOTER_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start 
+// Features whose extent padded by a number of bp overlaps any part of a
+// location
+const WITHIN_GENE_AND_PROMOTER_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start, {score} 
     FROM genes 
-    WHERE level = ? AND chr = ? AND ((start - ? <= ? AND end + ? >= ?) OR (start - ? <= ? AND end + ? >= ?)) 
+    WHERE level = ? AND chr = ? AND start - ? <= ? AND end + ? >= ?{biotype} 
     ORDER BY start ASC"#;
 
-const IN_EXON_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start 
+// Features whose padded extent contains the midpoint of a location, which
+// is bound once as ?1 along with the pad as ?4
+const WITHIN_GENE_AND_PROMOTER_MID_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ?1 - stranded_start, {score} 
     FROM genes 
-    WHERE level=3 AND gene_id=? AND chr=? AND ((start <= ? AND end >= ?) OR (start <= ? AND end >= ?)) 
+    WHERE level = ?2 AND chr = ?3 AND start - ?4 <= ?1 AND end + ?4 >= ?1{biotype} 
     ORDER BY start ASC"#;
 
-const IN_PROMOTER_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start 
+const IN_EXON_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start, {score} 
+    FROM genes 
+    WHERE level=? AND gene_id=? AND chr=? AND start <= ? AND end >= ? 
+    ORDER BY start ASC"#;
+
+// The exons of every gene overlapping a location
+const IN_EXONS_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start, {score} 
+    FROM genes 
+    WHERE level=? AND chr=? AND start <= ? AND end >= ? 
+    ORDER BY start ASC"#;
+
+// The span of a gene's features at a level, e.g. its CDS
+const CODING_BOUNDS_SQL: &str =
+    r#"SELECT MIN(start), MAX(end) FROM genes WHERE level=? AND gene_id=? AND chr=?"#;
+
+const IN_PROMOTER_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start, {score} 
     FROM genes 
     WHERE level=2 AND gene_id=? AND chr=? AND ? >= stranded_start - ? AND ? <= stranded_start + ? 
     ORDER BY start ASC"#;
 
-const CLOSEST_GENE_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start 
+const CLOSEST_GENE_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start, {score} 
+	FROM genes
+	WHERE level=? AND chr=? AND ABS(stranded_start - ?) BETWEEN ? AND ?{biotype}
+	ORDER BY ABS(stranded_start - ?), gene_id, start 
+	LIMIT ?"#;
+
+// On a circular contig of the given length, e.g. a bacterial chromosome or
+// plasmid, ordering is by whichever is shorter of the linear and the
+// wrap-around distance
+const CLOSEST_GENE_CIRCULAR_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start, {score} 
 	FROM genes
-	WHERE level=? AND chr=?
-	ORDER BY ABS(stranded_start - ?) 
+	WHERE level=? AND chr=? AND MIN(ABS(stranded_start - ?), ? - ABS(stranded_start - ?)) BETWEEN ? AND ?{biotype}
+	ORDER BY MIN(ABS(stranded_start - ?), ? - ABS(stranded_start - ?)), gene_id, start 
 	LIMIT ?"#;
 
+const CLOSEST_GENE_ON_STRAND_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start, {score} 
+	FROM genes
+	WHERE level=? AND chr=? AND strand=?{biotype}
+	ORDER BY ABS(stranded_start - ?), gene_id, start 
+	LIMIT 1"#;
+
+// The nearest TSS before, i.e. at a lower coordinate than, a position
+const FLANKING_BEFORE_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start, {score} 
+	FROM genes
+	WHERE level=? AND chr=? AND stranded_start < ?{biotype}
+	ORDER BY stranded_start DESC, gene_id, start 
+	LIMIT 1"#;
+
+// The nearest TSS at or after a position
+const FLANKING_AFTER_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start, {score} 
+	FROM genes
+	WHERE level=? AND chr=? AND stranded_start >= ?{biotype}
+	ORDER BY stranded_start ASC, gene_id, start 
+	LIMIT 1"#;
+
+const CLOSEST_TRANSCRIPT_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start, {score} 
+	FROM genes
+	WHERE level=2 AND gene_id=? AND chr=?
+	ORDER BY ABS(stranded_start - ?), start 
+	LIMIT 1"#;
+
+// Features by gene symbol or id rather than position, so there is no
+// location to measure a distance from
+const GENE_BY_SYMBOL_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, 0, {score} 
+	FROM genes
+	WHERE level=? AND gene_symbol=? COLLATE NOCASE
+	ORDER BY chr, start"#;
+
+const GENE_BY_ID_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, 0, {score} 
+	FROM genes
+	WHERE level=? AND gene_id=?
+	ORDER BY chr, start"#;
+
+// The canonical transcript of a gene, either the one flagged in the
+// canonical column or, for databases without one, the longest
+const CANONICAL_TRANSCRIPT_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, 0, {score} 
+	FROM genes
+	WHERE level=? AND chr=? AND gene_id=? AND canonical = 1{biotype}
+	ORDER BY start
+	LIMIT 1"#;
+
+const LONGEST_TRANSCRIPT_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, 0, {score} 
+	FROM genes
+	WHERE level=? AND chr=? AND gene_id=?{biotype}
+	ORDER BY end - start DESC, start
+	LIMIT 1"#;
+
+// The canonical transcripts of several genes at once, as for
+// CANONICAL_TRANSCRIPT_SQL and LONGEST_TRANSCRIPT_SQL, with {gene_ids}
+// standing for a placeholder per gene. The first row of each gene is its
+// canonical transcript.
+const CANONICAL_TRANSCRIPTS_SQL: &str = r#"SELECT gene_id, id 
+	FROM genes
+	WHERE level=? AND chr=? AND gene_id IN ({gene_ids}) AND canonical = 1{biotype}
+	ORDER BY gene_id, start"#;
+
+const LONGEST_TRANSCRIPTS_SQL: &str = r#"SELECT gene_id, id 
+	FROM genes
+	WHERE level=? AND chr=? AND gene_id IN ({gene_ids}){biotype}
+	ORDER BY gene_id, end - start DESC, start"#;
+
+const HAS_CANONICAL_SQL: &str =
+    r#"SELECT EXISTS(SELECT 1 FROM pragma_table_info('genes') WHERE name = 'canonical')"#;
+
+const HAS_LEVEL_SQL: &str = r#"SELECT EXISTS(SELECT 1 FROM genes WHERE level = ?)"#;
+
+const HAS_CHR_SQL: &str = r#"SELECT EXISTS(SELECT 1 FROM genes WHERE chr = ?)"#;
+
+const SAMPLE_CHR_SQL: &str = r#"SELECT chr FROM genes LIMIT 1"#;
+
+const CHRS_SQL: &str = r#"SELECT DISTINCT chr FROM genes ORDER BY chr"#;
+
+const GENES_COLUMNS_SQL: &str = r#"SELECT name FROM pragma_table_info('genes')"#;
+
+// The columns every query relies on
+const REQUIRED_COLUMNS: [&str; 9] = [
+    "id",
+    "chr",
+    "start",
+    "end",
+    "strand",
+    "gene_id",
+    "gene_symbol",
+    "stranded_start",
+    "level",
+];
+
+const HAS_BIOTYPE_SQL: &str =
+    r#"SELECT EXISTS(SELECT 1 FROM pragma_table_info('genes') WHERE name = 'biotype')"#;
+
+const BIOTYPES_SQL: &str =
+    r#"SELECT DISTINCT biotype FROM genes WHERE biotype IS NOT NULL ORDER BY biotype"#;
+
+const LEVELS_SQL: &str = r#"SELECT DISTINCT level FROM genes ORDER BY level"#;
+
+const MAX_CHR_EXTENT_SQL: &str = r#"SELECT MAX(end) FROM genes"#;
+
+const CONTAINING_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, ? - stranded_start, {score} 
+	FROM genes
+	WHERE level=? AND chr=? AND start <= ? AND end >= ?{biotype}
+	ORDER BY ABS(stranded_start - ?), gene_id, start"#;
+
+// Every feature on a chromosome overlapping a span. The stranded start is
+// returned in place of the distance so the distance to each of several
+// locations in the span can be worked out afterwards.
+const GENES_IN_SPAN_SQL: &str = r#"SELECT id, chr, start, end, strand, gene_id, gene_symbol, stranded_start, {score} 
+	FROM genes
+	WHERE level=? AND chr=? AND start <= ? AND end >= ?{biotype}
+	ORDER BY start ASC"#;
+
 #[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Strand {
     Plus = 1,
     Neg = 2,
 }
 
+// Anything other than "-", e.g. "+" or "." for unstranded features, is
+// treated as the + strand, as it is when measuring TSS distances
 impl From<&str> for Strand {
-    fn from(level: &str) -> Self {
-        match level {
-            "-" => Strand::Plus,
+    fn from(strand: &str) -> Self {
+        match strand {
+            "-" => Strand::Neg,
             _ => Strand::Plus,
         }
     }
@@ -100,6 +254,10 @@ impl fmt::Display for Level {
     }
 }
 
+// Longest promoter offset a TSSRegion can have. Anything larger would take
+// in most of a chromosome and is almost certainly a unit mistake.
+pub const MAX_TSS_OFFSET: u32 = 1_000_000;
+
 #[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
 pub struct TSSRegion {
     offset_5p: u32,
@@ -107,19 +265,87 @@ pub struct TSSRegion {
 }
 
 impl TSSRegion {
-    pub fn new(offset_5p: u32, offset_3p: u32) -> Self {
-        return TSSRegion {
-            offset_5p: offset_5p,
-            offset_3p: offset_3p,
-        };
+    /// A window of offset_5p bp upstream and offset_3p bp downstream of the
+    /// TSS. Offsets over MAX_TSS_OFFSET are rejected.
+    ///
+    /// ```
+    /// use genes::loctogene::TSSRegion;
+    ///
+    /// assert!(TSSRegion::new(2000, 1000).is_ok());
+    /// assert!(TSSRegion::new(2000, 5_000_000).is_err());
+    /// ```
+    pub fn new(offset_5p: u32, offset_3p: u32) -> GenesResult<Self> {
+        for offset in [offset_5p, offset_3p] {
+            if offset > MAX_TSS_OFFSET {
+                return Err(GenesError::FormatError(format!(
+                    "promoter offset of {} bp is over the maximum of {} bp",
+                    offset, MAX_TSS_OFFSET
+                )));
+            }
+        }
+
+        Ok(TSSRegion {
+            offset_5p,
+            offset_3p,
+        })
+    }
+
+    /// A window given in kb upstream (5') and downstream (3') of the TSS,
+    /// rounded to the nearest bp.
+    ///
+    /// ```
+    /// use genes::loctogene::TSSRegion;
+    ///
+    /// assert_eq!(TSSRegion::from_kb(2.5, 0.5).unwrap(), TSSRegion::new(2500, 500).unwrap());
+    /// ```
+    pub fn from_kb(upstream_kb: f64, downstream_kb: f64) -> GenesResult<Self> {
+        let mut offsets: [u32; 2] = [0, 0];
+
+        for (i, kb) in [upstream_kb, downstream_kb].into_iter().enumerate() {
+            let bp: f64 = (kb * 1000.0).round();
+
+            if !bp.is_finite() || bp < 0.0 || bp > MAX_TSS_OFFSET as f64 {
+                return Err(GenesError::FormatError(format!(
+                    "promoter offset of {} kb is not between 0 and {} kb",
+                    kb,
+                    MAX_TSS_OFFSET / 1000
+                )));
+            }
+
+            offsets[i] = bp as u32;
+        }
+
+        TSSRegion::new(offsets[0], offsets[1])
+    }
+
+    /// A window of bp either side of the TSS.
+    ///
+    /// ```
+    /// use genes::loctogene::TSSRegion;
+    ///
+    /// assert_eq!(TSSRegion::symmetric(1000).unwrap(), TSSRegion::new(1000, 1000).unwrap());
+    /// ```
+    pub fn symmetric(bp: u32) -> GenesResult<Self> {
+        TSSRegion::new(bp, bp)
+    }
+
+    /// A window of bp upstream (5') of the TSS only.
+    ///
+    /// ```
+    /// use genes::loctogene::TSSRegion;
+    ///
+    /// assert_eq!(TSSRegion::upstream_only(2000).unwrap(), TSSRegion::new(2000, 0).unwrap());
+    /// ```
+    pub fn upstream_only(bp: u32) -> GenesResult<Self> {
+        TSSRegion::new(bp, 0)
     }
 
     pub fn offset_5p(self) -> u32 {
-        return self.offset_5p;
+        self.offset_5p
     }
 
     pub fn offset_3p(self) -> u32 {
-        return self.offset_3p;
+        self.offset_3p
     }
 }
 
@@ -143,7 +369,7 @@ impl fmt::Display for TSSRegion {
 //    offset_3p: 1000,
 //};
 
-#[derive(Serialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct GenomicFeature {
     pub id: u32,
     pub chr: String,
@@ -153,6 +379,7 @@ pub struct GenomicFeature {
     pub gene_id: String,
     pub gene_symbol: String,
     pub dist: i32,
+    pub score: Option<f64>,
 }
 
 // #[derive(Serialize)]
@@ -169,6 +396,8 @@ pub struct GenomicFeature {
 pub enum GenesError {
     DatabaseError(String),
     FormatError(String),
+    // a lookup that ran but matched nothing, e.g. an unknown gene id
+    NotFound(String),
 }
 
 impl Error for GenesError {}
@@ -178,33 +407,100 @@ impl Display for GenesError {
         match self {
             GenesError::DatabaseError(error) => write!(f, "{}", error),
             GenesError::FormatError(error) => write!(f, "{}", error),
+            GenesError::NotFound(error) => write!(f, "{}", error),
         }
     }
 }
 
+#[cfg(feature = "table")]
 impl From<csv::Error> for GenesError {
     fn from(e: csv::Error) -> GenesError {
-        return GenesError::FormatError(e.to_string());
+        GenesError::FormatError(e.to_string())
     }
 }
 
 impl From<FromUtf8Error> for GenesError {
     fn from(e: FromUtf8Error) -> GenesError {
-        return GenesError::FormatError(e.to_string());
+        GenesError::FormatError(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for GenesError {
+    fn from(e: serde_json::Error) -> GenesError {
+        GenesError::FormatError(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for GenesError {
+    fn from(e: std::io::Error) -> GenesError {
+        GenesError::FormatError(e.to_string())
     }
 }
 
+#[cfg(feature = "table")]
 impl<W> From<IntoInnerError<W>> for GenesError {
     fn from(e: IntoInnerError<W>) -> GenesError {
-        return GenesError::FormatError(e.to_string());
+        GenesError::FormatError(e.to_string())
+    }
+}
+
+// Hashable copy of the coordinates of a location for keying results
+#[derive(Serialize, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone)]
+pub struct LocationKey {
+    pub chr: String,
+    pub start: u32,
+    pub end: u32,
+}
+
+impl From<&Location> for LocationKey {
+    fn from(location: &Location) -> Self {
+        LocationKey {
+            chr: location.chr.to_owned(),
+            start: location.start,
+            end: location.end,
+        }
     }
 }
 
 pub type GenesResult<T> = Result<T, GenesError>;
 pub type FeaturesResult = GenesResult<Vec<GenomicFeature>>;
 
+// Numbers in-memory databases so each gets its own shared cache
+static IN_MEMORY_DBS: AtomicUsize = AtomicUsize::new(0);
+
+const CREATE_GENES_SQL: &str = r#"CREATE TABLE genes (
+    id INTEGER PRIMARY KEY,
+    level INTEGER NOT NULL,
+    chr TEXT NOT NULL,
+    start INTEGER NOT NULL,
+    end INTEGER NOT NULL,
+    strand TEXT NOT NULL,
+    gene_id TEXT NOT NULL,
+    gene_symbol TEXT,
+    stranded_start INTEGER NOT NULL)"#;
+
+const INSERT_GENE_SQL: &str = r#"INSERT INTO genes (level, chr, start, end, strand, gene_id, gene_symbol, stranded_start) 
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?)"#;
+
+// Connections from_file keeps open at most, enough for the default number
+// of annotation threads plus a couple of callers
+const FROM_FILE_MAX_CONNECTIONS: u32 = 10;
+
+// The features of each chromosome at each level held by the chromosome
+// cache, see LoctogeneDb::with_chr_cache, and whether they were filtered
+// by biotype
+type ChrCache = HashMap<(String, u8, bool), Arc<Vec<GenomicFeature>>>;
+
 pub struct LoctogeneDb {
     pool: r2d2::Pool<SqliteConnectionManager>,
+    score_column: Option<String>,
+    circular: HashMap<String, u32>,
+    overlap_priority: bool,
+    exon_level: u8,
+    query_count: AtomicUsize,
+    biotypes: Option<Vec<String>>,
+    chr_cache: Option<Mutex<ChrCache>>,
+    chr_prefix: Option<bool>,
 }
 
 impl LoctogeneDb {
@@ -221,282 +517,1651 @@ impl LoctogeneDb {
             Err(_) => return Err(GenesError::DatabaseError(format!("{} not found", file))),
         };
 
-        Ok(Self { pool })
+        Ok(Self::from_pool(pool))
     }
 
-    // pub fn get_genes_within_stranded(
-    //     &self,
-    //     location: &Location,
-    //     strand: Strand,
-    //     level: Level,
-    // ) -> Result<Vec<GenomicFeature>, Box<dyn Error>> {
-    //     let mid: u32 = location.mid();
-
-    //     let pool: r2d2::PooledConnection<SqliteConnectionManager> = self.pool.get()?;
-
-    //     let mut stmt: rusqlite::Statement<'_> = pool.prepare(WITHIN_GENE_STRANDED_SQL)?;
+    // Opens an existing database read only. Unlike new, which would create
+    // an empty database, a missing file is an error naming the path. If
+    // validate_schema is set, the file must also look like a loctogene
+    // database, see validate_schema.
+    pub fn from_file(path: &str, validate_schema: bool) -> GenesResult<Self> {
+        if !std::path::Path::new(path).is_file() {
+            return Err(GenesError::DatabaseError(format!("{} not found", path)));
+        }
 
-    //     let mapped_rows = stmt.query_map(
-    //         rusqlite::params![
-    //             mid,
-    //             level as u8,
-    //             location.chr,
-    //             strand.to_string(),
-    //             location.start,
-    //             location.start,
-    //             location.end,
-    //             location.end
-    //         ],
-    //         |row: &rusqlite::Row<'_>| row_to_feature(row),
-    //     )?;
+        let manager: SqliteConnectionManager = SqliteConnectionManager::file(path)
+            .with_flags(OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX);
 
-    //     let features: Vec<GenomicFeature> = mapped_rows
-    //         .filter_map(|x: Result<GenomicFeature, rusqlite::Error>| x.ok())
-    //         .collect::<Vec<GenomicFeature>>();
+        let pool: r2d2::Pool<SqliteConnectionManager> = match r2d2::Pool::builder()
+            .max_size(FROM_FILE_MAX_CONNECTIONS)
+            .build(manager)
+        {
+            Ok(pool) => pool,
+            Err(_) => return Err(GenesError::DatabaseError(format!("could not open {}", path))),
+        };
 
-    //     Ok(features)
-    // }
+        let db: LoctogeneDb = Self::from_pool(pool);
 
-    pub fn conn(&self) -> GenesResult<PooledConnection<SqliteConnectionManager>> {
-        match self.pool.get() {
-            Ok(pool) => Ok(pool),
-            Err(_) => Err(GenesError::DatabaseError(format!("error getting pool"))),
+        if validate_schema {
+            db.validate_schema()?;
         }
-    }
 
-    pub fn get_genes_within(&self, location: &Location, level: &Level) -> FeaturesResult {
-        let mid: u32 = location.mid();
+        Ok(db)
+    }
 
+    // Checks the database has a genes table with every column the queries
+    // use, so opening the wrong file fails with a clear message rather
+    // than every query failing later
+    pub fn validate_schema(&self) -> GenesResult<()> {
         let pool = self.conn()?;
 
-        let mut stmt = stmt(&pool, WITHIN_GENE_SQL)?;
+        let mut stmt = self.stmt(&pool, GENES_COLUMNS_SQL)?;
 
-        let mapped_rows = match stmt.query_map(
-            rusqlite::params![
-                mid,
-                *level as u8,
-                location.chr,
-                location.start,
-                location.start,
-                location.end,
-                location.end
-            ],
-            |row| row_to_feature(row),
-        ) {
+        let mapped_rows = match stmt.query_map([], |row| row.get(0)) {
             Ok(mapped_rows) => mapped_rows,
-            Err(_) => return Err(GenesError::DatabaseError(format!("error getting rows"))),
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
         };
 
-        let features: Vec<GenomicFeature> = mapped_rows
+        let columns: Vec<String> = mapped_rows
             .filter_map(|x| x.ok())
-            .collect::<Vec<GenomicFeature>>();
+            .collect::<Vec<String>>();
 
-        Ok(features)
+        if columns.is_empty() {
+            return Err(GenesError::DatabaseError(
+                "not a loctogene database: there is no genes table".to_string(),
+            ));
+        }
+
+        let missing: Vec<&str> = REQUIRED_COLUMNS
+            .iter()
+            .filter(|column| !columns.iter().any(|c| c == *column))
+            .copied()
+            .collect();
+
+        if !missing.is_empty() {
+            return Err(GenesError::DatabaseError(format!(
+                "not a loctogene database: the genes table has no {} column",
+                missing.join(", ")
+            )));
+        }
+
+        Ok(())
     }
 
-    pub fn get_genes_within_promoter(
-        &self,
-        location: &Location,
-        level: &Level,
-        pad: u32,
-    ) -> FeaturesResult {
-        let mid: u32 = location.mid();
+    // A database held in memory containing the given features, each at its
+    // level, e.g. for tests or small gene sets that have no file. Ids are
+    // assigned in the order of the features, and the stranded start of
+    // each is its start, or its end for - strand features. The id and dist
+    // of the features given are ignored.
+    pub fn in_memory_with(features: &[(Level, GenomicFeature)]) -> GenesResult<Self> {
+        // connections to a plain in-memory database each get their own
+        // empty one, so share a named one between the pooled connections
+        let uri: String = format!(
+            "file:genes_in_memory_{}?mode=memory&cache=shared",
+            IN_MEMORY_DBS.fetch_add(1, Ordering::Relaxed)
+        );
+
+        let manager: SqliteConnectionManager = SqliteConnectionManager::file(&uri).with_flags(
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_CREATE
+                | OpenFlags::SQLITE_OPEN_URI,
+        );
+
+        // the database is dropped with its last connection, so never close
+        // idle ones
+        let pool: r2d2::Pool<SqliteConnectionManager> = match r2d2::Pool::builder()
+            .max_size(FROM_FILE_MAX_CONNECTIONS)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .build(manager)
+        {
+            Ok(pool) => pool,
+            Err(_) => {
+                return Err(GenesError::DatabaseError(
+                    "error creating in-memory database".to_string(),
+                ))
+            }
+        };
 
-        let pool = self.conn()?;
+        let db: LoctogeneDb = Self::from_pool(pool);
 
-        let mut stmt = stmt(&pool, WITHIN_GENE_AND_PROMOTER_SQL)?;
+        let mut conn = db.conn()?;
 
-        let mapped_rows = match stmt.query_map(
-            rusqlite::params![
-                mid,
-                *level as u8,
-                location.chr,
-                pad,
-                location.start,
-                pad,
-                location.start,
-                pad,
-                location.end,
-                pad,
-                location.end
-            ],
-            |row| row_to_feature(row),
-        ) {
-            Ok(mapped_rows) => mapped_rows,
-            Err(_) => return Err(GenesError::DatabaseError(format!("error getting rows"))),
+        let tx = match conn.transaction() {
+            Ok(tx) => tx,
+            Err(_) => return Err(GenesError::DatabaseError("error creating genes".to_string())),
         };
 
-        let features: Vec<GenomicFeature> = mapped_rows
-            .filter_map(|x| x.ok())
-            .collect::<Vec<GenomicFeature>>();
+        if tx.execute_batch(CREATE_GENES_SQL).is_err() {
+            return Err(GenesError::DatabaseError("error creating genes".to_string()));
+        }
 
-        Ok(features)
-    }
+        for (level, feature) in features {
+            let stranded_start: u32 = if feature.strand == "-" {
+                feature.end
+            } else {
+                feature.start
+            };
+
+            if tx
+                .execute(
+                    INSERT_GENE_SQL,
+                    rusqlite::params![
+                        *level as u8,
+                        feature.chr,
+                        feature.start,
+                        feature.end,
+                        feature.strand,
+                        feature.gene_id,
+                        feature.gene_symbol,
+                        stranded_start
+                    ],
+                )
+                .is_err()
+            {
+                return Err(GenesError::DatabaseError(format!(
+                    "error adding {}",
+                    feature.gene_id
+                )));
+            }
+        }
 
-    // Returns the exons that a location is in within a particular gene. Useful
-    // for determining if a gene is exonic or not.
-    pub fn in_exon(&self, location: &Location, gene_id: &str) -> FeaturesResult {
-        let mid: u32 = location.mid();
+        if tx.commit().is_err() {
+            return Err(GenesError::DatabaseError("error creating genes".to_string()));
+        }
 
-        let pool = self.conn()?;
+        drop(conn);
 
-        let mut stmt = stmt(&pool, IN_EXON_SQL)?;
+        Ok(db)
+    }
 
-        let mapped_rows = match stmt.query_map(
-            rusqlite::params![
-                mid,
-                gene_id,
-                location.chr,
-                location.start,
-                location.start,
-                location.end,
-                location.end
-            ],
-            |row| row_to_feature(row),
-        ) {
-            Ok(mapped_rows) => mapped_rows,
-            Err(_) => return Err(GenesError::DatabaseError(format!("error getting rows"))),
-        };
+    fn from_pool(pool: r2d2::Pool<SqliteConnectionManager>) -> Self {
+        Self {
+            pool,
+            score_column: None,
+            circular: HashMap::new(),
+            overlap_priority: false,
+            exon_level: Level::Exon as u8,
+            query_count: AtomicUsize::new(0),
+            biotypes: None,
+            chr_cache: None,
+            chr_prefix: None,
+        }
+    }
 
-        let features: Vec<GenomicFeature> = mapped_rows
-            .filter_map(|x: Result<GenomicFeature, rusqlite::Error>| x.ok())
-            .collect::<Vec<GenomicFeature>>();
+    // Treats a chromosome, e.g. a bacterial chromosome or plasmid, as
+    // circular with the given length so its distances are the shorter of
+    // the linear and wrap-around distance. Call once per circular
+    // chromosome. Chromosomes are linear by default. The name is matched
+    // with the same chr normalization as queries whenever this is called
+    // relative to with_chr_normalization.
+    pub fn with_circular(mut self, chr: &str, length: u32) -> Self {
+        self.circular.insert(chr.to_owned(), length);
+        self
+    }
 
-        Ok(features)
+    // Ranks genes whose body contains the midpoint of a location ahead of
+    // all others in get_closest_genes, since a location inside a gene most
+    // likely belongs to it even if another gene has a closer TSS.
+    pub fn with_overlap_priority(mut self, overlap_priority: bool) -> Self {
+        self.overlap_priority = overlap_priority;
+        self
     }
 
-    // Returns a list of features if location is in tss of specific gene
-    pub fn in_promoter(
-        &self,
-        location: &Location,
-        gene_id: &str,
-        tss_region: &TSSRegion,
-    ) -> FeaturesResult {
-        let mid: u32 = location.mid();
+    // Keeps every feature of a chromosome in memory the first time it is
+    // queried so later get_genes_within, get_genes_within_promoter_mid,
+    // in_exon(s) and get_closest_genes calls on the same chromosome, i.e.
+    // the queries annotate makes, need no query. Worth it for many
+    // locations sorted by chromosome. The database is read only, so the
+    // cache only goes stale if the biotypes change, see with_biotypes.
+    pub fn with_chr_cache(mut self, chr_cache: bool) -> Self {
+        self.chr_cache = if chr_cache {
+            Some(Mutex::new(HashMap::new()))
+        } else {
+            None
+        };
+
+        self
+    }
 
+    // Makes in_exon look at features of the given level, for example CDS
+    // features stored at their own level, so that exonic means coding. If
+    // the database has no features at that level, in_exon keeps using
+    // exons.
+    pub fn with_cds_level(mut self, level: u8) -> GenesResult<Self> {
         let pool = self.conn()?;
 
-        let mut stmt1 = stmt(&pool, IN_PROMOTER_SQL)?;
+        let mut stmt = self.stmt(&pool, HAS_LEVEL_SQL)?;
 
-        let mapped_rows_1 = match stmt1.query_map(
-            rusqlite::params![
-                mid,
-                gene_id,
-                location.chr,
-                mid,
-                tss_region.offset_5p,
-                mid,
-                tss_region.offset_3p,
-            ],
-            |row| row_to_feature(row),
-        ) {
-            Ok(mapped_rows) => mapped_rows,
-            Err(_) => return Err(GenesError::DatabaseError(format!("error getting rows"))),
+        let has_level: bool = match stmt.query_row(rusqlite::params![level], |row| row.get(0)) {
+            Ok(has_level) => has_level,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
         };
 
-        let features_pos = mapped_rows_1.filter_map(|x| x.ok());
+        if has_level {
+            self.exon_level = level;
+        }
 
-        let mut stmt2 = stmt(&pool, IN_PROMOTER_SQL)?;
+        Ok(self)
+    }
 
-        // negative strand so flip tss region
-        let mapped_rows_2 = match stmt2.query_map(
-            rusqlite::params![
-                mid,
-                gene_id,
-                location.chr,
-                mid,
-                tss_region.offset_3p,
-                mid,
-                tss_region.offset_5p,
-            ],
-            |row| row_to_feature(row),
-        ) {
-            Ok(mapped_rows) => mapped_rows,
-            Err(_) => return Err(GenesError::DatabaseError(format!("error getting rows"))),
-        };
+    // Restricts gene queries to features whose biotype column is one of
+    // the given biotypes, e.g. protein_coding. None removes the filter.
+    // Empties the chromosome cache, whose filtered features were chosen by
+    // the previous biotypes. A database without a biotype column cannot be
+    // filtered and is an error.
+    pub fn with_biotypes(mut self, biotypes: Option<Vec<String>>) -> GenesResult<Self> {
+        if biotypes.is_some() && !self.has_biotype()? {
+            return Err(GenesError::FormatError(
+                "cannot filter by biotype, the genes table has no biotype column".to_string(),
+            ));
+        }
 
-        let features_neg =
-            mapped_rows_2.filter_map(|x: Result<GenomicFeature, rusqlite::Error>| x.ok());
+        self.biotypes = biotypes;
 
-        let features: Vec<GenomicFeature> = features_pos
-            .chain(features_neg)
-            .collect::<Vec<GenomicFeature>>();
+        if self.chr_cache.is_some() {
+            self.chr_cache = Some(Mutex::new(HashMap::new()));
+        }
 
-        Ok(features)
+        Ok(self)
     }
 
-    pub fn get_closest_genes(
-        &self,
-        location: &dna::Location,
-        n: u16,
-        level: Level,
-    ) -> FeaturesResult {
-        let mid: u32 = location.mid();
+    // Adds or strips the chr prefix of query chromosomes so that chr3 and 3
+    // both match whichever naming the database uses. The convention is
+    // detected from one row of the database when this is called.
+    pub fn with_chr_normalization(mut self, normalize: bool) -> GenesResult<Self> {
+        if !normalize {
+            self.chr_prefix = None;
+            return Ok(self);
+        }
 
         let pool = self.conn()?;
 
-        let mut stmt = stmt(&pool, CLOSEST_GENE_SQL)?;
+        let mut stmt = self.stmt(&pool, SAMPLE_CHR_SQL)?;
 
-        // query_map converts rusqlite into a standard iterator
-        let mapped_rows = match stmt.query_map(
-            rusqlite::params![mid, level as u8, location.chr, mid, n],
-            |row| row_to_feature(row),
-        ) {
-            Ok(mapped_rows) => mapped_rows,
-            Err(_) => return Err(GenesError::DatabaseError(format!("error getting rows"))),
+        let chr: Option<String> = match stmt.query_row([], |row| row.get(0)) {
+            Ok(chr) => Some(chr),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
         };
 
-        // filter map because the query returns an iterator of results
-        // and if element is ok, the data is the feature record. Use
-        // filter map to keep only the valid records and convert them to
-        // actual data by removing the Ok wrapper
-        let features: Vec<GenomicFeature> = mapped_rows
-            .filter_map(|x| x.ok())
-            .collect::<Vec<GenomicFeature>>();
+        // an empty database has no convention to follow so leave queries alone
+        self.chr_prefix = chr.map(|chr| chr.starts_with("chr"));
 
-        Ok(features)
+        Ok(self)
     }
 
-    // Returns element
-}
+    // The chromosome name as stored in the database
+    fn db_chr(&self, chr: &str) -> String {
+        match self.chr_prefix {
+            Some(true) if !chr.starts_with("chr") => format!("chr{}", chr),
+            Some(false) => chr.strip_prefix("chr").unwrap_or(chr).to_owned(),
+            _ => chr.to_owned(),
+        }
+    }
 
-fn stmt<'a>(
-    conn: &'a r2d2::PooledConnection<SqliteConnectionManager>,
-    sql: &'a str,
-) -> GenesResult<rusqlite::CachedStatement<'a>> {
-    match conn.prepare_cached(sql) {
-        Ok(stmt) => Ok(stmt),
-        Err(_) => {
-            return Err(GenesError::DatabaseError(format!(
-                "error preparing statement"
-            )))
+    // Whether the database has any features on a chromosome
+    pub fn has_chr(&self, chr: &str) -> GenesResult<bool> {
+        let pool = self.conn()?;
+
+        let mut stmt = self.stmt(&pool, HAS_CHR_SQL)?;
+
+        match stmt.query_row(rusqlite::params![self.db_chr(chr)], |row| row.get(0)) {
+            Ok(has_chr) => Ok(has_chr),
+            Err(_) => Err(GenesError::DatabaseError("error getting rows".to_string())),
         }
     }
-}
 
-// pub fn unwrap_stmt<'a>(
-//     stmt: Result<rusqlite::CachedStatement<'a>, rusqlite::Error>
-// ) -> GenesResult<rusqlite::CachedStatement<'a>> {
+    // Whether the genes table has a canonical column flagging the
+    // canonical transcript of each gene
+    pub fn has_canonical(&self) -> GenesResult<bool> {
+        let pool = self.conn()?;
 
-//     match stmt {
-//         Ok(stmt) => Ok(stmt),
-//         Err(_) => {
-//             Err(GenesError::DatabaseError(format!(
+        let mut stmt = self.stmt(&pool, HAS_CANONICAL_SQL)?;
+
+        match stmt.query_row([], |row| row.get(0)) {
+            Ok(has_canonical) => Ok(has_canonical),
+            Err(_) => Err(GenesError::DatabaseError("error getting rows".to_string())),
+        }
+    }
+
+    // Whether the genes table has a biotype column, see with_biotypes
+    pub fn has_biotype(&self) -> GenesResult<bool> {
+        let pool = self.conn()?;
+
+        let mut stmt = self.stmt(&pool, HAS_BIOTYPE_SQL)?;
+
+        match stmt.query_row([], |row| row.get(0)) {
+            Ok(has_biotype) => Ok(has_biotype),
+            Err(_) => Err(GenesError::DatabaseError("error getting rows".to_string())),
+        }
+    }
+
+    // The chromosomes in the database
+    pub fn chromosomes(&self) -> GenesResult<Vec<String>> {
+        let pool = self.conn()?;
+
+        let mut stmt = self.stmt(&pool, CHRS_SQL)?;
+
+        let mapped_rows = match stmt.query_map([], |row| row.get(0)) {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        let chrs: Vec<String> = mapped_rows
+            .filter_map(|x| x.ok())
+            .collect::<Vec<String>>();
+
+        Ok(chrs)
+    }
+
+    // The biotypes of the features in the database, e.g. to offer as
+    // filters for with_biotypes. Empty if the database has no biotype
+    // column.
+    pub fn distinct_biotypes(&self) -> GenesResult<Vec<String>> {
+        if !self.has_biotype()? {
+            return Ok(vec![]);
+        }
+
+        let pool = self.conn()?;
+
+        let mut stmt = self.stmt(&pool, BIOTYPES_SQL)?;
+
+        let mapped_rows = match stmt.query_map([], |row| row.get(0)) {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        let biotypes: Vec<String> = mapped_rows
+            .filter_map(|x| x.ok())
+            .collect::<Vec<String>>();
+
+        Ok(biotypes)
+    }
+
+    // The levels of the features in the database, gene first
+    pub fn distinct_levels(&self) -> GenesResult<Vec<Level>> {
+        let pool = self.conn()?;
+
+        let mut stmt = self.stmt(&pool, LEVELS_SQL)?;
+
+        let mapped_rows = match stmt.query_map([], |row| row.get::<usize, u8>(0)) {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        let levels: Vec<Level> = mapped_rows
+            .filter_map(|x| x.ok())
+            .map(Level::from)
+            .collect::<Vec<Level>>();
+
+        Ok(levels)
+    }
+
+    // The largest end of any feature, so how far along its chromosome the
+    // genes of the longest chromosome reach, or None for an empty database.
+    // Databases do not store chromosome lengths, so this is only a lower
+    // bound on the length of the longest chromosome.
+    pub fn max_chr_extent(&self) -> GenesResult<Option<u32>> {
+        let pool = self.conn()?;
+
+        let mut stmt = self.stmt(&pool, MAX_CHR_EXTENT_SQL)?;
+
+        match stmt.query_row([], |row| row.get(0)) {
+            Ok(extent) => Ok(extent),
+            Err(_) => Err(GenesError::DatabaseError("error getting rows".to_string())),
+        }
+    }
+
+    // The length of a chromosome if it is circular, see with_circular
+    pub fn circular(&self, chr: &str) -> Option<u32> {
+        if self.circular.is_empty() {
+            return None;
+        }
+
+        let chr: String = self.db_chr(chr);
+
+        self
+            .circular
+            .iter()
+            .find(|(name, _)| self.db_chr(name) == chr)
+            .map(|(_, length)| *length)
+    }
+
+    // Converts a signed linear distance on a chromosome to the shorter way
+    // round if the chromosome is circular. Distances on linear chromosomes
+    // are returned unchanged.
+    pub fn wrap_dist(&self, chr: &str, d: i32) -> i32 {
+        match self.circular(chr) {
+            Some(length) => {
+                let length: i64 = length as i64;
+                let d: i64 = d as i64;
+
+                if d > length / 2 {
+                    (d - length) as i32
+                } else if d < -length / 2 {
+                    (d + length) as i32
+                } else {
+                    d as i32
+                }
+            }
+            None => d,
+        }
+    }
+
+    // Reads an extra numeric column, e.g. a conservation or expression
+    // score, into the score field of each feature. Without it the score
+    // is always None.
+    pub fn with_score_column(mut self, column: &str) -> GenesResult<Self> {
+        if column.is_empty() || !column.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(GenesError::FormatError(format!(
+                "{} is not a valid score column",
+                column
+            )));
+        }
+
+        self.score_column = Some(column.to_owned());
+
+        Ok(self)
+    }
+
+    // pub fn get_genes_within_stranded(
+    //     &self,
+    //     location: &Location,
+    //     strand: Strand,
+    //     level: Level,
+    // ) -> Result<Vec<GenomicFeature>, Box<dyn Error>> {
+    //     let mid: u32 = location.mid();
+
+    //     let pool: r2d2::PooledConnection<SqliteConnectionManager> = self.pool.get()?;
+
+    //     let mut stmt: rusqlite::Statement<'_> = pool.prepare(WITHIN_GENE_STRANDED_SQL)?;
+
+    //     let mapped_rows = stmt.query_map(
+    //         rusqlite::params![
+    //             mid,
+    //             level as u8,
+    //             location.chr,
+    //             strand.to_string(),
+    //             location.start,
+    //             location.start,
+    //             location.end,
+    //             location.end
+    //         ],
+    //         |row: &rusqlite::Row<'_>| row_to_feature(row),
+    //     )?;
+
+    //     let features: Vec<GenomicFeature> = mapped_rows
+    //         .filter_map(|x: Result<GenomicFeature, rusqlite::Error>| x.ok())
+    //         .collect::<Vec<GenomicFeature>>();
+
+    //     Ok(features)
+    // }
+
+    // Fills in the optional score column and biotype filter of a query
+    // template. The biotypes are bound as parameters numbered after the
+    // template's own, so the ? of a filtered template are numbered too,
+    // see bind.
+    fn sql(&self, template: &str) -> String {
+        let score: &str = match &self.score_column {
+            Some(column) => column,
+            None => "NULL",
+        };
+
+        let sql: String = template.replace("{score}", score);
+
+        match &self.biotypes {
+            Some(biotypes) if sql.contains("{biotype}") => {
+                let (sql, n): (String, usize) = number_params(&sql);
+
+                let placeholders: Vec<String> =
+                    (1..=biotypes.len()).map(|i| format!("?{}", n + i)).collect();
+
+                sql.replace(
+                    "{biotype}",
+                    &format!(" AND biotype IN ({})", placeholders.join(",")),
+                )
+            }
+            _ => sql.replace("{biotype}", ""),
+        }
+    }
+
+    // The parameters of a query made from a template by sql, i.e. the
+    // given ones followed by the biotypes if the template filters on them
+    fn bind<'a>(
+        &'a self,
+        template: &str,
+        params: &[&'a dyn rusqlite::ToSql],
+    ) -> Vec<&'a dyn rusqlite::ToSql> {
+        let mut bound: Vec<&dyn rusqlite::ToSql> = params.to_vec();
+
+        if let Some(biotypes) = &self.biotypes {
+            if template.contains("{biotype}") {
+                bound.extend(biotypes.iter().map(|b| b as &dyn rusqlite::ToSql));
+            }
+        }
+
+        bound
+    }
+
+    // The number of statements run against the database so far, useful for
+    // checking how much work an annotation does
+    pub fn query_count(&self) -> usize {
+        self.query_count.load(Ordering::Relaxed)
+    }
+
+    fn stmt<'a>(
+        &self,
+        conn: &'a r2d2::PooledConnection<SqliteConnectionManager>,
+        sql: &str,
+    ) -> GenesResult<rusqlite::CachedStatement<'a>> {
+        self.query_count.fetch_add(1, Ordering::Relaxed);
+
+        match conn.prepare_cached(sql) {
+            Ok(stmt) => Ok(stmt),
+            Err(_) => {
+                Err(GenesError::DatabaseError("error preparing statement".to_string()))
+            }
+        }
+    }
+
+    pub fn conn(&self) -> GenesResult<PooledConnection<SqliteConnectionManager>> {
+        match self.pool.get() {
+            Ok(pool) => Ok(pool),
+            Err(_) => Err(GenesError::DatabaseError("error getting pool".to_string())),
+        }
+    }
+
+    // Features overlapping any part of a location. Note annotate instead
+    // uses get_genes_within_promoter_mid, which goes by the midpoint of the
+    // location, so for wide locations some of these are not annotated
+    // unless Annotate::with_interval_overlap is on.
+    pub fn get_genes_within(&self, location: &Location, level: &Level) -> FeaturesResult {
+        let mid: u32 = location.mid();
+
+        if let Some(features) = self.cached_chr(&self.db_chr(&location.chr), *level as u8, true)? {
+            let features: Vec<GenomicFeature> = features
+                .iter()
+                .filter(|f| f.start <= location.end && f.end >= location.start)
+                .map(|f| with_mid_dist(f, mid))
+                .collect::<Vec<GenomicFeature>>();
+
+            return Ok(features);
+        }
+
+        let pool = self.conn()?;
+
+        let sql: String = self.sql(WITHIN_GENE_SQL);
+
+        let mut stmt = self.stmt(&pool, &sql)?;
+
+        let mapped_rows = match stmt.query_map(
+            self.bind(
+                WITHIN_GENE_SQL,
+                rusqlite::params![
+                    mid,
+                    *level as u8,
+                    self.db_chr(&location.chr),
+                    location.end,
+                    location.start,
+                ],
+            )
+            .as_slice(),
+            row_to_feature,
+        ) {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        let features: Vec<GenomicFeature> = collect_features(mapped_rows)?;
+
+        Ok(features)
+    }
+
+    // Every feature of a level on a chromosome in start order, if the
+    // chromosome cache is on. Loaded from the database on first use. The
+    // dist of each feature is its stranded start, see with_mid_dist. Only
+    // features of the chosen biotypes are kept if filtered, which is what
+    // the gene queries want but not the exon ones.
+    fn cached_chr(
+        &self,
+        chr: &str,
+        level: u8,
+        filtered: bool,
+    ) -> GenesResult<Option<Arc<Vec<GenomicFeature>>>> {
+        let cache = match &self.chr_cache {
+            Some(cache) => cache,
+            None => return Ok(None),
+        };
+
+        let key: (String, u8, bool) = (chr.to_owned(), level, filtered);
+
+        match cache.lock() {
+            Ok(cache) => {
+                if let Some(features) = cache.get(&key) {
+                    return Ok(Some(features.clone()));
+                }
+            }
+            Err(_) => {
+                return Err(GenesError::DatabaseError("chromosome cache unavailable".to_string()))
+            }
+        };
+
+        let pool = self.conn()?;
+
+        let template: String = if filtered {
+            GENES_IN_SPAN_SQL.to_owned()
+        } else {
+            GENES_IN_SPAN_SQL.replace("{biotype}", "")
+        };
+
+        let sql: String = self.sql(&template);
+
+        let mut stmt = self.stmt(&pool, &sql)?;
+
+        let mapped_rows = match stmt.query_map(
+            self.bind(&template, rusqlite::params![level, chr, u32::MAX, 0]).as_slice(),
+            row_to_feature,
+        ) {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        let features: Arc<Vec<GenomicFeature>> = Arc::new(collect_features(mapped_rows)?);
+
+        match cache.lock() {
+            Ok(mut cache) => {
+                cache.insert(key, features.clone());
+            }
+            Err(_) => {
+                return Err(GenesError::DatabaseError("chromosome cache unavailable".to_string()))
+            }
+        };
+
+        Ok(Some(features))
+    }
+
+    // Same as get_genes_within for many locations at once, but with one
+    // query per chromosome covering all of its locations. Genes are then
+    // assigned to each location in memory using the same overlap rule.
+    pub fn get_genes_within_genome(
+        &self,
+        locations: &[Location],
+        level: &Level,
+    ) -> GenesResult<HashMap<LocationKey, Vec<GenomicFeature>>> {
+        let mut chr_map: BTreeMap<&str, Vec<&Location>> = BTreeMap::new();
+
+        for location in locations {
+            chr_map
+                .entry(location.chr.as_str())
+                .or_default()
+                .push(location);
+        }
+
+        let pool = self.conn()?;
+
+        let sql: String = self.sql(GENES_IN_SPAN_SQL);
+
+        let mut ret: HashMap<LocationKey, Vec<GenomicFeature>> = HashMap::new();
+
+        for (chr, chr_locations) in chr_map.iter() {
+            let start: u32 = chr_locations.iter().map(|l| l.start).min().unwrap_or(0);
+            let end: u32 = chr_locations.iter().map(|l| l.end).max().unwrap_or(0);
+
+            let mut stmt = self.stmt(&pool, &sql)?;
+
+            let mapped_rows = match stmt.query_map(
+                self.bind(
+                    GENES_IN_SPAN_SQL,
+                    rusqlite::params![*level as u8, self.db_chr(chr), end, start],
+                )
+                .as_slice(),
+                row_to_feature,
+            ) {
+                Ok(mapped_rows) => mapped_rows,
+                Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+            };
+
+            let features: Vec<GenomicFeature> = collect_features(mapped_rows)?;
+
+            for location in chr_locations {
+                let mid: u32 = location.mid();
+
+                let within: Vec<GenomicFeature> = features
+                    .iter()
+                    .filter(|f| f.start <= location.end && f.end >= location.start)
+                    .map(|f| with_mid_dist(f, mid))
+                    .collect::<Vec<GenomicFeature>>();
+
+                ret.insert(LocationKey::from(*location), within);
+            }
+        }
+
+        Ok(ret)
+    }
+
+    // Features within a location by interval overlap: those whose extent,
+    // padded by pad bp on both sides to take in their promoters, overlaps
+    // any part of the location. Annotate uses this when
+    // Annotate::with_interval_overlap is on.
+    pub fn get_genes_within_promoter(
+        &self,
+        location: &Location,
+        level: &Level,
+        pad: u32,
+    ) -> FeaturesResult {
+        let mid: u32 = location.mid();
+
+        let pool = self.conn()?;
+
+        let sql: String = self.sql(WITHIN_GENE_AND_PROMOTER_SQL);
+
+        let mut stmt = self.stmt(&pool, &sql)?;
+
+        let mapped_rows = match stmt.query_map(
+            self.bind(
+                WITHIN_GENE_AND_PROMOTER_SQL,
+                rusqlite::params![
+                    mid,
+                    *level as u8,
+                    self.db_chr(&location.chr),
+                    pad,
+                    location.end,
+                    pad,
+                    location.start,
+                ],
+            )
+            .as_slice(),
+            row_to_feature,
+        ) {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        let features: Vec<GenomicFeature> = collect_features(mapped_rows)?;
+
+        Ok(features)
+    }
+
+    // Features within a location by midpoint: those whose extent, padded by
+    // pad bp on both sides, contains the midpoint of the location. This is
+    // what annotate classifies on by default, so unlike
+    // get_genes_within_promoter it does not fetch features that only
+    // overlap the ends of a wide location and would be thrown away.
+    pub fn get_genes_within_promoter_mid(
+        &self,
+        location: &Location,
+        level: &Level,
+        pad: u32,
+    ) -> FeaturesResult {
+        let mid: u32 = location.mid();
+
+        if let Some(features) = self.cached_chr(&self.db_chr(&location.chr), *level as u8, true)? {
+            let features: Vec<GenomicFeature> = features
+                .iter()
+                .filter(|f| f.start.saturating_sub(pad) <= mid && f.end.saturating_add(pad) >= mid)
+                .map(|f| with_mid_dist(f, mid))
+                .collect::<Vec<GenomicFeature>>();
+
+            return Ok(features);
+        }
+
+        let pool = self.conn()?;
+
+        let sql: String = self.sql(WITHIN_GENE_AND_PROMOTER_MID_SQL);
+
+        let mut stmt = self.stmt(&pool, &sql)?;
+
+        let mapped_rows = match stmt.query_map(
+            self.bind(
+                WITHIN_GENE_AND_PROMOTER_MID_SQL,
+                rusqlite::params![mid, *level as u8, self.db_chr(&location.chr), pad],
+            )
+            .as_slice(),
+            row_to_feature,
+        ) {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        let features: Vec<GenomicFeature> = collect_features(mapped_rows)?;
+
+        Ok(features)
+    }
+
+    // Same as in_exon, but for every gene at once with a single query, so
+    // the exons a location is in grouped by gene id
+    pub fn in_exons(
+        &self,
+        location: &Location,
+    ) -> GenesResult<HashMap<String, Vec<GenomicFeature>>> {
+        self.in_features(location, self.exon_level)
+    }
+
+    // The features of a level that a location overlaps grouped by gene id,
+    // e.g. the CDS features when they are stored at their own level. This
+    // is the lookup in_exons makes at the exon level, see with_cds_level.
+    pub fn in_features(
+        &self,
+        location: &Location,
+        level: u8,
+    ) -> GenesResult<HashMap<String, Vec<GenomicFeature>>> {
+        let mid: u32 = location.mid();
+
+        let features: Vec<GenomicFeature> =
+            match self.cached_chr(&self.db_chr(&location.chr), level, false)? {
+                Some(features) => features
+                    .iter()
+                    .filter(|f| f.start <= location.end && f.end >= location.start)
+                    .map(|f| with_mid_dist(f, mid))
+                    .collect::<Vec<GenomicFeature>>(),
+                None => {
+                    let pool = self.conn()?;
+
+                    let sql: String = self.sql(IN_EXONS_SQL);
+
+                    let mut stmt = self.stmt(&pool, &sql)?;
+
+                    let mapped_rows = match stmt.query_map(
+                        rusqlite::params![
+                            mid,
+                            level,
+                            self.db_chr(&location.chr),
+                            location.end,
+                            location.start
+                        ],
+                        row_to_feature,
+                    ) {
+                        Ok(mapped_rows) => mapped_rows,
+                        Err(_) => {
+                            return Err(GenesError::DatabaseError("error getting rows".to_string()))
+                        }
+                    };
+
+                    collect_features(mapped_rows)?
+                }
+            };
+
+        let mut ret: HashMap<String, Vec<GenomicFeature>> = HashMap::new();
+
+        for feature in features {
+            ret.entry(feature.gene_id.to_owned()).or_default().push(feature);
+        }
+
+        Ok(ret)
+    }
+
+    // Returns the exons that a location is in within a particular gene. Useful
+    // for determining if a gene is exonic or not.
+    pub fn in_exon(&self, location: &Location, gene_id: &str) -> FeaturesResult {
+        let mid: u32 = location.mid();
+
+        if let Some(features) =
+            self.cached_chr(&self.db_chr(&location.chr), self.exon_level, false)?
+        {
+            let features: Vec<GenomicFeature> = features
+                .iter()
+                .filter(|f| {
+                    f.gene_id == gene_id && f.start <= location.end && f.end >= location.start
+                })
+                .map(|f| with_mid_dist(f, mid))
+                .collect::<Vec<GenomicFeature>>();
+
+            return Ok(features);
+        }
+
+        let pool = self.conn()?;
+
+        let sql: String = self.sql(IN_EXON_SQL);
+
+        let mut stmt = self.stmt(&pool, &sql)?;
+
+        let mapped_rows = match stmt.query_map(
+            rusqlite::params![
+                mid,
+                self.exon_level,
+                gene_id,
+                self.db_chr(&location.chr),
+                location.end,
+                location.start
+            ],
+            row_to_feature,
+        ) {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        let features: Vec<GenomicFeature> = collect_features(mapped_rows)?;
+
+        Ok(features)
+    }
+
+    // The distance from the midpoint of a location to the nearest edge of an
+    // exon of a gene, 0 inside an exon. It is negative when the location is
+    // upstream of the gene's first exon, reading along the gene's strand,
+    // and positive otherwise, e.g. in an intron. None if the gene has no
+    // exons on the chromosome of the location.
+    pub fn nearest_exon_distance(
+        &self,
+        location: &Location,
+        gene_id: &str,
+    ) -> GenesResult<Option<i32>> {
+        let mid: i32 = location.mid() as i32;
+
+        let chr: String = self.db_chr(&location.chr);
+
+        let exons: Vec<GenomicFeature> = match self.get_gene_by_id(gene_id, Level::Exon) {
+            Ok(exons) => exons.into_iter().filter(|exon| exon.chr == chr).collect(),
+            Err(GenesError::NotFound(_)) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let dist: i32 = match exons
+            .iter()
+            .map(|exon| {
+                if mid < exon.start as i32 {
+                    exon.start as i32 - mid
+                } else if mid > exon.end as i32 {
+                    mid - exon.end as i32
+                } else {
+                    0
+                }
+            })
+            .min()
+        {
+            Some(dist) => dist,
+            None => return Ok(None),
+        };
+
+        let upstream: bool = if exons[0].strand == "-" {
+            exons.iter().all(|exon| mid > exon.end as i32)
+        } else {
+            exons.iter().all(|exon| mid < exon.start as i32)
+        };
+
+        Ok(Some(if upstream { -dist } else { dist }))
+    }
+
+    // The start of the first and end of the last feature of a gene at a
+    // level on the chromosome of a location, e.g. the coding bounds when
+    // CDS features are stored at their own level. None if the gene has no
+    // features at that level.
+    pub fn get_coding_bounds(
+        &self,
+        location: &Location,
+        gene_id: &str,
+        level: u8,
+    ) -> GenesResult<Option<(u32, u32)>> {
+        let pool = self.conn()?;
+
+        let mut stmt = self.stmt(&pool, CODING_BOUNDS_SQL)?;
+
+        let bounds: (Option<u32>, Option<u32>) = match stmt.query_row(
+            rusqlite::params![level, gene_id, self.db_chr(&location.chr)],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ) {
+            Ok(bounds) => bounds,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        match bounds {
+            (Some(start), Some(end)) => Ok(Some((start, end))),
+            _ => Ok(None),
+        }
+    }
+
+    // Returns a list of features if location is in tss of specific gene
+    pub fn in_promoter(
+        &self,
+        location: &Location,
+        gene_id: &str,
+        tss_region: &TSSRegion,
+    ) -> FeaturesResult {
+        let mid: u32 = location.mid();
+
+        let pool = self.conn()?;
+
+        let sql: String = self.sql(IN_PROMOTER_SQL);
+
+        let mut stmt1 = self.stmt(&pool, &sql)?;
+
+        let mapped_rows_1 = match stmt1.query_map(
+            rusqlite::params![
+                mid,
+                gene_id,
+                self.db_chr(&location.chr),
+                mid,
+                tss_region.offset_5p,
+                mid,
+                tss_region.offset_3p,
+            ],
+            row_to_feature,
+        ) {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        let features_pos: Vec<GenomicFeature> = collect_features(mapped_rows_1)?;
+
+        let mut stmt2 = self.stmt(&pool, &sql)?;
+
+        // negative strand so flip tss region
+        let mapped_rows_2 = match stmt2.query_map(
+            rusqlite::params![
+                mid,
+                gene_id,
+                self.db_chr(&location.chr),
+                mid,
+                tss_region.offset_3p,
+                mid,
+                tss_region.offset_5p,
+            ],
+            row_to_feature,
+        ) {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        let features_neg: Vec<GenomicFeature> = collect_features(mapped_rows_2)?;
+
+        let features: Vec<GenomicFeature> = features_pos
+            .into_iter()
+            .chain(features_neg)
+            .collect::<Vec<GenomicFeature>>();
+
+        Ok(features)
+    }
+
+    pub fn get_closest_genes(
+        &self,
+        location: &dna::Location,
+        n: u16,
+        level: Level,
+    ) -> FeaturesResult {
+        self.get_closest_genes_within(location, n, level, None, None)
+    }
+
+    // Same as get_closest_genes, but only considering genes whose TSS is at
+    // least min_dist and at most max_dist bp from the midpoint, e.g.
+    // min_dist = 1 to skip a gene starting exactly at the location. None
+    // leaves that side unbounded.
+    pub fn get_closest_genes_within(
+        &self,
+        location: &dna::Location,
+        n: u16,
+        level: Level,
+        min_dist: Option<u32>,
+        max_dist: Option<u32>,
+    ) -> FeaturesResult {
+        let mid: u32 = location.mid();
+
+        let min_dist: u32 = min_dist.unwrap_or(0);
+        let max_dist: u32 = max_dist.unwrap_or(u32::MAX);
+
+        if self.circular(&location.chr).is_none() {
+            if let Some(features) =
+                self.cached_chr(&self.db_chr(&location.chr), level as u8, true)?
+            {
+                let mut features: Vec<GenomicFeature> = features
+                    .iter()
+                    .map(|f| with_mid_dist(f, mid))
+                    .filter(|f| within_dist(f, min_dist, max_dist))
+                    .collect::<Vec<GenomicFeature>>();
+
+                // same order as the closest gene query
+                features.sort_by(|a, b| {
+                    a.dist
+                        .abs()
+                        .cmp(&b.dist.abs())
+                        .then_with(|| a.gene_id.cmp(&b.gene_id))
+                        .then_with(|| a.start.cmp(&b.start))
+                });
+
+                let mut features: Vec<GenomicFeature> = dedup_gene_ids(features);
+
+                features.truncate(n as usize);
+
+                if self.overlap_priority {
+                    return self.prioritize_overlaps(
+                        location, features, n, level, min_dist, max_dist,
+                    );
+                }
+
+                return Ok(features);
+            }
+        }
+
+        // a gene can have several rows at the same level, e.g. in the
+        // pseudoautosomal regions, so fetch extra rows to still have n
+        // distinct genes once duplicates are removed, fetching more if not
+        let mut limit: u32 = 2 * n as u32;
+
+        let features: Vec<GenomicFeature> = loop {
+            let rows: Vec<GenomicFeature> =
+                self.query_closest_genes(location, level, min_dist, max_dist, limit)?;
+
+            let fetched: usize = rows.len();
+
+            let mut features: Vec<GenomicFeature> = dedup_gene_ids(rows);
+
+            if features.len() >= n as usize || fetched < limit as usize {
+                features.truncate(n as usize);
+                break features;
+            }
+
+            limit *= 2;
+        };
+
+        if self.overlap_priority {
+            return self.prioritize_overlaps(location, features, n, level, min_dist, max_dist);
+        }
+
+        Ok(features)
+    }
+
+    // The closest gene query of get_closest_genes_within returning up to
+    // limit rows
+    fn query_closest_genes(
+        &self,
+        location: &Location,
+        level: Level,
+        min_dist: u32,
+        max_dist: u32,
+        limit: u32,
+    ) -> FeaturesResult {
+        let mid: u32 = location.mid();
+
+        let pool = self.conn()?;
+
+        let circular: Option<u32> = self.circular(&location.chr);
+
+        let sql: String = match circular {
+            Some(_) => self.sql(CLOSEST_GENE_CIRCULAR_SQL),
+            None => self.sql(CLOSEST_GENE_SQL),
+        };
+
+        let mut stmt = self.stmt(&pool, &sql)?;
+
+        // query_map converts rusqlite into a standard iterator
+        let mapped_rows = match circular {
+            Some(length) => stmt.query_map(
+                self.bind(
+                    CLOSEST_GENE_CIRCULAR_SQL,
+                    rusqlite::params![
+                        mid,
+                        level as u8,
+                        self.db_chr(&location.chr),
+                        mid,
+                        length,
+                        mid,
+                        min_dist,
+                        max_dist,
+                        mid,
+                        length,
+                        mid,
+                        limit
+                    ],
+                )
+                .as_slice(),
+                row_to_feature,
+            ),
+            None => stmt.query_map(
+                self.bind(
+                    CLOSEST_GENE_SQL,
+                    rusqlite::params![
+                        mid,
+                        level as u8,
+                        self.db_chr(&location.chr),
+                        mid,
+                        min_dist,
+                        max_dist,
+                        mid,
+                        limit
+                    ],
+                )
+                .as_slice(),
+                row_to_feature,
+            ),
+        };
+
+        let mapped_rows = match mapped_rows {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        // filter map because the query returns an iterator of results
+        // and if element is ok, the data is the feature record. Use
+        // filter map to keep only the valid records and convert them to
+        // actual data by removing the Ok wrapper
+        let features: Vec<GenomicFeature> = collect_features(mapped_rows)?
+            .into_iter()
+            .map(|mut feature| {
+                feature.dist = self.wrap_dist(&feature.chr, feature.dist);
+                feature
+            })
+            .collect::<Vec<GenomicFeature>>();
+
+        Ok(features)
+    }
+
+    // The gene on a given strand whose TSS is closest to the midpoint of a
+    // location, if the chromosome has any genes on that strand
+    pub fn get_closest_gene_on_strand(
+        &self,
+        location: &Location,
+        strand: &str,
+        level: Level,
+    ) -> GenesResult<Option<GenomicFeature>> {
+        let mid: u32 = location.mid();
+
+        let pool = self.conn()?;
+
+        let sql: String = self.sql(CLOSEST_GENE_ON_STRAND_SQL);
+
+        let mut stmt = self.stmt(&pool, &sql)?;
+
+        let mapped_rows = match stmt.query_map(
+            self.bind(
+                CLOSEST_GENE_ON_STRAND_SQL,
+                rusqlite::params![mid, level as u8, self.db_chr(&location.chr), strand, mid],
+            )
+            .as_slice(),
+            row_to_feature,
+        ) {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        let feature: Option<GenomicFeature> = collect_features(mapped_rows)?
+            .into_iter()
+            .map(|mut feature| {
+                feature.dist = self.wrap_dist(&feature.chr, feature.dist);
+                feature
+            })
+            .next();
+
+        Ok(feature)
+    }
+
+    // The genes with the nearest TSS on either side of the midpoint of a
+    // location, the first before it and the second at or after it in
+    // chromosome coordinates, so unlike get_closest_genes both sides are
+    // always represented. A side is None if the location lies nearer the
+    // end of the chromosome than any TSS on that side, including when it is
+    // inside the only gene there.
+    pub fn get_flanking_genes(
+        &self,
+        location: &Location,
+        level: Level,
+    ) -> GenesResult<(Option<GenomicFeature>, Option<GenomicFeature>)> {
+        let before: Option<GenomicFeature> =
+            self.get_flanking_gene(location, level, FLANKING_BEFORE_SQL)?;
+
+        let after: Option<GenomicFeature> =
+            self.get_flanking_gene(location, level, FLANKING_AFTER_SQL)?;
+
+        Ok((before, after))
+    }
+
+    fn get_flanking_gene(
+        &self,
+        location: &Location,
+        level: Level,
+        template: &str,
+    ) -> GenesResult<Option<GenomicFeature>> {
+        let mid: u32 = location.mid();
+
+        let pool = self.conn()?;
+
+        let sql: String = self.sql(template);
+
+        let mut stmt = self.stmt(&pool, &sql)?;
+
+        let mapped_rows = match stmt.query_map(
+            self.bind(
+                template,
+                rusqlite::params![mid, level as u8, self.db_chr(&location.chr), mid],
+            )
+            .as_slice(),
+            row_to_feature,
+        ) {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        let feature: Option<GenomicFeature> = collect_features(mapped_rows)?.into_iter().next();
+
+        Ok(feature)
+    }
+
+    // The transcript of a gene whose TSS is closest to the midpoint of a
+    // location
+    pub fn get_closest_transcript(
+        &self,
+        location: &Location,
+        gene_id: &str,
+    ) -> GenesResult<Option<GenomicFeature>> {
+        let mid: u32 = location.mid();
+
+        let pool = self.conn()?;
+
+        let sql: String = self.sql(CLOSEST_TRANSCRIPT_SQL);
+
+        let mut stmt = self.stmt(&pool, &sql)?;
+
+        let mapped_rows = match stmt.query_map(
+            rusqlite::params![mid, gene_id, self.db_chr(&location.chr), mid],
+            row_to_feature,
+        ) {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        let feature: Option<GenomicFeature> = collect_features(mapped_rows)?
+            .into_iter()
+            .map(|mut feature| {
+                feature.dist = self.wrap_dist(&feature.chr, feature.dist);
+                feature
+            })
+            .next();
+
+        Ok(feature)
+    }
+
+    // The features of a gene at a level looked up by symbol, ignoring case,
+    // so for example every transcript of a gene. A symbol on several
+    // chromosomes, e.g. in the pseudoautosomal regions, gives the features
+    // on each ordered by chromosome then start. The distances are 0. An
+    // unknown symbol is a NotFound error.
+    pub fn get_gene_by_symbol(&self, symbol: &str, level: Level) -> FeaturesResult {
+        self.get_gene_by(GENE_BY_SYMBOL_SQL, "gene symbol", symbol, level)
+    }
+
+    // Same as get_gene_by_symbol, but by gene id, which is matched exactly
+    pub fn get_gene_by_id(&self, gene_id: &str, level: Level) -> FeaturesResult {
+        self.get_gene_by(GENE_BY_ID_SQL, "gene id", gene_id, level)
+    }
+
+    // The canonical transcript of a gene on a chromosome. If flagged, this is
+    // the transcript flagged in the canonical column (see has_canonical)
+    // and None if the gene has none, otherwise it is the longest transcript.
+    pub fn get_canonical_transcript(
+        &self,
+        chr: &str,
+        gene_id: &str,
+        flagged: bool,
+    ) -> GenesResult<Option<GenomicFeature>> {
+        let pool = self.conn()?;
+
+        let template: &str = if flagged {
+            CANONICAL_TRANSCRIPT_SQL
+        } else {
+            LONGEST_TRANSCRIPT_SQL
+        };
+
+        let sql: String = self.sql(template);
+
+        let mut stmt = self.stmt(&pool, &sql)?;
+
+        match stmt.query_row(
+            self.bind(
+                template,
+                rusqlite::params![Level::Transcript as u8, self.db_chr(chr), gene_id],
+            )
+            .as_slice(),
+            row_to_feature,
+        ) {
+            Ok(feature) => Ok(Some(feature)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(_) => Err(GenesError::DatabaseError("error getting rows".to_string())),
+        }
+    }
+
+    // Same as get_canonical_transcript, but for several genes on a
+    // chromosome with a single query. Gives the id of the canonical
+    // transcript of each gene, leaving out genes without one.
+    pub fn get_canonical_transcript_ids(
+        &self,
+        chr: &str,
+        gene_ids: &[&str],
+        flagged: bool,
+    ) -> GenesResult<HashMap<String, u32>> {
+        let mut ids: HashMap<String, u32> = HashMap::new();
+
+        if gene_ids.is_empty() {
+            return Ok(ids);
+        }
+
+        let pool = self.conn()?;
+
+        let template: &str = if flagged {
+            CANONICAL_TRANSCRIPTS_SQL
+        } else {
+            LONGEST_TRANSCRIPTS_SQL
+        };
+
+        let placeholders: Vec<&str> = gene_ids.iter().map(|_| "?").collect();
+
+        let template: String = template.replace("{gene_ids}", &placeholders.join(","));
+
+        let sql: String = self.sql(&template);
+
+        let mut stmt = self.stmt(&pool, &sql)?;
+
+        let level: u8 = Level::Transcript as u8;
+        let chr: String = self.db_chr(chr);
+
+        let mut params: Vec<&dyn rusqlite::ToSql> = vec![&level, &chr];
+
+        for gene_id in gene_ids.iter() {
+            params.push(gene_id);
+        }
+
+        let mapped_rows = match stmt.query_map(self.bind(&template, &params).as_slice(), |row| {
+            Ok((row.get::<usize, String>(0)?, row.get::<usize, u32>(1)?))
+        }) {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        for row in mapped_rows {
+            let (gene_id, id) = match row {
+                Ok(row) => row,
+                Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+            };
+
+            ids.entry(gene_id).or_insert(id);
+        }
+
+        Ok(ids)
+    }
+
+    fn get_gene_by(&self, template: &str, what: &str, key: &str, level: Level) -> FeaturesResult {
+        let pool = self.conn()?;
+
+        let sql: String = self.sql(template);
+
+        let mut stmt = self.stmt(&pool, &sql)?;
+
+        let mapped_rows = match stmt.query_map(rusqlite::params![level as u8, key], row_to_feature)
+        {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        let features: Vec<GenomicFeature> = collect_features(mapped_rows)?;
+
+        if features.is_empty() {
+            return Err(GenesError::NotFound(format!(
+                "{} {} not found at {} level",
+                what, key, level
+            )));
+        }
+
+        Ok(features)
+    }
+
+    // Reorders the closest genes so those containing the midpoint come
+    // first, in TSS distance order, followed by the rest. Containing genes
+    // outside the distance bounds are left out as they are for the closest.
+    fn prioritize_overlaps(
+        &self,
+        location: &Location,
+        closest: Vec<GenomicFeature>,
+        n: u16,
+        level: Level,
+        min_dist: u32,
+        max_dist: u32,
+    ) -> FeaturesResult {
+        let mid: u32 = location.mid();
+
+        let pool = self.conn()?;
+
+        let sql: String = self.sql(CONTAINING_SQL);
+
+        let mut stmt = self.stmt(&pool, &sql)?;
+
+        let mapped_rows = match stmt.query_map(
+            self.bind(
+                CONTAINING_SQL,
+                rusqlite::params![mid, level as u8, self.db_chr(&location.chr), mid, mid, mid],
+            )
+            .as_slice(),
+            row_to_feature,
+        ) {
+            Ok(mapped_rows) => mapped_rows,
+            Err(_) => return Err(GenesError::DatabaseError("error getting rows".to_string())),
+        };
+
+        let mut features: Vec<GenomicFeature> = collect_features(mapped_rows)?
+            .into_iter()
+            .map(|mut feature| {
+                feature.dist = self.wrap_dist(&feature.chr, feature.dist);
+                feature
+            })
+            .filter(|feature| within_dist(feature, min_dist, max_dist))
+            .collect::<Vec<GenomicFeature>>();
+
+        for feature in closest {
+            if !features.iter().any(|f| f.id == feature.id) {
+                features.push(feature);
+            }
+        }
+
+        let mut features: Vec<GenomicFeature> = dedup_gene_ids(features);
+
+        features.truncate(n as usize);
+
+        Ok(features)
+    }
+
+    // Returns element
+}
+
+
+// pub fn unwrap_stmt<'a>(
+//     stmt: Result<rusqlite::CachedStatement<'a>, rusqlite::Error>
+// ) -> GenesResult<rusqlite::CachedStatement<'a>> {
+
+//     match stmt {
+//         Ok(stmt) => Ok(stmt),
+//         Err(_) => {
+//             Err(GenesError::DatabaseError(format!(
 //                 "error preparing statement"
 //             )))
 //         }
 //     }
 // }
 
+// Keeps the first feature of each gene id, so the closest when features
+// are in distance order
+fn dedup_gene_ids(features: Vec<GenomicFeature>) -> Vec<GenomicFeature> {
+    let mut seen: HashSet<String> = HashSet::new();
+
+    features
+        .into_iter()
+        .filter(|feature| seen.insert(feature.gene_id.to_owned()))
+        .collect()
+}
+
+// Whether the TSS distance of a feature is within min_dist..=max_dist
+fn within_dist(feature: &GenomicFeature, min_dist: u32, max_dist: u32) -> bool {
+    let dist: u32 = feature.dist.unsigned_abs();
+
+    dist >= min_dist && dist <= max_dist
+}
+
+// Copies a feature whose dist holds its stranded start, as returned by
+// GENES_IN_SPAN_SQL, with dist set to the distance from mid as the other
+// queries return it
+fn with_mid_dist(feature: &GenomicFeature, mid: u32) -> GenomicFeature {
+    let mut feature: GenomicFeature = feature.clone();
+    feature.dist = (mid as i32) - feature.dist;
+    feature
+}
+
+// Collects the features of a query. Rows that cannot be read are skipped,
+// except those with invalid coordinates, which mean the database itself is
+// malformed and so are reported.
+fn collect_features<I>(rows: I) -> FeaturesResult
+where
+    I: Iterator<Item = Result<GenomicFeature, rusqlite::Error>>,
+{
+    let mut features: Vec<GenomicFeature> = Vec::new();
+
+    for row in rows {
+        match row {
+            Ok(feature) => features.push(feature),
+            Err(rusqlite::Error::FromSqlConversionFailure(_, _, e)) => {
+                if let Some(e) = e.downcast_ref::<GenesError>() {
+                    return Err(e.clone());
+                }
+            }
+            Err(_) => (),
+        }
+    }
+
+    Ok(features)
+}
+
+// Reads a coordinate column as i64 first so negative or out of range values
+// give an error naming the feature rather than a generic conversion error
+fn row_coord(row: &rusqlite::Row<'_>, idx: usize, id: i64) -> Result<u32, rusqlite::Error> {
+    let value: i64 = row.get(idx)?;
+
+    match u32::try_from(value) {
+        Ok(value) => Ok(value),
+        Err(_) => {
+            let name: &str = match idx {
+                0 => "id",
+                2 => "start",
+                _ => "end",
+            };
+
+            Err(rusqlite::Error::FromSqlConversionFailure(
+                idx,
+                rusqlite::types::Type::Integer,
+                Box::new(GenesError::DatabaseError(format!(
+                    "feature {} has invalid {} {}",
+                    id, name, value
+                ))),
+            ))
+        }
+    }
+}
+
 fn row_to_feature(row: &rusqlite::Row<'_>) -> Result<GenomicFeature, rusqlite::Error> {
-    let id: u32 = row.get(0)?;
+    let row_id: i64 = row.get(0)?;
+    let id: u32 = row_coord(row, 0, row_id)?;
     let chr: String = row.get(1)?;
-    let start: u32 = row.get(2)?;
-    let end: u32 = row.get(3)?;
+    let start: u32 = row_coord(row, 2, row_id)?;
+    let end: u32 = row_coord(row, 3, row_id)?;
     let strand: String = row.get(4)?;
     let gene_id: String = row.get(5)?;
-    let gene_symbol: String = row.get(6)?;
+    // some features have no symbol so treat null as empty
+    let gene_symbol: String = row.get::<usize, Option<String>>(6)?.unwrap_or_default();
     let dist: i32 = row.get(7)?;
+    let score: Option<f64> = row.get(8)?;
 
     Ok(GenomicFeature {
         id,
@@ -507,5 +2172,39 @@ fn row_to_feature(row: &rusqlite::Row<'_>) -> Result<GenomicFeature, rusqlite::E
         gene_id,
         gene_symbol,
         dist,
+        score,
     })
 }
+
+// Numbers the bare ? parameters of a query in order, as SQLite would, so
+// that parameters can be added after them whatever follows in the query.
+// Returns the query and the number of parameters it takes.
+fn number_params(sql: &str) -> (String, usize) {
+    let mut numbered: String = String::with_capacity(sql.len());
+    let mut n: usize = 0;
+    let mut chars = sql.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        numbered.push(c);
+
+        if c != '?' {
+            continue;
+        }
+
+        let mut digits: String = String::new();
+
+        while let Some(d) = chars.next_if(|d| d.is_ascii_digit()) {
+            digits.push(d);
+        }
+
+        if digits.is_empty() {
+            n += 1;
+            numbered.push_str(&n.to_string());
+        } else {
+            n = n.max(digits.parse().unwrap_or(0));
+            numbered.push_str(&digits);
+        }
+    }
+
+    (numbered, n)
+}
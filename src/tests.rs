@@ -1,94 +1,4047 @@
 
 
 #[cfg(test)]
-use std::error::Error;
+use std::collections::HashMap;
 #[cfg(test)]
-use dna::Location;
+use std::io::Cursor;
 #[cfg(test)]
-use serde_json::json;
+use dna::Location;
 #[cfg(test)]
 use crate::annotate::Annotate;
 #[cfg(test)]
+use crate::annotate::AnnotateBuilder;
+#[cfg(test)]
+use crate::annotate::AnnotationColumns;
+#[cfg(test)]
+use crate::annotate::AnnotationDiff;
+#[cfg(test)]
+use crate::annotate::AssignPolicy;
+#[cfg(test)]
+use crate::annotate::Boundary;
+#[cfg(test)]
+use crate::annotate::Classifier;
+#[cfg(test)]
+use crate::annotate::ClosestGene;
+#[cfg(test)]
+use crate::annotate::compare_chrs;
+#[cfg(test)]
+use crate::annotate::CoordBase;
+#[cfg(test)]
+use crate::annotate::CoverageStats;
+#[cfg(test)]
+use crate::annotate::ExonDetail;
+#[cfg(test)]
+#[cfg(feature = "table")]
+use crate::annotate::Separators;
+#[cfg(test)]
+use crate::annotate::stranded_distance;
+#[cfg(test)]
+use crate::annotate::Liftover;
+#[cfg(test)]
+use crate::annotate::LocationFormat;
+#[cfg(test)]
+use crate::annotate::read_bed;
+#[cfg(test)]
+use crate::annotate::read_locations;
+#[cfg(test)]
+#[cfg(feature = "table")]
+use crate::annotate::read_named_locations;
+#[cfg(test)]
+#[cfg(feature = "table")]
+use crate::annotate::NamedLocation;
+#[cfg(test)]
+use crate::annotate::MissingSymbol;
+#[cfg(test)]
 use crate::annotate::GeneAnnotation;
 #[cfg(test)]
+use crate::annotate::GeneHitRow;
+#[cfg(test)]
+use crate::annotate::CDS;
+#[cfg(test)]
+use crate::annotate::EXONIC;
+#[cfg(test)]
+use crate::annotate::FIVE_PRIME_UTR;
+#[cfg(test)]
+use crate::annotate::INTERGENIC;
+#[cfg(test)]
+use crate::annotate::INTRONIC;
+#[cfg(test)]
+use crate::annotate::NA;
+#[cfg(test)]
+use crate::annotate::PromoterOverlap;
+#[cfg(test)]
+use crate::annotate::PROMOTER;
+#[cfg(test)]
+use crate::annotate::PromoterLabel;
+#[cfg(test)]
+use crate::annotate::RegionClass;
+#[cfg(test)]
+use crate::annotate::THREE_PRIME_UTR;
+#[cfg(test)]
+use crate::annotate::TranscriptAnnotation;
+#[cfg(test)]
+use crate::loctogene::GenesError;
+#[cfg(test)]
 use crate::loctogene::GenomicFeature;
 #[cfg(test)]
 use crate::loctogene::Level;
 #[cfg(test)]
+use crate::loctogene::LocationKey;
+#[cfg(test)]
 use crate::loctogene::LoctogeneDb;
 #[cfg(test)]
-
+use crate::loctogene::MAX_TSS_OFFSET;
+#[cfg(test)]
+use crate::loctogene::Strand;
 #[cfg(test)]
 use crate::loctogene::TSSRegion;
 
+// A few genes on chr3 held in memory for the query tests
+#[cfg(test)]
+fn test_features_db() -> LoctogeneDb {
+    let feature = |chr: &str, start: u32, end: u32, strand: &str, gene_id: &str| GenomicFeature {
+        id: 0,
+        chr: chr.to_owned(),
+        start,
+        end,
+        strand: strand.to_owned(),
+        gene_id: gene_id.to_owned(),
+        gene_symbol: format!("SYM{}", gene_id),
+        dist: 0,
+        score: None,
+    };
+
+    LoctogeneDb::in_memory_with(&[
+        (Level::Gene, feature("chr3", 187700000, 187750000, "+", "G1")),
+        (Level::Transcript, feature("chr3", 187700000, 187750000, "+", "G1")),
+        (Level::Exon, feature("chr3", 187745000, 187746000, "+", "G1")),
+        (Level::Gene, feature("chr3", 187760000, 187800000, "-", "G2")),
+        (Level::Transcript, feature("chr3", 187760000, 187800000, "-", "G2")),
+        (Level::Gene, feature("chr3", 187900000, 187950000, "+", "G3")),
+        (Level::Transcript, feature("chr3", 187900000, 187950000, "+", "G3")),
+    ])
+    .unwrap()
+}
+
 #[test]
-fn test_annotation() ->Result<(), Box<dyn Error>>{
-    
+fn test_in_memory_with() {
+    let genesdb: LoctogeneDb = test_features_db();
 
-    //let loc: Location = Location::parse("chr3:187721370-187733550")?;
+    let loc: Location = Location::parse("chr3:187721370-187733550").unwrap();
 
-    
+    let within: Vec<GenomicFeature> = genesdb.get_genes_within(&loc, &Level::Gene).unwrap();
 
+    assert_eq!(within.len(), 1);
+    assert_eq!(within[0].gene_id, "G1");
 
-    let loc: Location = Location::parse("chr3:187745448-187745468")?;
+    let closest: Vec<GenomicFeature> = genesdb.get_closest_genes(&loc, 10, Level::Gene).unwrap();
 
-    let genesdb: LoctogeneDb = LoctogeneDb::new("../docker-rust-edb-api/data/loctogene/grch38.db")?;
+    let ids: Vec<&str> = closest.iter().map(|r| r.gene_id.as_str()).collect();
 
+    assert_eq!(ids, vec!["G1", "G2", "G3"]);
 
     let annotatedb: Annotate = Annotate::new(genesdb, TSSRegion::default(), 10);
 
-    let annotation: GeneAnnotation = annotatedb.annotate(&loc)?;
+    let annotation: GeneAnnotation =
+        annotatedb.annotate(&Location::parse("chr3:187745448-187745468").unwrap()).unwrap();
+
+    assert_eq!(annotation.gene_ids, "G1");
+    assert_eq!(annotation.gene_symbols, "SYMG1");
+    assert_eq!(annotation.prom_labels, "exonic");
+    // fewer genes than asked for
+    assert_eq!(annotation.closest_genes.len(), 3);
+}
+
+// (level, chr, start, end, strand, gene_id, gene_symbol) for a synthetic
+// genes table
+#[cfg(test)]
+type TestRow<'a> = (u8, &'a str, u32, u32, &'a str, &'a str, &'a str);
+
+// Builds a small genes database in memory so tests do not depend on a
+// real genome database being present.
+#[cfg(test)]
+fn test_db(rows: &[TestRow]) -> LoctogeneDb {
+    test_db_with(rows, "")
+}
+
+// As test_db, but runs extra sql, e.g. to add optional columns, once the
+// rows are inserted
+#[cfg(test)]
+fn test_db_with(rows: &[TestRow], sql: &str) -> LoctogeneDb {
+    let features: Vec<(Level, GenomicFeature)> = rows
+        .iter()
+        .map(|(level, chr, start, end, strand, gene_id, gene_symbol)| {
+            let feature = GenomicFeature {
+                id: 0,
+                chr: chr.to_string(),
+                start: *start,
+                end: *end,
+                strand: strand.to_string(),
+                gene_id: gene_id.to_string(),
+                gene_symbol: gene_symbol.to_string(),
+                dist: 0,
+                score: None,
+            };
+
+            (Level::from(*level), feature)
+        })
+        .collect();
+
+    let genesdb: LoctogeneDb = LoctogeneDb::in_memory_with(&features).unwrap();
+
+    let conn = genesdb.conn().unwrap();
+
+    // levels past exons, e.g. CDS, have no Level so are set by id, which
+    // follows the order of the rows
+    for (i, (level, ..)) in rows.iter().enumerate() {
+        if Level::from(*level) as u8 != *level {
+            conn.execute("UPDATE genes SET level = ?1 WHERE id = ?2", [*level as usize, i + 1])
+                .unwrap();
+        }
+    }
+
+    conn.execute_batch(sql).unwrap();
+
+    drop(conn);
+
+    genesdb
+}
+
+// As test_db_with, but writes the database to a file in the temp dir for
+// tests that open it by path
+#[cfg(test)]
+fn test_db_file(name: &str, rows: &[TestRow], sql: &str) -> std::path::PathBuf {
+    let file = std::env::temp_dir().join(format!("genes_test_{}.db", name));
+
+    let _ = std::fs::remove_file(&file);
 
-    //let js: serde_json::Value = json!(records);
+    let genesdb: LoctogeneDb = test_db_with(rows, sql);
 
-    //println!("{}", js);
+    genesdb
+        .conn()
+        .unwrap()
+        .execute("VACUUM INTO ?1", [file.to_str().unwrap()])
+        .unwrap();
 
-    Ok(())
+    file
 }
 
+// Gene sets shared by several tests. Each gene has a gene and a transcript
+// row spanning the same bases.
+#[cfg(test)]
+const ONE_GENE_ROWS: [TestRow; 2] = [
+    (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+    (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+];
+
+// As ONE_GENE_ROWS, but 10kb further along chr1
+#[cfg(test)]
+const GENE_20KB_ROWS: [TestRow; 2] = [
+    (1, "chr1", 20000, 30000, "+", "G1", "GENE1"),
+    (2, "chr1", 20000, 30000, "+", "G1", "GENE1"),
+];
+
+// ONE_GENE_ROWS plus a - strand gene 30kb downstream of it
+#[cfg(test)]
+const TWO_GENE_ROWS: [TestRow; 4] = [
+    (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+    (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+    (1, "chr1", 50000, 60000, "-", "G2", "GENE2"),
+    (2, "chr1", 50000, 60000, "-", "G2", "GENE2"),
+];
+
+// ONE_GENE_ROWS plus a - strand gene at the same place on chr2
+#[cfg(test)]
+const TWO_CHR_ROWS: [TestRow; 4] = [
+    (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+    (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+    (1, "chr2", 10000, 20000, "-", "G2", "GENE2"),
+    (2, "chr2", 10000, 20000, "-", "G2", "GENE2"),
+];
+
 #[test]
-fn test_within() {
- 
-    let loc: Location = match Location::parse("chr3:187721370-187733550") {
-        Ok(loc)=>loc,
-        Err(err)=>panic!("{}", err)
-    };
+fn test_nearest_gene_join() {
+    let genesdb: LoctogeneDb = test_db(
+        &[
+            (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (1, "chr1", 50000, 60000, "-", "G2", "GENE2"),
+        ],
+    );
 
-    let genesdb: LoctogeneDb = match LoctogeneDb::new("../docker-rust-edb-api/data/loctogene/grch38.db") {
-        Ok(db)=>db,
-        Err(err)=>panic!("{}", err)
-    };
+    let annotatedb: Annotate = Annotate::new(genesdb, TSSRegion::default(), 5);
 
-    let records:Vec<GenomicFeature>  =  match genesdb.get_genes_within(&loc, &Level::Gene) {
-        Ok(records)=>records,
-        Err(err)=>panic!("{}", err)
-    };
+    let peaks: Vec<Location> = vec![
+        Location::parse("chr1:9000-9100").unwrap(),
+        Location::parse("chr1:61000-61100").unwrap(),
+        Location::parse("chr2:1000-1100").unwrap(),
+    ];
+
+    let joined = annotatedb.nearest_gene_join(&peaks).unwrap();
+
+    assert_eq!(joined.len(), 2);
+    assert_eq!(joined[0].1.gene_id, "G1");
+    assert_eq!(joined[1].1.gene_id, "G2");
+    assert_eq!(joined[1].0.start, 61000);
+}
+
+#[test]
+fn test_assign_gene_score_tie_break() {
+    let genesdb: LoctogeneDb = test_db_with(
+        &[
+            (1, "chr1", 20000, 30000, "+", "G1", "GENE1"),
+            (1, "chr1", 5000, 10000, "-", "G2", "GENE2"),
+        ],
+        "ALTER TABLE genes ADD COLUMN expression REAL; UPDATE genes SET expression = 1.0 WHERE gene_id = 'G1'; UPDATE genes SET expression = 5.0 WHERE gene_id = 'G2';",
+    )
+    .with_score_column("expression")
+    .unwrap();
 
-    let js: serde_json::Value = json!(records);
+    let annotatedb: Annotate =
+        Annotate::new(genesdb, TSSRegion::default(), 5).with_score_tie_break(true);
 
-    println!("{}", js);
+    // both TSS are 5kb from the peak midpoint
+    let loc: Location = Location::parse("chr1:15000-15000").unwrap();
 
+    let gene: GenomicFeature = annotatedb.assign_gene(&loc).unwrap().unwrap();
+
+    assert_eq!(gene.gene_id, "G2");
+    assert_eq!(gene.score, Some(5.0));
 }
 
 #[test]
-fn test_closest() {
-    let loc: Location = match Location::parse("chr3:187721370-187733550") {
-        Ok(loc)=>loc,
-        Err(err)=>panic!("{}", err)
-    };
+fn test_assign_policy() {
+    let rows = [
+        (1, "chr1", 10000, 30000, "+", "A", "GENEA"),
+        (1, "chr1", 25000, 40000, "+", "B", "GENEB"),
+    ];
 
-    let genesdb: LoctogeneDb = match LoctogeneDb::new("../docker-rust-edb-api/data/loctogene/grch38.db") {
-        Ok(db)=>db,
-        Err(err)=>panic!("{}", err)
+    // midpoint is in the body of A and 500 bp upstream of the TSS of B
+    let loc: Location = Location::parse("chr1:24450-24550").unwrap();
+
+    let assigned = |policy: AssignPolicy| -> String {
+        Annotate::new(test_db(&rows), TSSRegion::default(), 5)
+            .with_assign_policy(policy)
+            .assign_gene(&loc)
+            .unwrap()
+            .unwrap()
+            .gene_id
     };
 
-    let records:Vec<GenomicFeature>  =  match genesdb.get_closest_genes(&loc, 10, Level::Gene) {
-        Ok(records)=>records,
-        Err(err)=>panic!("{}", err)
+    assert_eq!(assigned(AssignPolicy::ClosestTss), "B");
+    assert_eq!(assigned(AssignPolicy::GeneBody), "A");
+    assert_eq!(assigned(AssignPolicy::Promoter), "B");
+}
+
+#[test]
+fn test_category_diff() {
+    let annotatedb: Annotate = Annotate::new(test_db(&ONE_GENE_ROWS), TSSRegion::default(), 5);
+
+    let locations: Vec<Location> = vec![
+        // 1.5 kb upstream of the TSS
+        Location::parse("chr1:8450-8550").unwrap(),
+        Location::parse("chr1:15000-15100").unwrap(),
+        Location::parse("chr1:50000-50100").unwrap(),
+    ];
+
+    let diffs: Vec<(Location, String, String)> = annotatedb
+        .category_diff(
+            &locations,
+            &TSSRegion::new(2000, 1000).unwrap(),
+            &TSSRegion::new(1000, 1000).unwrap(),
+        )
+        .unwrap();
+
+    assert_eq!(diffs.len(), 1);
+    assert_eq!(diffs[0].0.start, 8450);
+    assert_eq!(diffs[0].1, PROMOTER);
+    assert_eq!(diffs[0].2, INTERGENIC);
+}
+
+#[test]
+fn test_great_assignment() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (1, "chr1", 100000, 120000, "+", "A", "GENEA"),
+                (1, "chr1", 150000, 200000, "-", "B", "GENEB"),
+                (1, "chr1", 400000, 410000, "+", "C", "GENEC"),
+            ],
+        ),
+        TSSRegion::default(),
+        5,
+    );
+
+    // basal domains are A 95000-101000, B 199000-205000 and C
+    // 395000-401000, so with a 100 kb extension the regulatory domains are
+    // A 1-199000, B 101000-300000 and C 300000-500000
+    let basal: TSSRegion = TSSRegion::new(5000, 1000).unwrap();
+
+    let great = |loc: &str| -> Vec<String> {
+        annotatedb
+            .great_assignment(&Location::parse(loc).unwrap(), basal, 100000)
+            .unwrap()
     };
 
-    let js: serde_json::Value = json!(records);
+    assert_eq!(great("chr1:150000-150000"), vec!["A", "B"]);
+    assert_eq!(great("chr1:100500-100500"), vec!["A"]);
+    assert_eq!(great("chr1:250000-250000"), vec!["B"]);
+    assert_eq!(great("chr1:350000-350000"), vec!["C"]);
+    assert!(great("chr1:600000-600000").is_empty());
+}
+
+#[test]
+fn test_annotate_many() {
+    let rows = [
+        (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+        (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+        (1, "chr1", 50000, 60000, "-", "G2", "GENE2"),
+        (2, "chr1", 50000, 60000, "-", "G2", "GENE2"),
+        (1, "chr2", 1000, 2000, "+", "G3", "GENE3"),
+        (2, "chr2", 1000, 2000, "+", "G3", "GENE3"),
+    ];
+
+    let annotatedb: Annotate =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 5).with_threads(4);
+
+    let locations: Vec<Location> = (0..50)
+        .map(|i| {
+            let chr: &str = if i % 3 == 0 { "chr2" } else { "chr1" };
+            Location::new(chr, 500 + i * 1300, 600 + i * 1300).unwrap()
+        })
+        .collect();
+
+    let annotations: Vec<GeneAnnotation> = annotatedb.annotate_many(&locations).unwrap();
+
+    assert_eq!(annotations.len(), locations.len());
+
+    for (location, annotation) in locations.iter().zip(annotations.iter()) {
+        assert_eq!(
+            serde_json::to_string(annotation).unwrap(),
+            serde_json::to_string(&annotatedb.annotate(location).unwrap()).unwrap()
+        );
+    }
+
+    // tables are the same whatever the number of threads
+    #[cfg(feature = "table")]
+    {
+        let single: Annotate =
+            Annotate::new(test_db(&rows), TSSRegion::default(), 5)
+                .with_threads(1);
+
+        assert_eq!(
+            annotatedb.make_gene_table(&locations, 2, &TSSRegion::default()).unwrap(),
+            single.make_gene_table(&locations, 2, &TSSRegion::default()).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_annotate_writes_nothing_to_stdout() {
+    // the test harness captures print output, so annotate in a child run of
+    // this test with capture off where anything printed reaches its stdout
+    if std::env::var("GENES_TEST_STDOUT_CHILD").is_ok() {
+        let annotatedb: Annotate = Annotate::new(test_db(&ONE_GENE_ROWS), TSSRegion::default(), 5);
+
+        let annotation: GeneAnnotation = annotatedb
+            .annotate(&Location::parse("chr1:15000-15100").unwrap())
+            .unwrap();
+
+        assert_eq!(annotation.gene_ids, "G1");
+
+        return;
+    }
+
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args([
+            "tests::test_annotate_writes_nothing_to_stdout",
+            "--exact",
+            "--nocapture",
+        ])
+        .env("GENES_TEST_STDOUT_CHILD", "1")
+        .output()
+        .unwrap();
+
+    let stdout: String = String::from_utf8(output.stdout).unwrap();
+
+    assert!(output.status.success());
+    // only the harness's own lines, none of which mention the gene
+    assert!(stdout.contains("1 passed"));
+    assert!(!stdout.contains("G1"));
+    assert!(!stdout.contains("GENE1"));
+}
+
+#[test]
+fn test_closest_gene_stranded_tss_dist() {
+    let annotatedb: Annotate = Annotate::new(test_db(&TWO_CHR_ROWS), TSSRegion::default(), 1);
+
+    // 1kb upstream of each TSS, so in its promoter
+    let plus: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:9000-9000").unwrap())
+        .unwrap();
+
+    let minus: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr2:21000-21000").unwrap())
+        .unwrap();
+
+    assert_eq!(plus.closest_genes[0].tss_dist, -1000);
+    assert_eq!(minus.closest_genes[0].tss_dist, -1000);
+    assert_eq!(plus.tss_dists, "-1000");
+    assert_eq!(minus.tss_dists, "-1000");
+    assert_eq!(plus.nearest_distance, None);
+
+    // 1kb downstream of each TSS
+    let plus: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:11000-11000").unwrap())
+        .unwrap();
+
+    let minus: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr2:19000-19000").unwrap())
+        .unwrap();
+
+    assert_eq!(plus.closest_genes[0].tss_dist, 1000);
+    assert_eq!(minus.closest_genes[0].tss_dist, 1000);
+    assert_eq!(plus.tss_dists, "1000");
+    assert_eq!(minus.tss_dists, "1000");
+
+    // intergenic, 5kb upstream of each TSS
+    let plus: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:5000-5000").unwrap())
+        .unwrap();
+
+    let minus: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr2:25000-25000").unwrap())
+        .unwrap();
+
+    assert_eq!(plus.nearest_distance, Some(-5000));
+    assert_eq!(minus.nearest_distance, Some(-5000));
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn test_make_gene_table_tss_dist_signs() {
+    let annotatedb: Annotate = Annotate::new(test_db(&TWO_CHR_ROWS), TSSRegion::default(), 1);
+
+    // upstream and downstream of the + strand TSS and then of the - strand
+    let locations: Vec<Location> = [
+        "chr1:9000-9000",
+        "chr1:11000-11000",
+        "chr2:21000-21000",
+        "chr2:19000-19000",
+    ]
+    .iter()
+    .map(|loc| Location::parse(loc).unwrap())
+    .collect();
+
+    let table: String = annotatedb
+        .make_gene_table(&locations, 1, &TSSRegion::default())
+        .unwrap();
+
+    let lines: Vec<Vec<&str>> = table.lines().map(|line| line.split('\t').collect()).collect();
+
+    assert_eq!(lines[0][4], "TSS Distance");
+    assert_eq!(lines[0][8], "#1 TSS Closest Distance");
+
+    // the primary and closest distances of each row share a sign
+    for (line, dist) in lines[1..].iter().zip(["-1000", "1000", "-1000", "1000"]) {
+        assert_eq!(line[4], dist);
+        assert_eq!(line[8], dist);
+    }
+}
+
+#[test]
+fn test_annotate_level() {
+    let rows = [
+        (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+        (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+        // a second transcript with its own TSS inside the gene
+        (2, "chr1", 15000, 20000, "+", "G1", "GENE1"),
+    ];
+
+    let loc: Location = Location::parse("chr1:14850-14950").unwrap();
+
+    let transcripts: GeneAnnotation =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 1)
+            .annotate(&loc)
+            .unwrap();
+
+    let genes: GeneAnnotation =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 1)
+            .with_level(Level::Gene)
+            .annotate(&loc)
+            .unwrap();
+
+    assert_eq!(transcripts.gene_ids, "G1");
+    assert_eq!(genes.gene_ids, "G1");
+
+    assert_eq!(transcripts.prom_labels, "promoter,intronic");
+    assert_eq!(transcripts.tss_dists, "-100");
+    assert_eq!(transcripts.genes[0].transcript_support, 2);
+
+    // only the gene's own TSS counts
+    assert_eq!(genes.prom_labels, "intronic");
+    assert_eq!(genes.tss_dists, "4900");
+    assert_eq!(genes.genes[0].transcript_support, 1);
+}
+
+#[test]
+fn test_make_gene_json() {
+    let annotatedb: Annotate = Annotate::new(test_db(&TWO_GENE_ROWS), TSSRegion::default(), 2);
+
+    let locations: Vec<Location> = vec![
+        Location::parse("chr1:10000-10100").unwrap(),
+        Location::parse("chr1:30000-30100").unwrap(),
+    ];
+
+    let json: serde_json::Value =
+        serde_json::from_str(&annotatedb.make_gene_json(&locations).unwrap()).unwrap();
+
+    let rows: &Vec<serde_json::Value> = json.as_array().unwrap();
+
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0]["location"], "chr1:10000-10100");
+    assert_eq!(rows[0]["gene_ids"], "G1");
+    assert_eq!(rows[1]["gene_ids"], NA);
+
+    for row in rows {
+        assert_eq!(row["closest_genes"].as_array().unwrap().len(), 2);
+    }
+
+    // the same cells as the table
+    #[cfg(feature = "table")]
+    {
+        let table: String = annotatedb
+            .make_gene_table(&locations, 2, &TSSRegion::default())
+            .unwrap();
+
+        for (line, row) in table.lines().skip(1).zip(rows) {
+            let cells: Vec<&str> = line.split('\t').collect();
+
+            assert_eq!(cells[0], row["location"]);
+            assert_eq!(cells[1], row["gene_ids"]);
+            assert_eq!(cells[3], row["prom_labels"]);
+            assert_eq!(cells[5], row["closest_genes"][0]["gene_id"]);
+            assert_eq!(cells[8], row["closest_genes"][0]["tss_dist"].to_string());
+        }
+    }
+}
+
+#[test]
+fn test_write_gene_jsonl() {
+    let genesdb: LoctogeneDb = test_db(&TWO_GENE_ROWS);
+
+    let annotatedb: Annotate = Annotate::new(genesdb, TSSRegion::default(), 5);
+
+    let locations: Vec<Location> = vec![
+        Location::parse("chr1:10000-10100").unwrap(),
+        Location::parse("chr1:30000-30100").unwrap(),
+    ];
+
+    let mut out: Vec<u8> = Vec::new();
+
+    annotatedb
+        .write_gene_jsonl(&locations, 1, &mut out)
+        .unwrap();
+
+    let lines: Vec<serde_json::Value> = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["location"], "chr1:10000-10100");
+    assert_eq!(lines[0]["gene_ids"], "G1");
+    assert_eq!(lines[1]["gene_ids"], "n/a");
+    assert_eq!(lines[1]["closest_genes"].as_array().unwrap().len(), 1);
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn test_make_gene_table_no_closest() {
+    let genesdb: LoctogeneDb = test_db(&ONE_GENE_ROWS);
+
+    let annotatedb: Annotate = Annotate::new(genesdb, TSSRegion::default(), 5);
+
+    let locations: Vec<Location> = vec![
+        Location::parse("chr1:10000-10100").unwrap(),
+        Location::parse("chr1:30000-30100").unwrap(),
+    ];
+
+    let table: String = annotatedb
+        .make_gene_table(&locations, 0, &TSSRegion::default())
+        .unwrap();
+
+    let lines: Vec<&str> = table.lines().collect();
+
+    assert_eq!(lines.len(), 3);
+
+    for line in lines {
+        assert_eq!(line.split('\t').count(), 5);
+    }
+
+    // only the chromosome and promoter region checks, the genes each
+    // location is within and the exons of the genic one, so no closest
+    // gene query even for the intergenic location
+    assert_eq!(annotatedb.genesdb().query_count(), 5);
+
+    let table: String = annotatedb
+        .make_gene_table(&locations, 2, &TSSRegion::default())
+        .unwrap();
+
+    for line in table.lines() {
+        assert_eq!(line.split('\t').count(), 13);
+    }
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn test_make_gene_table_locus_columns() {
+    let genesdb: LoctogeneDb = test_db(
+        &[
+            (1, "chr1", 10000, 30000, "-", "G1", "GENE1"),
+            (2, "chr1", 10000, 20000, "-", "G1", "GENE1"),
+            (2, "chr1", 12000, 30000, "-", "G1", "GENE1"),
+        ],
+    );
+
+    let annotatedb: Annotate =
+        Annotate::new(genesdb, TSSRegion::default(), 5).with_locus_columns(true);
+
+    let locations: Vec<Location> = vec![Location::parse("chr1:15000-15100").unwrap()];
+
+    let table: String = annotatedb
+        .make_gene_table(&locations, 1, &TSSRegion::default())
+        .unwrap();
+
+    let lines: Vec<Vec<&str>> = table.lines().map(|line| line.split('\t').collect()).collect();
+
+    assert_eq!(lines[0], annotatedb.table_columns(1, &TSSRegion::default()));
+    assert_eq!(lines[0][5], "Gene Strand");
+    assert_eq!(lines[0][6], "Gene Locus");
+    assert_eq!(lines[1][5], "-");
+    assert_eq!(lines[1][6], "chr1:10000-30000");
+    assert_eq!(lines[0][11], "#1 Closest Gene Strand");
+    assert_eq!(lines[1][11], "-");
+    assert_eq!(lines[1].len(), 12);
+}
+
+#[test]
+fn test_strands() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (1, "chr1", 14000, 30000, "-", "G2", "GENE2"),
+                (2, "chr1", 14000, 30000, "-", "G2", "GENE2"),
+            ],
+        ),
+        TSSRegion::default(),
+        2,
+    );
+
+    // closer to the TSS of G1 than G2
+    let annotation: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:15000-15100").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.gene_ids, "G1;G2");
+    assert_eq!(annotation.strands, "+;-");
+
+    for (gene, strand) in annotation.genes.iter().zip(annotation.strands.split(';')) {
+        assert_eq!(gene.strand, strand);
+    }
+
+    let closest: Vec<(&str, &str)> = annotation
+        .closest_genes
+        .iter()
+        .map(|cg| (cg.gene_id.as_str(), cg.strand.as_str()))
+        .collect();
+
+    assert_eq!(closest, vec![("G1", "+"), ("G2", "-")]);
+}
+
+#[test]
+fn test_na_never_mixed_with_genes() {
+    let genesdb: LoctogeneDb = test_db(&ONE_GENE_ROWS);
+
+    let annotatedb: Annotate = Annotate::new(genesdb, TSSRegion::default(), 5);
+
+    let genic: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:15000-15100").unwrap())
+        .unwrap();
+
+    for field in [&genic.gene_ids, &genic.gene_symbols, &genic.prom_labels, &genic.tss_dists] {
+        assert!(!field.contains(NA));
+    }
+
+    assert_eq!(genic.gene_ids, "G1");
+
+    let empty: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:50000-50100").unwrap())
+        .unwrap();
+
+    for field in [&empty.gene_ids, &empty.gene_symbols, &empty.prom_labels, &empty.tss_dists] {
+        assert_eq!(field, NA);
+    }
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn test_make_gene_table_uses_ts() {
+    let genesdb: LoctogeneDb = test_db(&ONE_GENE_ROWS);
+
+    // the constructor's region does not reach the peak 4kb upstream
+    let annotatedb: Annotate = Annotate::new(genesdb, TSSRegion::default(), 5);
+
+    let locations: Vec<Location> = vec![Location::parse("chr1:6000-6000").unwrap()];
+
+    let table: String = annotatedb
+        .make_gene_table(&locations, 1, &TSSRegion::new(5000, 5000).unwrap())
+        .unwrap();
+
+    let lines: Vec<Vec<&str>> = table.lines().map(|line| line.split('\t').collect()).collect();
+
+    assert_eq!(lines[0][3], "Relative To Gene (prom=-5/+5kb)");
+    assert_eq!(lines[1][1], "G1");
+    assert_eq!(lines[1][3], "promoter");
+    assert_eq!(lines[1][7], "promoter");
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn test_make_gene_table_headers_agree() {
+    let genesdb: LoctogeneDb = test_db(
+        &[
+            (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (1, "chr1", 30000, 40000, "+", "G2", "GENE2"),
+            (1, "chr1", 50000, 60000, "+", "G3", "GENE3"),
+        ],
+    );
+
+    // the constructor's region makes the peak 4kb upstream a promoter and
+    // asks for 5 closest genes, but the table is narrower on both counts
+    let annotatedb: Annotate = Annotate::new(genesdb, TSSRegion::new(5000, 5000).unwrap(), 5);
+
+    let locations: Vec<Location> = vec![Location::parse("chr1:6000-6000").unwrap()];
+
+    let table: String = annotatedb
+        .make_gene_table(&locations, 2, &TSSRegion::default())
+        .unwrap();
+
+    let lines: Vec<Vec<&str>> = table.lines().map(|line| line.split('\t').collect()).collect();
+
+    assert_eq!(lines[0][3], "Relative To Gene (prom=-2/+1kb)");
+    assert_eq!(lines[0].len(), lines[1].len());
+    assert_eq!(lines[1].len(), 5 + 2 * 4);
+    assert_eq!(lines[1][1], NA);
+    assert_eq!(lines[1][3], NA);
+    assert_eq!(lines[1][6], "GENE1");
+
+    // the closest gene is also labelled with the table's region
+    assert_eq!(lines[1][7], "intergenic");
+}
+
+// A gene of an annotation with just the fields agrees_with looks at set
+#[cfg(test)]
+fn classification(gene_id: &str, is_promoter: bool, tss_dist: i32) -> PromoterOverlap {
+    PromoterOverlap {
+        gene_id: gene_id.to_owned(),
+        gene_symbol: gene_id.to_owned(),
+        strand: "+".to_owned(),
+        start: 1,
+        end: 2,
+        is_promoter,
+        is_exon: false,
+        exon_class: None,
+        is_intronic: true,
+        overlaps_tss: false,
+        tss_dist,
+        max_tss_dist: tss_dist,
+        tss_coord: 1,
+        gene_covered_frac: 0.0,
+        body_edge_dist: None,
+        custom_labels: vec![],
+        transcript_support: 1,
+        feature_count: 1,
+        antisense: None,
+    }
+}
+
+// An annotation of the given genes whose joined fields are deliberately
+// unhelpful, as though made with other separators and a join limit
+#[cfg(test)]
+fn annotation_of(genes: Vec<PromoterOverlap>) -> GeneAnnotation {
+    GeneAnnotation {
+        gene_ids: "G1|G2 (+1 more)".to_owned(),
+        gene_symbols: "GENE1|GENE2 (+1 more)".to_owned(),
+        prom_labels: "promoter/intronic|intronic (+1 more)".to_owned(),
+        tss_dists: "100|-4300 (+1 more)".to_owned(),
+        strands: "+".to_owned(),
+        loci: "chr1:1-2".to_owned(),
+        abs_tss_dists: "100".to_owned(),
+        tss_directions: "upstream".to_owned(),
+        feature_counts: "1".to_owned(),
+        antisense: None,
+        genes,
+        closest_genes: vec![],
+        is_genic: true,
+        nearest_distance: None,
+        search_window: "chr1:1-2".to_owned(),
+    }
+}
+
+#[test]
+fn test_agrees_with() {
+    let reference: GeneAnnotation = annotation_of(vec![
+        classification("G1", true, 100),
+        classification("G2", false, -4300),
+        classification("G3", false, 9000),
+    ]);
+
+    // same genes in a different order with slightly shifted distances
+    let other: GeneAnnotation = annotation_of(vec![
+        classification("G2", false, -4305),
+        classification("G3", false, 9000),
+        classification("G1", true, 100),
+    ]);
+
+    assert!(reference.agrees_with(&other, 5).agrees());
+
+    let diff: AnnotationDiff = reference.agrees_with(&other, 0);
+
+    assert!(diff.genes);
+    assert!(diff.labels);
+    assert!(!diff.tss_dists);
+
+    let relabelled: GeneAnnotation = annotation_of(vec![
+        classification("G1", false, 100),
+        classification("G2", false, -4300),
+        classification("G3", false, 9000),
+    ]);
+
+    let diff: AnnotationDiff = reference.agrees_with(&relabelled, 0);
+
+    assert!(diff.genes);
+    assert!(!diff.labels);
+    assert!(diff.tss_dists);
+
+    let missing: GeneAnnotation = annotation_of(vec![
+        classification("G1", true, 100),
+        classification("G2", false, -4300),
+    ]);
+
+    assert!(!reference.agrees_with(&missing, 5).genes);
+}
+
+#[test]
+fn test_gene_covered_frac() {
+    let genesdb: LoctogeneDb = test_db(
+        &[
+            (2, "chr1", 10001, 20000, "+", "G1", "GENE1"),
+            (2, "chr1", 30001, 31000, "+", "G2", "GENE2"),
+        ],
+    );
+
+    let annotatedb: Annotate = Annotate::new(genesdb, TSSRegion::default(), 5);
+
+    // covers the second half of G1
+    let partial: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:15001-25000").unwrap())
+        .unwrap();
+
+    assert_eq!(partial.genes.len(), 1);
+    assert_eq!(partial.genes[0].gene_covered_frac, 0.5);
+
+    // a deletion spanning all of G2
+    let full: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:29000-32000").unwrap())
+        .unwrap();
+
+    assert_eq!(full.genes[0].gene_id, "G2");
+    assert_eq!(full.genes[0].gene_covered_frac, 1.0);
+
+    // only in the promoter of G1
+    let promoter: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:9000-9500").unwrap())
+        .unwrap();
+
+    assert_eq!(promoter.gene_ids, "G1");
+    assert_eq!(promoter.genes[0].gene_covered_frac, 0.0);
+}
+
+#[test]
+fn test_closest_genes_stable_ties() {
+    // inserted in reverse id order so row order alone would pick G2
+    let genesdb: LoctogeneDb = test_db(
+        &[
+            (1, "chr1", 20000, 30000, "+", "G2", "GENE2"),
+            (1, "chr1", 5000, 10000, "-", "G1", "GENE1"),
+        ],
+    );
+
+    let loc: Location = Location::parse("chr1:15000-15000").unwrap();
+
+    for _ in 0..5 {
+        let records: Vec<GenomicFeature> = genesdb.get_closest_genes(&loc, 1, Level::Gene).unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].gene_id, "G1");
+    }
+}
+
+#[test]
+fn test_annotate_transcripts() {
+    let genesdb: LoctogeneDb = test_db(
+        &[
+            (1, "chr1", 10000, 40000, "+", "G1", "GENE1"),
+            (2, "chr1", 10000, 40000, "+", "G1", "GENE1"),
+            (2, "chr1", 20500, 40000, "+", "G1", "GENE1"),
+        ],
+    );
+
+    let annotatedb: Annotate = Annotate::new(genesdb, TSSRegion::default(), 5);
+
+    let loc: Location = Location::parse("chr1:20000-20000").unwrap();
+
+    let transcripts = annotatedb.annotate_transcripts(&loc).unwrap();
 
-    println!("{}", js);
+    assert_eq!(transcripts.len(), 2);
+    assert_eq!(transcripts[0].tss_dist, 10000);
+    assert_eq!(transcripts[0].prom_label, "intronic");
+    assert_eq!(transcripts[1].tss_dist, -500);
+    assert_eq!(transcripts[1].prom_label, "promoter");
 
-}
\ No newline at end of file
+    // annotate collapses both to the gene keeping the closest TSS
+    let annotation: GeneAnnotation = annotatedb.annotate(&loc).unwrap();
+
+    assert_eq!(annotation.gene_ids, "G1");
+    assert_eq!(annotation.tss_dists, "-500");
+}
+
+// shifts locations 10kb to the right and cannot lift chrY
+#[cfg(test)]
+struct ShiftLiftover;
+
+#[cfg(test)]
+impl Liftover for ShiftLiftover {
+    fn lift(&self, location: &Location) -> Option<Location> {
+        if location.chr == "chrY" {
+            return None;
+        }
+
+        Location::new(&location.chr, location.start + 10000, location.end + 10000).ok()
+    }
+}
+
+#[test]
+fn test_liftover() {
+    let genesdb: LoctogeneDb = test_db(&GENE_20KB_ROWS);
+
+    let annotatedb: Annotate =
+        Annotate::new(genesdb, TSSRegion::default(), 5).with_liftover(Box::new(ShiftLiftover));
+
+    // only inside G1 once lifted
+    let annotation: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:15000-15000").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.gene_ids, "G1");
+    assert_eq!(annotation.tss_dists, "5000");
+
+    let err = annotatedb.annotate(&Location::parse("chrY:15000-15000").unwrap());
+
+    assert!(err.is_err());
+    assert!(err.err().unwrap().to_string().contains("chrY:15000-15000"));
+}
+
+#[test]
+fn test_missing_symbol() {
+    let rows: [TestRow; 4] = [
+        (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+        (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+        (1, "chr1", 12000, 30000, "+", "G2", ""),
+        (2, "chr1", 12000, 30000, "+", "G2", ""),
+    ];
+
+    let loc: Location = Location::parse("chr1:15000-15000").unwrap();
+
+    let annotatedb: Annotate =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 5);
+
+    let annotation: GeneAnnotation = annotatedb.annotate(&loc).unwrap();
+
+    assert_eq!(annotation.gene_symbols, "G2;GENE1");
+
+    // a null symbol is treated the same as an empty one
+    let annotatedb: Annotate = Annotate::new(
+        test_db_with(
+            &rows,
+            "UPDATE genes SET gene_symbol = NULL WHERE gene_id = 'G2'",
+        ),
+        TSSRegion::default(),
+        5,
+    )
+    .with_missing_symbol(MissingSymbol::Na);
+
+    let annotation: GeneAnnotation = annotatedb.annotate(&loc).unwrap();
+
+    assert_eq!(annotation.gene_symbols, "n/a;GENE1");
+    assert_eq!(annotation.closest_genes[0].gene_symbol, "n/a");
+}
+
+#[test]
+fn test_within_promoter_mid_fetches_fewer_rows() {
+    let genesdb: LoctogeneDb = test_db(
+        &[
+            (2, "chr1", 12000, 13000, "+", "G1", "GENE1"),
+            (2, "chr1", 8000, 32000, "+", "G2", "GENE2"),
+            (2, "chr1", 47000, 48000, "-", "G3", "GENE3"),
+        ],
+    );
+
+    // a wide peak whose midpoint is only in G2
+    let loc: Location = Location::parse("chr1:10000-50000").unwrap();
+
+    let all: Vec<GenomicFeature> = genesdb
+        .get_genes_within_promoter(&loc, &Level::Transcript, 2000)
+        .unwrap();
+
+    let mid: Vec<GenomicFeature> = genesdb
+        .get_genes_within_promoter_mid(&loc, &Level::Transcript, 2000)
+        .unwrap();
+
+    assert_eq!(all.len(), 3);
+    assert_eq!(mid.len(), 1);
+    assert_eq!(mid[0].gene_id, "G2");
+}
+
+// 10,000 overlapping + strand transcripts of 20kb, one every 1kb along chr1,
+// for benchmarks
+#[cfg(test)]
+const DENSE_SQL: &str = "WITH RECURSIVE n(i) AS (SELECT 0 UNION ALL SELECT i + 1 FROM n WHERE i < 9999) INSERT INTO genes (level, chr, start, end, strand, gene_id, gene_symbol, stranded_start) SELECT level, 'chr1', i * 1000 + 1, i * 1000 + 20000, '+', 'G' || i, 'GENE' || i, i * 1000 + 1 FROM n, (SELECT 1 AS level UNION ALL SELECT 2);";
+
+// Rows fetched and time taken for wide peaks by the interval and midpoint
+// promoter queries. Run with cargo test bench_ -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_within_promoter_mid() {
+    let genesdb: LoctogeneDb = test_db_with(&[], DENSE_SQL);
+
+    // 100kb peaks every 1Mb
+    let locations: Vec<Location> = (0..10)
+        .map(|i| Location::new("chr1", i * 1000000 + 1, i * 1000000 + 100000).unwrap())
+        .collect();
+
+    for (name, mid_only) in [("interval", false), ("midpoint", true)] {
+        let start: std::time::Instant = std::time::Instant::now();
+        let mut rows: usize = 0;
+
+        for _ in 0..10 {
+            for location in locations.iter() {
+                rows += if mid_only {
+                    genesdb.get_genes_within_promoter_mid(location, &Level::Transcript, 2000)
+                } else {
+                    genesdb.get_genes_within_promoter(location, &Level::Transcript, 2000)
+                }
+                .unwrap()
+                .len();
+            }
+        }
+
+        println!("{}: {} rows in {:?}", name, rows, start.elapsed());
+    }
+}
+
+#[test]
+fn test_within_promoter_overlaps() {
+    let genesdb: LoctogeneDb = test_db(
+        &[(2, "chr1", 10000, 20000, "+", "G1", "GENE1")],
+    );
+
+    // the gene padded by 2kb is 8000-22000
+    let within = |loc: &str| -> usize {
+        genesdb
+            .get_genes_within_promoter(&Location::parse(loc).unwrap(), &Level::Transcript, 2000)
+            .unwrap()
+            .len()
+    };
+
+    // only the end is in the padded gene
+    assert_eq!(within("chr1:7000-8500"), 1);
+    // only the start is
+    assert_eq!(within("chr1:21000-23000"), 1);
+    // neither end is, but the peak covers the whole padded gene
+    assert_eq!(within("chr1:5000-25000"), 1);
+    assert_eq!(within("chr1:5000-7999"), 0);
+    assert_eq!(within("chr1:22001-25000"), 0);
+}
+
+#[test]
+fn test_from_file() {
+    let file = test_db_file("from_file", &[(1, "chr1", 10000, 20000, "+", "G1", "GENE1")], "");
+
+    let genesdb: LoctogeneDb = LoctogeneDb::from_file(file.to_str().unwrap(), true).unwrap();
+
+    assert_eq!(genesdb.chromosomes().unwrap(), vec!["chr1"]);
+
+    let missing = std::env::temp_dir().join("genes_test_from_file_missing.db");
+
+    let _ = std::fs::remove_file(&missing);
+
+    let err = LoctogeneDb::from_file(missing.to_str().unwrap(), false).err().unwrap();
+
+    assert_eq!(err.to_string(), format!("{} not found", missing.to_str().unwrap()));
+    // nothing is created in its place
+    assert!(!missing.exists());
+}
+
+#[test]
+fn test_validate_schema() {
+    let genesdb: LoctogeneDb = test_db(&[]);
+
+    assert!(genesdb.validate_schema().is_ok());
+
+    let no_table_sql: &str = "DROP TABLE genes; CREATE TABLE peaks (id INTEGER PRIMARY KEY);";
+
+    let no_table: LoctogeneDb = test_db_with(&[], no_table_sql);
+
+    assert_eq!(
+        no_table.validate_schema().err().unwrap().to_string(),
+        "not a loctogene database: there is no genes table"
+    );
+
+    let no_stranded_start: LoctogeneDb =
+        test_db_with(&[], "ALTER TABLE genes DROP COLUMN stranded_start;");
+
+    assert_eq!(
+        no_stranded_start.validate_schema().err().unwrap().to_string(),
+        "not a loctogene database: the genes table has no stranded_start column"
+    );
+
+    // from_file only checks when asked to
+    let file = test_db_file("validate_schema_no_table", &[], no_table_sql);
+
+    assert!(LoctogeneDb::from_file(file.to_str().unwrap(), false).is_ok());
+    assert!(LoctogeneDb::from_file(file.to_str().unwrap(), true).is_err());
+}
+
+#[test]
+fn test_circular_closest_genes() {
+    let rows: [TestRow; 4] = [
+        (1, "plasmid", 1000, 2000, "+", "G1", "GENE1"),
+        (1, "plasmid", 50000, 51000, "+", "G2", "GENE2"),
+        (1, "chr1", 1000, 2000, "+", "G3", "GENE3"),
+        (1, "chr1", 50000, 51000, "+", "G4", "GENE4"),
+    ];
+
+    let loc: Location = Location::parse("plasmid:99000-99000").unwrap();
+
+    let linear: Vec<GenomicFeature> = test_db(&rows)
+        .get_closest_genes(&loc, 1, Level::Gene)
+        .unwrap();
+
+    assert_eq!(linear[0].gene_id, "G2");
+    assert_eq!(linear[0].dist, 49000);
+
+    // across the origin G1 is only 2kb away
+    let genesdb: LoctogeneDb = test_db(&rows).with_circular("plasmid", 100000);
+
+    let circular: Vec<GenomicFeature> = genesdb.get_closest_genes(&loc, 2, Level::Gene).unwrap();
+
+    assert_eq!(circular[0].gene_id, "G1");
+    assert_eq!(circular[0].dist, -2000);
+    assert_eq!(circular[1].gene_id, "G2");
+    assert_eq!(circular[1].dist, 49000);
+
+    // other chromosomes stay linear
+    assert_eq!(genesdb.circular("plasmid"), Some(100000));
+    assert_eq!(genesdb.circular("chr1"), None);
+
+    let linear: Vec<GenomicFeature> = genesdb
+        .get_closest_genes(&Location::parse("chr1:99000-99000").unwrap(), 1, Level::Gene)
+        .unwrap();
+
+    assert_eq!(linear[0].gene_id, "G4");
+    assert_eq!(linear[0].dist, 49000);
+    assert_eq!(genesdb.wrap_dist("chr1", 98000), 98000);
+    assert_eq!(genesdb.wrap_dist("plasmid", 98000), -2000);
+
+    // the name is normalized whichever order the builders are called in
+    for genesdb in [
+        test_db(&rows).with_circular("1", 100000).with_chr_normalization(true).unwrap(),
+        test_db(&rows).with_chr_normalization(true).unwrap().with_circular("1", 100000),
+    ] {
+        assert_eq!(genesdb.circular("chr1"), Some(100000));
+        assert_eq!(genesdb.circular("1"), Some(100000));
+        assert_eq!(genesdb.circular("plasmid"), None);
+    }
+}
+
+#[test]
+fn test_closest_genes_overlap_priority() {
+    let rows: [TestRow; 2] = [
+        (1, "chr1", 10000, 100000, "+", "G1", "GENE1"),
+        (1, "chr1", 60000, 61000, "+", "G2", "GENE2"),
+    ];
+
+    // deep inside G1 but closer to the TSS of G2
+    let loc: Location = Location::parse("chr1:55000-55000").unwrap();
+
+    let by_tss: Vec<GenomicFeature> = test_db(&rows)
+        .get_closest_genes(&loc, 2, Level::Gene)
+        .unwrap();
+
+    assert_eq!(by_tss[0].gene_id, "G2");
+
+    let by_overlap: Vec<GenomicFeature> = test_db(&rows)
+        .with_overlap_priority(true)
+        .get_closest_genes(&loc, 2, Level::Gene)
+        .unwrap();
+
+    assert_eq!(by_overlap.len(), 2);
+    assert_eq!(by_overlap[0].gene_id, "G1");
+    assert_eq!(by_overlap[1].gene_id, "G2");
+}
+
+#[test]
+fn test_zero_based_coords() {
+    let rows: [TestRow; 2] = [
+        (1, "chr1", 15001, 20000, "+", "G1", "GENE1"),
+        (2, "chr1", 15001, 20000, "+", "G1", "GENE1"),
+    ];
+
+    // the first base of G1 as a BED interval and as a 1-based location
+    let bed: Location = Location::parse("chr1:15000-15001").unwrap();
+    let one_based: Location = Location::parse("chr1:15001-15001").unwrap();
+
+    let expected: GeneAnnotation =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 5)
+            .annotate(&one_based)
+            .unwrap();
+
+    let annotation: GeneAnnotation =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 5)
+            .with_coord_base(CoordBase::Zero)
+            .annotate(&bed)
+            .unwrap();
+
+    assert_eq!(annotation.gene_ids, "G1");
+    assert_eq!(annotation.tss_dists, "0");
+    assert!(annotation.agrees_with(&expected, 0).agrees());
+}
+
+#[test]
+fn test_gene_hit_report() {
+    let genesdb: LoctogeneDb = test_db(
+        &[
+            (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (3, "chr1", 10000, 11000, "+", "G1", "GENE1"),
+            (1, "chr1", 50000, 60000, "+", "G2", "GENE2"),
+            (2, "chr1", 50000, 60000, "+", "G2", "GENE2"),
+        ],
+    );
+
+    let annotatedb: Annotate = Annotate::new(genesdb, TSSRegion::default(), 5);
+
+    let locations: Vec<Location> = vec![
+        Location::parse("chr1:10500-10500").unwrap(),
+        Location::parse("chr1:15000-15000").unwrap(),
+        Location::parse("chr1:55000-55000").unwrap(),
+        Location::parse("chr1:70000-70000").unwrap(),
+    ];
+
+    let rows: Vec<GeneHitRow> = annotatedb.gene_hit_report(&locations).unwrap();
+
+    assert_eq!(rows.len(), 2);
+
+    // ties on the number of peaks are ordered by symbol
+    assert_eq!(rows[0].gene_id, "G1");
+    assert_eq!(rows[0].peaks, 2);
+    assert_eq!(rows[0].promoter, 1);
+    assert_eq!(rows[0].exonic, 1);
+    assert_eq!(rows[0].intronic, 1);
+    assert_eq!(rows[1].gene_id, "G2");
+    assert_eq!(rows[1].peaks, 2);
+    assert_eq!(rows[1].intronic, 1);
+    assert_eq!(rows[1].intergenic, 1);
+
+    let mut wtr = csv::WriterBuilder::new().delimiter(b'\t').from_writer(vec![]);
+
+    for row in rows.iter() {
+        wtr.serialize(row).unwrap();
+    }
+
+    let table: String = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+
+    assert!(table.starts_with("gene_id\tgene_symbol\tpeaks\tpromoter\texonic\tintronic\tintergenic\n"));
+}
+
+#[test]
+fn test_cds_level() {
+    let rows: [TestRow; 4] = [
+        (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+        (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+        (3, "chr1", 10000, 11000, "+", "G1", "GENE1"),
+        // coding part of the first exon
+        (4, "chr1", 10500, 11000, "+", "G1", "GENE1"),
+    ];
+
+    let utr: Location = Location::parse("chr1:10200-10200").unwrap();
+    let cds: Location = Location::parse("chr1:10700-10700").unwrap();
+
+    let exons: LoctogeneDb = test_db(&rows);
+
+    assert_eq!(exons.in_exon(&utr, "G1").unwrap().len(), 1);
+    assert_eq!(exons.in_exon(&cds, "G1").unwrap().len(), 1);
+
+    let coding: LoctogeneDb = test_db(&rows).with_cds_level(4).unwrap();
+
+    assert_eq!(coding.in_exon(&utr, "G1").unwrap().len(), 0);
+    assert_eq!(coding.in_exon(&cds, "G1").unwrap().len(), 1);
+
+    // no features at level 5 so exons are still used
+    let fallback: LoctogeneDb = test_db(&rows).with_cds_level(5).unwrap();
+
+    assert_eq!(fallback.in_exon(&utr, "G1").unwrap().len(), 1);
+}
+
+#[test]
+fn test_validate_chromosomes() {
+    let genesdb: LoctogeneDb = test_db(
+        &[
+            (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (1, "chrM", 1000, 2000, "+", "G2", "GENE2"),
+        ],
+    );
+
+    assert!(genesdb.has_chr("chr1").unwrap());
+    assert!(!genesdb.has_chr("chr2").unwrap());
+
+    let annotatedb: Annotate = Annotate::new(genesdb, TSSRegion::default(), 5);
+
+    let locations: Vec<Location> = vec![
+        Location::parse("chr1:15000-15000").unwrap(),
+        Location::parse("chrMT:1500-1500").unwrap(),
+        Location::parse("chrZ:1500-1500").unwrap(),
+        Location::parse("chrMT:1600-1600").unwrap(),
+    ];
+
+    let err = annotatedb.validate_chromosomes(&locations).err().unwrap();
+
+    assert_eq!(
+        err.to_string(),
+        "chromosomes not in database: chrMT (did you mean chrM?), chrZ"
+    );
+
+    // tables are checked before any location is annotated
+    #[cfg(feature = "table")]
+    assert!(annotatedb
+        .make_gene_table(&locations, 1, &TSSRegion::default())
+        .is_err());
+
+    assert!(annotatedb.validate_chromosomes(&locations[0..1]).is_ok());
+
+    // chromosomes are checked once lifted over
+    let ensembl: Vec<Location> = vec![Location::parse("1:15000-15000").unwrap()];
+
+    assert!(annotatedb.validate_chromosomes(&ensembl).is_err());
+
+    let annotatedb: Annotate = annotatedb.with_liftover(Box::new(UcscLiftover));
+
+    assert!(annotatedb.validate_chromosomes(&ensembl).is_ok());
+    assert!(annotatedb.validate_chromosomes(&locations[0..1]).is_err());
+}
+
+// renames Ensembl chromosomes to UCSC ones and cannot lift anything else
+#[cfg(test)]
+struct UcscLiftover;
+
+#[cfg(test)]
+impl Liftover for UcscLiftover {
+    fn lift(&self, location: &Location) -> Option<Location> {
+        if location.chr.starts_with("chr") {
+            return None;
+        }
+
+        Location::new(&format!("chr{}", location.chr), location.start, location.end).ok()
+    }
+}
+
+#[test]
+fn test_validate_tss_region() {
+    // a real assembly, where chrM is shorter than an ordinary promoter
+    let assembly: Annotate = Annotate::new(
+        test_db(&[
+            (1, "chr1", 10000, 200000, "+", "G1", "GENE1"),
+            (1, "chrM", 100, 1500, "+", "G2", "GENE2"),
+        ]),
+        TSSRegion::default(),
+        5,
+    );
+
+    assert_eq!(assembly.genesdb().max_chr_extent().unwrap(), Some(200000));
+    assert!(assembly.validate_tss_region(&TSSRegion::symmetric(5000).unwrap()).is_ok());
+
+    // a database of tiny contigs only
+    let contigs: Annotate = Annotate::new(
+        test_db(&[
+            (1, "contig1", 100, 5000, "+", "G1", "GENE1"),
+            (1, "contig2", 100, 3000, "+", "G2", "GENE2"),
+        ]),
+        TSSRegion::default(),
+        5,
+    );
+
+    assert!(contigs.validate_tss_region(&TSSRegion::default()).is_ok());
+
+    // a promoter longer than any of the contigs
+    let huge: TSSRegion = TSSRegion::symmetric(8000).unwrap();
+
+    let err = contigs.validate_tss_region(&huge).err().unwrap();
+
+    assert_eq!(
+        err.to_string(),
+        "promoter offset of 8000 bp exceeds the largest chromosome extent of 5000 bp"
+    );
+
+    #[cfg(feature = "table")]
+    assert_eq!(
+        contigs
+            .make_gene_table(&[Location::parse("contig1:200-300").unwrap()], 1, &huge)
+            .err()
+            .unwrap()
+            .to_string(),
+        err.to_string()
+    );
+
+    // 2 Mb given where 2 kb was meant is rejected outright
+    assert!(TSSRegion::new(2000000, 1000).is_err());
+}
+
+#[test]
+fn test_promoter_at_chromosome_start() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (1, "chr1", 500, 5000, "+", "G1", "GENE1"),
+                (2, "chr1", 500, 5000, "+", "G1", "GENE1"),
+            ],
+        ),
+        TSSRegion::new(2000, 1000).unwrap(),
+        1,
+    );
+
+    // the promoter would start 1500 bp before the chromosome does
+    let annotation: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:100-200").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.prom_labels, PROMOTER);
+    assert_eq!(annotation.closest_genes[0].prom_label, PROMOTER);
+
+    let annotation: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:2000-2100").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.closest_genes[0].prom_label, INTRONIC);
+}
+
+#[test]
+fn test_body_edge_dist() {
+    let genesdb: LoctogeneDb = test_db(
+        &[
+            (2, "chr1", 10000, 200000, "+", "G1", "GENE1"),
+            (2, "chr1", 300000, 310000, "+", "G2", "GENE2"),
+        ],
+    );
+
+    let annotatedb: Annotate = Annotate::new(genesdb, TSSRegion::default(), 5);
+
+    // near the 3' end of a long gene
+    let annotation: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:199000-199000").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.genes[0].tss_dist, 189000);
+    assert_eq!(annotation.genes[0].body_edge_dist, Some(1000));
+
+    // upstream promoter peaks are not in the gene body
+    let annotation: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:299000-299000").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.genes[0].gene_id, "G2");
+    assert_eq!(annotation.genes[0].body_edge_dist, None);
+}
+
+#[test]
+fn test_skip_exon_detail() {
+    let rows: [TestRow; 6] = [
+        (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+        (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+        (3, "chr1", 14000, 16000, "+", "G1", "GENE1"),
+        (1, "chr1", 12000, 30000, "-", "G2", "GENE2"),
+        (2, "chr1", 12000, 30000, "-", "G2", "GENE2"),
+        (3, "chr1", 14000, 16000, "-", "G2", "GENE2"),
+    ];
+
+    let loc: Location = Location::parse("chr1:15000-15000").unwrap();
+
+    let annotatedb: Annotate =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 5);
+
+    let annotation: GeneAnnotation = annotatedb.annotate(&loc).unwrap();
+
+    assert_eq!(annotation.prom_labels, "exonic;exonic");
+
+    let annotatedb: Annotate =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 5)
+            .with_exon_detail(ExonDetail::Skip);
+
+    let annotation: GeneAnnotation = annotatedb.annotate(&loc).unwrap();
+
+    assert_eq!(annotation.prom_labels, "intronic;intronic");
+    assert_eq!(annotation.closest_genes[0].prom_label, "intronic");
+
+    // only the within and closest gene queries run, no exon queries
+    assert_eq!(annotatedb.genesdb().query_count(), 2);
+}
+
+#[test]
+fn test_tss_region_constructors() {
+    assert_eq!(TSSRegion::symmetric(500).unwrap(), TSSRegion::new(500, 500).unwrap());
+    assert_eq!(TSSRegion::upstream_only(5000).unwrap().offset_5p(), 5000);
+    assert_eq!(TSSRegion::upstream_only(5000).unwrap().offset_3p(), 0);
+}
+
+#[test]
+fn test_tss_coord() {
+    let genesdb: LoctogeneDb = test_db(
+        &[
+            (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (2, "chr1", 10500, 20000, "+", "G1", "GENE1"),
+            (2, "chr1", 2000, 9000, "-", "G2", "GENE2"),
+        ],
+    );
+
+    let annotatedb: Annotate = Annotate::new(genesdb, TSSRegion::default(), 5);
+
+    let annotation: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:9400-9400").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.gene_ids, "G2;G1");
+    assert_eq!(annotation.prom_labels, "promoter;promoter");
+
+    // - strand TSS is the end of the gene
+    assert_eq!(annotation.genes[0].tss_coord, 9000);
+
+    // + strand TSS is the start of the closest transcript
+    assert_eq!(annotation.genes[1].tss_coord, 10000);
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn test_make_gene_table_pads_closest_genes() {
+    let genesdb: LoctogeneDb = test_db(
+        &[
+            (1, "contig1", 10000, 20000, "+", "G1", "GENE1"),
+            (1, "contig1", 30000, 40000, "+", "G2", "GENE2"),
+        ],
+    );
+
+    let annotatedb: Annotate = Annotate::new(genesdb, TSSRegion::default(), 5);
+
+    let locations: Vec<Location> = vec![Location::parse("contig1:25000-25000").unwrap()];
+
+    let table: String = annotatedb
+        .make_gene_table(&locations, 10, &TSSRegion::default())
+        .unwrap();
+
+    let lines: Vec<Vec<&str>> = table.lines().map(|line| line.split('\t').collect()).collect();
+
+    assert_eq!(lines[0].len(), 45);
+    assert_eq!(lines[1].len(), 45);
+
+    let groups: Vec<&[&str]> = lines[1][5..].chunks(4).collect();
+
+    assert_eq!(groups.len(), 10);
+    assert_eq!(groups.iter().filter(|g| g.iter().all(|c| *c == NA)).count(), 8);
+}
+
+#[cfg(test)]
+const BIOTYPE_ROWS: [TestRow; 6] = [
+    (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+    (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+    (1, "chr1", 12000, 30000, "-", "G2", "LINC2"),
+    (2, "chr1", 12000, 30000, "-", "G2", "LINC2"),
+    (1, "chr1", 14000, 16000, "+", "G3", "PSEUDO3"),
+    (2, "chr1", 14000, 16000, "+", "G3", "PSEUDO3"),
+];
+
+#[cfg(test)]
+const BIOTYPE_SQL: &str = "ALTER TABLE genes ADD COLUMN biotype TEXT; UPDATE genes SET biotype = 'protein_coding' WHERE gene_id = 'G1'; UPDATE genes SET biotype = 'lincRNA' WHERE gene_id = 'G2'; UPDATE genes SET biotype = 'processed_pseudogene' WHERE gene_id = 'G3';";
+
+#[test]
+fn test_coding_only() {
+    let loc: Location = Location::parse("chr1:15000-15000").unwrap();
+
+    let all: GeneAnnotation = Annotate::new(
+        test_db_with(&BIOTYPE_ROWS, BIOTYPE_SQL),
+        TSSRegion::default(),
+        5,
+    )
+    .annotate(&loc)
+    .unwrap();
+
+    assert_eq!(all.gene_ids, "G3;G1;G2");
+
+    let coding: GeneAnnotation = Annotate::new(
+        test_db_with(&BIOTYPE_ROWS, BIOTYPE_SQL),
+        TSSRegion::default(),
+        5,
+    )
+    .coding_only()
+    .unwrap()
+    .annotate(&loc)
+    .unwrap();
+
+    assert_eq!(coding.gene_ids, "G1");
+    assert_eq!(coding.closest_genes.len(), 1);
+
+    let noncoding: GeneAnnotation = Annotate::new(
+        test_db_with(&BIOTYPE_ROWS, BIOTYPE_SQL),
+        TSSRegion::default(),
+        5,
+    )
+    .include_noncoding()
+    .unwrap()
+    .annotate(&loc)
+    .unwrap();
+
+    assert_eq!(noncoding.gene_ids, "G1;G2");
+}
+
+#[test]
+fn test_annotate_biotypes() {
+    let loc: Location = Location::parse("chr1:15000-15000").unwrap();
+
+    let unfiltered: GeneAnnotation = Annotate::new(
+        test_db_with(&BIOTYPE_ROWS, BIOTYPE_SQL),
+        TSSRegion::default(),
+        5,
+    )
+    .with_biotypes(None)
+    .unwrap()
+    .annotate(&loc)
+    .unwrap();
+
+    assert_eq!(unfiltered.gene_ids, "G3;G1;G2");
+    assert_eq!(unfiltered.closest_genes.len(), 3);
+
+    let filtered: GeneAnnotation = Annotate::new(
+        test_db_with(&BIOTYPE_ROWS, BIOTYPE_SQL),
+        TSSRegion::default(),
+        5,
+    )
+    .with_biotypes(Some(vec!["lincRNA".to_string()]))
+    .unwrap()
+    .annotate(&loc)
+    .unwrap();
+
+    assert_eq!(filtered.gene_ids, "G2");
+    assert_eq!(filtered.closest_genes.len(), 1);
+    assert_eq!(filtered.closest_genes[0].gene_id, "G2");
+
+    // features cached before the biotypes change are not reused
+    let cached: LoctogeneDb = test_db_with(&BIOTYPE_ROWS, BIOTYPE_SQL).with_chr_cache(true);
+
+    assert_eq!(cached.get_genes_within(&loc, &Level::Transcript).unwrap().len(), 3);
+
+    let cached: LoctogeneDb = cached.with_biotypes(Some(vec!["lincRNA".to_string()])).unwrap();
+
+    let ids: Vec<String> = cached
+        .get_genes_within(&loc, &Level::Transcript)
+        .unwrap()
+        .into_iter()
+        .map(|f| f.gene_id)
+        .collect();
+
+    assert_eq!(ids, ["G2"]);
+
+    // biotypes are bound, so quotes in them are matched rather than
+    // changing the query
+    let quoted: Annotate = Annotate::new(
+        test_db_with(&BIOTYPE_ROWS, BIOTYPE_SQL),
+        TSSRegion::default(),
+        5,
+    )
+    .with_biotypes(Some(vec!["lincRNA') OR ('1' = '1".to_string()]))
+    .unwrap();
+
+    let annotation: GeneAnnotation = quoted.annotate(&loc).unwrap();
+
+    assert_eq!(annotation.gene_ids, NA);
+    assert!(annotation.closest_genes.is_empty());
+
+    // a database without a biotype column cannot be filtered
+    let rows: [TestRow; 1] = [(2, "chr1", 10000, 20000, "+", "G1", "GENE1")];
+
+    assert!(test_db(&rows).with_biotypes(None).is_ok());
+
+    match test_db(&rows).with_biotypes(Some(vec!["lincRNA".to_string()])) {
+        Err(GenesError::FormatError(error)) => assert!(error.contains("no biotype column")),
+        _ => panic!("expected a format error"),
+    }
+}
+
+#[test]
+fn test_distinct_biotypes_and_levels() {
+    let genesdb: LoctogeneDb = test_db_with(
+        &[
+            (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (3, "chr1", 10000, 11000, "+", "G1", "GENE1"),
+            (1, "chr1", 12000, 30000, "-", "G2", "LINC2"),
+        ],
+        "ALTER TABLE genes ADD COLUMN biotype TEXT; UPDATE genes SET biotype = 'protein_coding' WHERE gene_id = 'G1'; UPDATE genes SET biotype = 'lincRNA' WHERE gene_id = 'G2';",
+    );
+
+    assert_eq!(
+        genesdb.distinct_biotypes().unwrap(),
+        vec!["lincRNA", "protein_coding"]
+    );
+
+    assert_eq!(
+        genesdb.distinct_levels().unwrap(),
+        vec![Level::Gene, Level::Transcript, Level::Exon]
+    );
+
+    // no biotype column at all
+    let genesdb: LoctogeneDb = test_db(
+        &[(1, "chr1", 10000, 20000, "+", "G1", "GENE1")],
+    );
+
+    assert!(genesdb.distinct_biotypes().unwrap().is_empty());
+    assert_eq!(genesdb.distinct_levels().unwrap(), vec![Level::Gene]);
+}
+
+#[test]
+fn test_strand_from_str() {
+    assert_eq!(Strand::from("+"), Strand::Plus);
+    assert_eq!(Strand::from("-"), Strand::Neg);
+    assert_eq!(Strand::from("."), Strand::Plus);
+
+    for strand in [Strand::Plus, Strand::Neg] {
+        assert_eq!(Strand::from(strand.to_string().as_str()), strand);
+    }
+}
+
+#[test]
+fn test_promoter_label() {
+    assert_eq!(RegionClass::Promoter.to_string(), PROMOTER);
+    assert_eq!(RegionClass::Intergenic.to_string(), INTERGENIC);
+
+    let label: PromoterLabel =
+        PromoterLabel::new(vec![RegionClass::Promoter, RegionClass::Exonic], vec![]);
+
+    assert_eq!(label.to_string(), "promoter,exonic");
+    assert!(label.contains(RegionClass::Exonic));
+    assert!(!label.contains(RegionClass::Intronic));
+
+    let custom: PromoterLabel =
+        PromoterLabel::new(vec![RegionClass::Intergenic], vec!["cpg_island".to_owned()]);
+
+    assert_eq!(custom.to_string(), "intergenic,cpg_island");
+    assert_eq!(custom, "intergenic,cpg_island");
+    assert_ne!(custom, "intergenic");
+    assert_ne!(custom, "intergenic,cpg_island,");
+    assert_ne!(label, "promoter");
+    assert_eq!(PromoterLabel::default().to_string(), "");
+    assert_eq!(PromoterLabel::default(), "");
+    assert_ne!(label, "");
+    assert_eq!(RegionClass::Cds.as_str(), CDS);
+
+    // serializes as the plain string rather than its parts
+    assert_eq!(serde_json::to_string(&label).unwrap(), r#""promoter,exonic""#);
+}
+
+#[cfg(test)]
+fn stranded_feature(strand: &str) -> GenomicFeature {
+    GenomicFeature {
+        id: 1,
+        chr: "chr1".to_owned(),
+        start: 10000,
+        end: 20000,
+        strand: strand.to_owned(),
+        gene_id: "G1".to_owned(),
+        gene_symbol: "GENE1".to_owned(),
+        dist: 0,
+        score: None,
+    }
+}
+
+#[test]
+fn test_stranded_distance_plus_upstream() {
+    let loc: Location = Location::parse("chr1:9000-9000").unwrap();
+
+    assert_eq!(stranded_distance(&loc, &stranded_feature("+")), -1000);
+}
+
+#[test]
+fn test_stranded_distance_plus_downstream() {
+    let loc: Location = Location::parse("chr1:11000-11000").unwrap();
+
+    assert_eq!(stranded_distance(&loc, &stranded_feature("+")), 1000);
+}
+
+#[test]
+fn test_stranded_distance_minus_upstream() {
+    let loc: Location = Location::parse("chr1:21000-21000").unwrap();
+
+    assert_eq!(stranded_distance(&loc, &stranded_feature("-")), -1000);
+}
+
+#[test]
+fn test_stranded_distance_minus_downstream() {
+    let loc: Location = Location::parse("chr1:19000-19000").unwrap();
+
+    assert_eq!(stranded_distance(&loc, &stranded_feature("-")), 1000);
+}
+
+#[test]
+fn test_boundary() {
+    // the promoter of G1 starts at 8000 and the gene ends at 20000
+    let before: Location = Location::parse("chr1:7000-8000").unwrap();
+    let after: Location = Location::parse("chr1:20000-21000").unwrap();
+
+    let inclusive: Annotate = Annotate::new(test_db(&ONE_GENE_ROWS), TSSRegion::default(), 1);
+
+    let annotation: GeneAnnotation = inclusive.annotate(&before).unwrap();
+    assert_ne!(annotation.closest_genes[0].prom_label, INTERGENIC);
+
+    let annotation: GeneAnnotation = inclusive.annotate(&after).unwrap();
+    assert_ne!(annotation.closest_genes[0].prom_label, INTERGENIC);
+
+    let exclusive: Annotate = Annotate::new(test_db(&ONE_GENE_ROWS), TSSRegion::default(), 1)
+        .with_boundary(Boundary::Exclusive);
+
+    let annotation: GeneAnnotation = exclusive.annotate(&before).unwrap();
+    assert_eq!(annotation.closest_genes[0].prom_label, INTERGENIC);
+
+    let annotation: GeneAnnotation = exclusive.annotate(&after).unwrap();
+    assert_eq!(annotation.closest_genes[0].prom_label, INTERGENIC);
+}
+
+#[test]
+fn test_genes_within_genome() {
+    let db: LoctogeneDb = test_db(
+        &[
+            (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (2, "chr1", 15000, 40000, "-", "G2", "GENE2"),
+            (2, "chr2", 10000, 20000, "+", "G3", "GENE3"),
+            (2, "chr3", 50000, 60000, "-", "G4", "GENE4"),
+        ],
+    );
+
+    let locations: Vec<Location> = vec![
+        Location::parse("chr1:12000-12100").unwrap(),
+        Location::parse("chr1:16000-16100").unwrap(),
+        Location::parse("chr1:30000-30100").unwrap(),
+        Location::parse("chr1:45000-45100").unwrap(),
+        Location::parse("chr2:19000-19100").unwrap(),
+        Location::parse("chr3:55000-55100").unwrap(),
+    ];
+
+    let genes: HashMap<LocationKey, Vec<GenomicFeature>> = db
+        .get_genes_within_genome(&locations, &Level::Transcript)
+        .unwrap();
+
+    // a single query per chromosome
+    assert_eq!(db.query_count(), 3);
+    assert_eq!(genes.len(), locations.len());
+
+    for location in locations.iter() {
+        let expected: Vec<GenomicFeature> =
+            db.get_genes_within(location, &Level::Transcript).unwrap();
+
+        assert_eq!(genes[&LocationKey::from(location)], expected);
+    }
+
+    let ids = |location: &Location| -> Vec<String> {
+        genes[&LocationKey::from(location)]
+            .iter()
+            .map(|f| f.gene_id.to_owned())
+            .collect()
+    };
+
+    assert_eq!(ids(&locations[0]), vec!["G1"]);
+    assert_eq!(ids(&locations[1]), vec!["G1", "G2"]);
+    assert_eq!(ids(&locations[2]), vec!["G2"]);
+    assert!(ids(&locations[3]).is_empty());
+    assert_eq!(ids(&locations[4]), vec!["G3"]);
+    assert_eq!(ids(&locations[5]), vec!["G4"]);
+}
+
+#[test]
+fn test_search_window() {
+    let annotate: Annotate = Annotate::new(
+        test_db(&ONE_GENE_ROWS),
+        TSSRegion::new(2000, 1000).unwrap(),
+        1,
+    );
+
+    let annotation: GeneAnnotation = annotate
+        .annotate(&Location::parse("chr1:9000-9200").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.search_window, "chr1:7100-11100");
+
+    // the window is clipped at the start of the chromosome
+    let annotation: GeneAnnotation = annotate
+        .annotate(&Location::parse("chr1:1000-1000").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.search_window, "chr1:0-3000");
+
+    // by interval overlap the whole location is padded
+    let annotation: GeneAnnotation = annotate
+        .with_interval_overlap(true)
+        .annotate(&Location::parse("chr1:9000-9200").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.search_window, "chr1:7000-11200");
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn test_make_gene_table_boolean_columns() {
+    let genesdb: LoctogeneDb = test_db(
+        &[
+            (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (3, "chr1", 10000, 10500, "+", "G1", "GENE1"),
+        ],
+    );
+
+    let annotatedb: Annotate =
+        Annotate::new(genesdb, TSSRegion::default(), 5).with_boolean_columns(true);
+
+    let locations: Vec<Location> = vec![
+        Location::parse("chr1:9800-9900").unwrap(),
+        Location::parse("chr1:15000-15100").unwrap(),
+        Location::parse("chr1:50000-50100").unwrap(),
+    ];
+
+    let table: String = annotatedb
+        .make_gene_table(&locations, 1, &TSSRegion::default())
+        .unwrap();
+
+    let lines: Vec<Vec<&str>> = table.lines().map(|line| line.split('\t').collect()).collect();
+
+    assert_eq!(lines[0], annotatedb.table_columns(1, &TSSRegion::default()));
+    assert_eq!(lines[0][5..8], ["Is Promoter", "Is Exonic", "Is Intronic"]);
+
+    // promoter peak upstream of the TSS
+    assert_eq!(lines[1][5..8], ["true", "false", "false"]);
+    // intron
+    assert_eq!(lines[2][5..8], ["false", "false", "true"]);
+    // intergenic
+    assert_eq!(lines[3][5..8], ["false", "false", "false"]);
+    assert_eq!(lines[1].len(), 12);
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn test_make_gene_table_direction_columns() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (1, "chr1", 30000, 40000, "-", "G2", "GENE2"),
+                (2, "chr1", 30000, 40000, "-", "G2", "GENE2"),
+            ],
+        ),
+        TSSRegion::default(),
+        5,
+    )
+    .with_direction_columns(true);
+
+    let locations: Vec<Location> = vec![
+        // before the TSS of a + strand gene
+        Location::parse("chr1:9400-9600").unwrap(),
+        // after the TSS of a - strand gene
+        Location::parse("chr1:40900-41100").unwrap(),
+        Location::parse("chr1:9900-10100").unwrap(),
+        Location::parse("chr1:39000-39200").unwrap(),
+    ];
+
+    let table: String = annotatedb
+        .make_gene_table(&locations, 1, &TSSRegion::default())
+        .unwrap();
+
+    let lines: Vec<Vec<&str>> = table.lines().map(|line| line.split('\t').collect()).collect();
+
+    assert_eq!(lines[0], annotatedb.table_columns(1, &TSSRegion::default()));
+    assert_eq!(lines[0][4..6], ["TSS Distance (bp)", "Direction"]);
+
+    assert_eq!(lines[1][4..6], ["500", "upstream"]);
+    assert_eq!(lines[2][4..6], ["1000", "upstream"]);
+    assert_eq!(lines[3][4..6], ["0", "overlapping"]);
+    assert_eq!(lines[4][4..6], ["900", "downstream"]);
+    assert_eq!(lines[1].len(), lines[0].len());
+}
+
+#[test]
+fn test_join_limit() {
+    let rows: Vec<(u8, &str, u32, u32, &str, String, String)> = (0..12)
+        .map(|i| {
+            (
+                2,
+                "chr1",
+                10000 + i * 100,
+                20000,
+                "+",
+                format!("G{}", i),
+                format!("GENE{}", i),
+            )
+        })
+        .collect();
+
+    let rows: Vec<TestRow> = rows
+        .iter()
+        .map(|r| (r.0, r.1, r.2, r.3, r.4, r.5.as_str(), r.6.as_str()))
+        .collect();
+
+    let loc: Location = Location::parse("chr1:15000-15000").unwrap();
+
+    let annotation: GeneAnnotation =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 0)
+            .with_join_limit(Some(10))
+            .annotate(&loc)
+            .unwrap();
+
+    // genes are in distance order, so the furthest, G0 and G1, are counted
+    assert_eq!(annotation.gene_ids, "G11;G10;G9;G8;G7;G6;G5;G4;G3;G2 (+2 more)");
+    assert!(annotation.gene_symbols.starts_with("GENE11;GENE10;"));
+    assert!(annotation.gene_symbols.ends_with("GENE2 (+2 more)"));
+    assert_eq!(annotation.genes.len(), 12);
+
+    let annotation: GeneAnnotation =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 0)
+            .annotate(&loc)
+            .unwrap();
+
+    assert_eq!(annotation.gene_ids.split(';').count(), 12);
+}
+
+#[test]
+fn test_annotation_vecs() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (1, "chr1", 10000, 20000, "+", "G1", "GENE;1"),
+                (2, "chr1", 10000, 20000, "+", "G1", "GENE;1"),
+                (1, "chr1", 12000, 30000, "-", "G2", "GENE2"),
+                (2, "chr1", 12000, 30000, "-", "G2", "GENE2"),
+            ],
+        ),
+        TSSRegion::default(),
+        0,
+    );
+
+    let annotation: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:15000-15000").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.gene_ids_vec(), vec!["G1", "G2"]);
+    assert_eq!(annotation.tss_dists_vec(), vec![5000, 15000]);
+
+    // the symbol keeps its semicolon rather than splitting in two
+    assert_eq!(annotation.gene_symbols_vec(), vec!["GENE;1", "GENE2"]);
+    assert_eq!(annotation.gene_symbols.split(';').count(), 3);
+
+    // joining the vecs gives back the joined fields
+    assert_eq!(annotation.gene_ids_vec().join(";"), annotation.gene_ids);
+    assert_eq!(annotation.gene_symbols_vec().join(";"), annotation.gene_symbols);
+
+    assert_eq!(
+        annotation
+            .prom_labels_vec()
+            .iter()
+            .map(|l| l.to_string())
+            .collect::<Vec<String>>()
+            .join(";"),
+        annotation.prom_labels
+    );
+
+    assert_eq!(
+        annotation
+            .tss_dists_vec()
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<String>>()
+            .join(";"),
+        annotation.tss_dists
+    );
+}
+
+#[test]
+fn test_bidirectional_promoter() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (1, "chr1", 1000, 9900, "-", "G1", "GENE1"),
+                (1, "chr1", 10100, 20000, "+", "G2", "GENE2"),
+                (1, "chr2", 1000, 9900, "+", "G3", "GENE3"),
+                (1, "chr2", 10100, 20000, "-", "G4", "GENE4"),
+            ],
+        ),
+        TSSRegion::default(),
+        5,
+    );
+
+    let loc: Location = Location::parse("chr1:9950-10050").unwrap();
+
+    let (minus, plus) = annotatedb.is_bidirectional_promoter(&loc, 500).unwrap().unwrap();
+
+    assert_eq!(minus.gene_id, "G1");
+    assert_eq!(plus.gene_id, "G2");
+
+    // TSSs are 200 bp apart
+    assert!(annotatedb.is_bidirectional_promoter(&loc, 100).unwrap().is_none());
+
+    // same genes, but the location is not in either promoter
+    let loc: Location = Location::parse("chr1:50000-50100").unwrap();
+    assert!(annotatedb.is_bidirectional_promoter(&loc, 500).unwrap().is_none());
+
+    // genes pointing towards each other are not divergent
+    let loc: Location = Location::parse("chr2:9950-10050").unwrap();
+    assert!(annotatedb.is_bidirectional_promoter(&loc, 500).unwrap().is_none());
+}
+
+#[test]
+fn test_classify_features() {
+    // only exons come from the database
+    let annotatedb: Annotate = Annotate::new(
+        test_db(&[(3, "chr1", 15000, 15500, "+", "G1", "GENE1")]),
+        TSSRegion::default(),
+        5,
+    );
+
+    let feature = |strand: &str, start: u32, end: u32, gene_id: &str| -> GenomicFeature {
+        GenomicFeature {
+            id: 1,
+            chr: "chr1".to_owned(),
+            start,
+            end,
+            strand: strand.to_owned(),
+            gene_id: gene_id.to_owned(),
+            gene_symbol: gene_id.to_owned(),
+            dist: 0,
+            score: None,
+        }
+    };
+
+    let features: Vec<GenomicFeature> = vec![
+        feature("+", 10000, 20000, "G1"),
+        feature("-", 30000, 40000, "G2"),
+    ];
+
+    let labels: Vec<(String, String)> = annotatedb
+        .classify_features(&Location::parse("chr1:9500-9600").unwrap(), &features)
+        .unwrap();
+
+    assert_eq!(
+        labels,
+        vec![
+            ("G1".to_owned(), "promoter".to_owned()),
+            ("G2".to_owned(), "intergenic".to_owned())
+        ]
+    );
+
+    let labels: Vec<(String, String)> = annotatedb
+        .classify_features(&Location::parse("chr1:15000-15100").unwrap(), &features[..1])
+        .unwrap();
+
+    assert_eq!(labels, vec![("G1".to_owned(), "exonic".to_owned())]);
+
+    let labels: Vec<(String, String)> = annotatedb
+        .classify_features(&Location::parse("chr1:18000-18100").unwrap(), &features[..1])
+        .unwrap();
+
+    assert_eq!(labels, vec![("G1".to_owned(), "intronic".to_owned())]);
+}
+
+#[test]
+fn test_classify() {
+    let feature: GenomicFeature = GenomicFeature {
+        id: 1,
+        chr: "chr1".to_owned(),
+        start: 10000,
+        end: 20000,
+        strand: "+".to_owned(),
+        gene_id: "G1".to_owned(),
+        gene_symbol: "GENE1".to_owned(),
+        dist: 0,
+        score: None,
+    };
+
+    let annotatedb: Annotate = Annotate::new(
+        test_db(&[(3, "chr1", 9000, 10500, "+", "G1", "GENE1")]),
+        TSSRegion::default(),
+        5,
+    );
+
+    let label: PromoterLabel = annotatedb
+        .classify(&Location::parse("chr1:9900-10000").unwrap(), &feature)
+        .unwrap();
+
+    assert_eq!(label.classes, vec![RegionClass::Promoter, RegionClass::Exonic]);
+
+    // a failed exon lookup is reported rather than treated as not exonic
+    let broken: Annotate = Annotate::new(
+        test_db_with(
+            &[],
+            "DROP TABLE genes; CREATE TABLE genes (id INTEGER PRIMARY KEY);",
+        ),
+        TSSRegion::default(),
+        5,
+    );
+
+    assert!(broken
+        .classify(&Location::parse("chr1:15000-15100").unwrap(), &feature)
+        .is_err());
+
+    // no lookup is needed for locations nowhere near the feature
+    assert_eq!(
+        broken
+            .classify(&Location::parse("chr1:50000-50100").unwrap(), &feature)
+            .unwrap(),
+        INTERGENIC
+    );
+}
+
+#[test]
+fn test_max_tss_dist() {
+    // two transcripts whose TSSs are 50kb apart
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (2, "chr1", 10000, 100000, "+", "G1", "GENE1"),
+                (2, "chr1", 60000, 100000, "+", "G1", "GENE1"),
+            ],
+        ),
+        TSSRegion::default(),
+        0,
+    );
+
+    let annotation: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:65000-65000").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.genes.len(), 1);
+    assert_eq!(annotation.genes[0].tss_dist, 5000);
+    assert_eq!(annotation.genes[0].max_tss_dist, 55000);
+
+    // a single transcript has the same min and max
+    let annotation: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:30000-30000").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.genes[0].tss_dist, 20000);
+    assert_eq!(annotation.genes[0].max_tss_dist, 20000);
+}
+
+#[test]
+fn test_is_genic() {
+    let annotatedb: Annotate = Annotate::new(test_db(&ONE_GENE_ROWS), TSSRegion::default(), 1);
+
+    let genic: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:15000-15100").unwrap())
+        .unwrap();
+
+    assert!(genic.is_genic);
+
+    // promoter only
+    let promoter: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:9000-9100").unwrap())
+        .unwrap();
+
+    assert!(promoter.is_genic);
+
+    let intergenic: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:50000-50100").unwrap())
+        .unwrap();
+
+    assert!(!intergenic.is_genic);
+    assert_eq!(intergenic.gene_ids, NA);
+}
+
+#[test]
+fn test_read_locations() {
+    let bed: &str = concat!(
+        "track name=peaks\n",
+        "# comment\n",
+        "chr1\t100\t200\tpeak1\n",
+        "\n",
+        "chr2\t300\t400\r\n",
+        "chr3\t500\t600"
+    );
+
+    let locations: Vec<Location> = read_locations(Cursor::new(bed), LocationFormat::Bed).unwrap();
+
+    // the last line has no newline
+    assert_eq!(
+        locations.iter().map(|l| l.to_string()).collect::<Vec<String>>(),
+        vec!["chr1:100-200", "chr2:300-400", "chr3:500-600"]
+    );
+
+    let tsv: &str = "chr1:100-200\tpeak1\nchr2:300-400\n";
+
+    let locations: Vec<Location> =
+        read_locations(Cursor::new(tsv), LocationFormat::Locations).unwrap();
+
+    assert_eq!(locations.len(), 2);
+    assert_eq!(locations[1].to_string(), "chr2:300-400");
+
+    let err = read_locations(Cursor::new("chr1\t100\n"), LocationFormat::Bed)
+        .err()
+        .unwrap();
+
+    assert_eq!(err.to_string(), "line 1: expected chr, start and end columns");
+}
+
+#[test]
+fn test_read_bed() {
+    let bed: &str = "chr1\t99\t200\nchr2\t0\t50\n";
+
+    let locations: Vec<Location> = read_bed(Cursor::new(bed)).unwrap();
+
+    assert_eq!(
+        locations.iter().map(|l| l.to_string()).collect::<Vec<String>>(),
+        vec!["chr1:100-200", "chr2:1-50"]
+    );
+
+    let bed: &str = concat!(
+        "browser position chr1:1-1000\n",
+        "track name=peaks\n",
+        "chr1\t999\t2000\tpeak1\t500\t+\n",
+        "chr1\t4999\t6000\tpeak2\t250\t-\n"
+    );
+
+    let locations: Vec<Location> = read_bed(Cursor::new(bed)).unwrap();
+
+    assert_eq!(
+        locations.iter().map(|l| l.to_string()).collect::<Vec<String>>(),
+        vec!["chr1:1000-2000", "chr1:5000-6000"]
+    );
+
+    let err = read_bed(Cursor::new("chr1\t100\t200\nchr1\tstart\t200\n"))
+        .err()
+        .unwrap();
+
+    assert_eq!(err.to_string(), "line 2: invalid coordinates");
+}
+
+#[test]
+fn test_point_features() {
+    let db: LoctogeneDb = test_db(
+        &[
+            (2, "chr1", 15000, 15000, "+", "P1", "POINT1"),
+            (3, "chr1", 15000, 15000, "+", "P1", "POINT1"),
+        ],
+    );
+
+    // a location spanning the point, touching it at either end and a
+    // point query on the same base all overlap it
+    for loc in ["chr1:14000-16000", "chr1:15000-16000", "chr1:14000-15000", "chr1:15000-15000"] {
+        let loc: Location = Location::parse(loc).unwrap();
+
+        assert_eq!(db.get_genes_within(&loc, &Level::Transcript).unwrap().len(), 1);
+        assert_eq!(db.in_exon(&loc, "P1").unwrap().len(), 1);
+    }
+
+    for loc in ["chr1:14000-14999", "chr1:15001-16000"] {
+        let loc: Location = Location::parse(loc).unwrap();
+
+        assert!(db.get_genes_within(&loc, &Level::Transcript).unwrap().is_empty());
+        assert!(db.in_exon(&loc, "P1").unwrap().is_empty());
+    }
+}
+
+#[cfg(test)]
+struct CpgClassifier;
+
+#[cfg(test)]
+impl Classifier for CpgClassifier {
+    fn classify(&self, _location: &Location, _feature: &GenomicFeature) -> Vec<String> {
+        vec!["cpg_island".to_owned()]
+    }
+}
+
+#[test]
+fn test_classifier() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (2, "chr1", 12000, 20000, "+", "G1", "GENE1"),
+                (1, "chr1", 50000, 60000, "+", "G2", "GENE2"),
+            ],
+        ),
+        TSSRegion::default(),
+        2,
+    )
+    .with_classifier(Box::new(CpgClassifier));
+
+    let annotation: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:9500-9600").unwrap())
+        .unwrap();
+
+    // added once per gene however many transcripts it has
+    assert_eq!(annotation.prom_labels, "promoter,cpg_island");
+    assert_eq!(annotation.genes[0].custom_labels, vec!["cpg_island"]);
+    assert_eq!(annotation.closest_genes[0].prom_label, "promoter,cpg_island");
+    assert_eq!(annotation.closest_genes[1].prom_label, "intergenic,cpg_island");
+}
+
+#[test]
+fn test_chr_cache() {
+    let rows: [TestRow; 8] = [
+        (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+        (1, "chr1", 15000, 40000, "-", "G2", "GENE2"),
+        (1, "chr1", 60000, 70000, "+", "G3", "GENE3"),
+        (1, "chr2", 10000, 20000, "-", "G4", "GENE4"),
+        (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+        (2, "chr1", 15000, 40000, "-", "G2", "GENE2"),
+        (3, "chr1", 10000, 11000, "+", "G1", "GENE1"),
+        (3, "chr1", 36000, 40000, "-", "G2", "GENE2"),
+    ];
+
+    let db: LoctogeneDb = test_db(&rows);
+    let cached: LoctogeneDb = test_db(&rows).with_chr_cache(true);
+
+    let locations: Vec<Location> = (0..20)
+        .map(|i| Location::new("chr1", 5000 + i * 4000, 5100 + i * 4000).unwrap())
+        .collect();
+
+    for location in locations.iter() {
+        assert_eq!(
+            cached.get_closest_genes(location, 2, Level::Gene).unwrap(),
+            db.get_closest_genes(location, 2, Level::Gene).unwrap()
+        );
+
+        assert_eq!(
+            cached.get_genes_within(location, &Level::Transcript).unwrap(),
+            db.get_genes_within(location, &Level::Transcript).unwrap()
+        );
+
+        assert_eq!(
+            cached.get_genes_within_promoter_mid(location, &Level::Transcript, 2000).unwrap(),
+            db.get_genes_within_promoter_mid(location, &Level::Transcript, 2000).unwrap()
+        );
+
+        assert_eq!(cached.in_exons(location).unwrap(), db.in_exons(location).unwrap());
+        assert_eq!(cached.in_exon(location, "G2").unwrap(), db.in_exon(location, "G2").unwrap());
+    }
+
+    // one query per chromosome and level rather than one per call
+    assert_eq!(db.query_count(), 100);
+    assert_eq!(cached.query_count(), 3);
+
+    // annotating a batch on one chromosome needs no more queries than the
+    // first location does
+    let annotatedb: Annotate = Annotate::new(
+        test_db(&rows).with_chr_cache(true),
+        TSSRegion::default(),
+        2,
+    );
+
+    let annotations: Vec<GeneAnnotation> = locations
+        .iter()
+        .map(|location| annotatedb.annotate(location).unwrap())
+        .collect();
+
+    assert_eq!(annotatedb.genesdb().query_count(), 3);
+
+    let uncached: Annotate =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 2);
+
+    for (location, annotation) in locations.iter().zip(annotations) {
+        assert_eq!(uncached.annotate(location).unwrap(), annotation);
+    }
+}
+
+// Time taken to annotate a batch of locations on one chromosome with and
+// without the chromosome cache. Run with
+// cargo test bench_ -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_chr_cache() {
+    let locations: Vec<Location> = (0..500)
+        .map(|i| Location::new("chr1", i * 20000 + 1, i * 20000 + 500).unwrap())
+        .collect();
+
+    for (name, chr_cache) in [("uncached", false), ("cached", true)] {
+        let annotatedb: Annotate = Annotate::new(
+            test_db_with(&[], DENSE_SQL).with_chr_cache(chr_cache),
+            TSSRegion::default(),
+            5,
+        );
+
+        let start: std::time::Instant = std::time::Instant::now();
+
+        for location in locations.iter() {
+            annotatedb.annotate(location).unwrap();
+        }
+
+        println!(
+            "{}: {} locations, {} queries in {:?}",
+            name,
+            locations.len(),
+            annotatedb.genesdb().query_count(),
+            start.elapsed()
+        );
+    }
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn test_to_row() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (1, "chr1", 30000, 40000, "-", "G2", "GENE2"),
+            ],
+        ),
+        TSSRegion::default(),
+        5,
+    );
+
+    let locations: Vec<Location> = vec![
+        Location::parse("chr1:9500-9600").unwrap(),
+        Location::parse("chr1:25000-25100").unwrap(),
+    ];
+
+    let table: String = annotatedb
+        .make_gene_table(&locations, 3, &TSSRegion::default())
+        .unwrap();
+
+    let lines: Vec<Vec<&str>> = table.lines().map(|line| line.split('\t').collect()).collect();
+
+    for (i, location) in locations.iter().enumerate() {
+        let row: Vec<String> = annotatedb.annotate(location).unwrap().to_row(location, 3);
+
+        assert_eq!(lines[i + 1], row);
+        assert_eq!(row.len(), lines[0].len());
+    }
+}
+
+#[test]
+fn test_overlaps_tss() {
+    let annotatedb: Annotate = Annotate::new(test_db(&ONE_GENE_ROWS), TSSRegion::default(), 5);
+
+    // starts upstream of the TSS with its midpoint in the gene body
+    let straddling: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:9800-10600").unwrap())
+        .unwrap();
+
+    assert!(straddling.genes[0].overlaps_tss);
+    assert!(straddling.genes[0].is_intronic);
+
+    // in the promoter but not reaching the TSS
+    let promoter: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:9000-9500").unwrap())
+        .unwrap();
+
+    assert!(!promoter.genes[0].overlaps_tss);
+    assert!(promoter.genes[0].is_promoter);
+}
+
+#[test]
+fn test_transcript_support() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (2, "chr1", 5000, 8000, "+", "G1", "GENE1"),
+                (2, "chr1", 4000, 8500, "+", "G1", "GENE1"),
+            ],
+        ),
+        TSSRegion::default(),
+        0,
+    );
+
+    // only the first transcript has the location in its promoter, the
+    // others end shortly before it
+    let annotation: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:9500-9500").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.genes.len(), 1);
+    assert!(annotation.genes[0].is_promoter);
+    assert_eq!(annotation.genes[0].transcript_support, 1);
+
+    // inside the two shorter transcripts
+    let annotation: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:7000-7000").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.genes[0].transcript_support, 2);
+}
+
+#[test]
+fn test_coverage_stats() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (1, "chr1", 19000, 25000, "-", "G2", "GENE2"),
+                (2, "chr1", 19000, 25000, "-", "G2", "GENE2"),
+            ],
+        ),
+        TSSRegion::default(),
+        0,
+    );
+
+    let locations: Vec<Location> = vec![
+        // overlapping peaks across the end of G1 and start of G2
+        Location::parse("chr1:18001-20000").unwrap(),
+        Location::parse("chr1:19001-21000").unwrap(),
+        // half in the promoter of G1, half before it
+        Location::parse("chr1:9001-11000").unwrap(),
+        Location::parse("chr1:50001-51000").unwrap(),
+    ];
+
+    let stats: CoverageStats = annotatedb.coverage_stats(&locations).unwrap();
+
+    assert_eq!(stats.peaks, 4);
+    assert_eq!(stats.genic_peaks, 3);
+    assert_eq!(stats.intergenic_peaks, 1);
+    // 18001-21000, 9001-11000 and 50001-51000
+    assert_eq!(stats.total_bp, 3000 + 2000 + 1000);
+    // 18001-21000 is all in genes and 10000-11000 is in G1
+    assert_eq!(stats.genic_bp, 3000 + 1001);
+}
+
+#[test]
+fn test_negative_coordinates() {
+    let db: LoctogeneDb = test_db_with(
+        &[
+            (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (2, "chr1", 12000, 30000, "+", "G2", "GENE2"),
+        ],
+        "UPDATE genes SET start = -5 WHERE gene_id = 'G2'",
+    );
+
+    let loc: Location = Location::parse("chr1:15000-15000").unwrap();
+
+    let err = db.get_genes_within(&loc, &Level::Transcript).err().unwrap();
+
+    assert_eq!(err.to_string(), "feature 2 has invalid start -5");
+}
+
+#[test]
+fn test_interval_overlap() {
+    let rows: [TestRow; 2] = [
+        (2, "chr1", 1000, 3000, "+", "G1", "GENE1"),
+        (2, "chr1", 25000, 28000, "+", "G2", "GENE2"),
+    ];
+
+    // the midpoint, 17500, is far from both genes but the peak overlaps G2
+    let loc: Location = Location::parse("chr1:5000-30000").unwrap();
+
+    let annotation: GeneAnnotation =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 0)
+            .annotate(&loc)
+            .unwrap();
+
+    assert_eq!(annotation.gene_ids, NA);
+
+    let annotation: GeneAnnotation =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 0)
+            .with_interval_overlap(true)
+            .annotate(&loc)
+            .unwrap();
+
+    // G1 only comes within the padding of the peak so is still left out
+    assert_eq!(annotation.gene_ids, "G2");
+    assert_eq!(annotation.prom_labels, "intronic");
+}
+
+#[test]
+fn test_compare_chrs() {
+    let mut chrs: Vec<&str> = vec![
+        "chrM", "chr10", "chrY", "chr2", "chrUn_KI270302v1", "chrX", "chr1", "chr22",
+    ];
+
+    chrs.sort_by(|a, b| compare_chrs(a, b));
+
+    assert_eq!(
+        chrs,
+        vec!["chr1", "chr2", "chr10", "chr22", "chrX", "chrY", "chrM", "chrUn_KI270302v1"]
+    );
+}
+
+#[test]
+fn test_annotate_sorted() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (2, "chr2", 10000, 20000, "+", "G1", "GENE1"),
+                (2, "chr10", 10000, 20000, "+", "G2", "GENE2"),
+            ],
+        ),
+        TSSRegion::default(),
+        0,
+    );
+
+    let locations: Vec<Location> = vec![
+        Location::parse("chr10:15000-15100").unwrap(),
+        Location::parse("chrX:100-200").unwrap(),
+        Location::parse("chr2:15000-15100").unwrap(),
+        Location::parse("chr2:5000-5100").unwrap(),
+    ];
+
+    let sorted: Vec<(Location, GeneAnnotation)> = annotatedb.annotate_sorted(&locations).unwrap();
+
+    assert_eq!(
+        sorted.iter().map(|(l, _)| l.to_string()).collect::<Vec<String>>(),
+        vec!["chr2:5000-5100", "chr2:15000-15100", "chr10:15000-15100", "chrX:100-200"]
+    );
+
+    // annotations stay with their locations
+    assert_eq!(sorted[1].1.gene_ids, "G1");
+    assert_eq!(sorted[2].1.gene_ids, "G2");
+}
+
+#[test]
+fn test_annotate_detailed() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (3, "chr1", 14000, 16000, "+", "G1", "GENE1"),
+                (1, "chr1", 15500, 30000, "-", "G2", "GENE2"),
+                (2, "chr1", 15500, 30000, "-", "G2", "GENE2"),
+            ],
+        ),
+        TSSRegion::default(),
+        5,
+    );
+
+    let loc: Location = Location::parse("chr1:15000-15100").unwrap();
+
+    let detailed: Vec<PromoterOverlap> = annotatedb.annotate_detailed(&loc).unwrap();
+    let annotation: GeneAnnotation = annotatedb.annotate(&loc).unwrap();
+
+    assert_eq!(detailed, annotation.genes);
+
+    let ids: Vec<&str> = annotation.gene_ids.split(';').collect();
+    let labels: Vec<&str> = annotation.prom_labels.split(';').collect();
+    let dists: Vec<&str> = annotation.tss_dists.split(';').collect();
+
+    assert_eq!(detailed.len(), 2);
+
+    for (i, gene) in detailed.iter().enumerate() {
+        assert_eq!(gene.gene_id, ids[i]);
+        assert_eq!(gene.tss_dist.to_string(), dists[i]);
+        assert_eq!(
+            labels[i],
+            match (gene.is_exon, gene.is_intronic) {
+                (true, _) => "exonic",
+                (false, true) => "intronic",
+                _ => "",
+            }
+        );
+    }
+
+    assert!(detailed[0].is_exon);
+    assert!(!detailed[1].is_exon);
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn test_make_gene_table_dedupe_locations() {
+    let rows: [TestRow; 3] = [
+        (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+        (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+        (3, "chr1", 10000, 11000, "+", "G1", "GENE1"),
+    ];
+
+    let loc: Location = Location::parse("chr1:15000-15100").unwrap();
+    let other: Location = Location::parse("chr1:9000-9100").unwrap();
+
+    let single: Annotate =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 1);
+
+    single
+        .make_gene_table(&[loc.clone(), other.clone()], 1, &TSSRegion::default())
+        .unwrap();
+
+    let annotatedb: Annotate =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 1)
+            .with_dedupe_locations(true);
+
+    let locations: Vec<Location> = vec![loc.clone(), other.clone(), loc.clone(), loc.clone()];
+
+    let table: String = annotatedb
+        .make_gene_table(&locations, 1, &TSSRegion::default())
+        .unwrap();
+
+    let lines: Vec<&str> = table.lines().collect();
+
+    // every occurrence has a row, in input order, but is only annotated once
+    assert_eq!(lines.len(), 5);
+    assert_eq!(lines[1], lines[3]);
+    assert_eq!(lines[1], lines[4]);
+    assert!(lines[2].starts_with("chr1:9000-9100\t"));
+    assert_eq!(annotatedb.genesdb().query_count(), single.genesdb().query_count());
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn test_make_named_gene_table() {
+    let annotatedb: Annotate = Annotate::new(test_db(&ONE_GENE_ROWS), TSSRegion::default(), 1);
+
+    let bed: &str = concat!(
+        "chr1\t15000\t15100\tpeak1\t0\t+\n",
+        "chr1\t9000\t9100\n",
+        "chr1\t50000\t50100\tpeak3\n"
+    );
+
+    let locations: Vec<NamedLocation> =
+        read_named_locations(Cursor::new(bed), LocationFormat::Bed).unwrap();
+
+    assert_eq!(locations[0].name.as_deref(), Some("peak1"));
+    assert_eq!(locations[1].name, None);
+
+    let table: String = annotatedb
+        .make_named_gene_table(&locations, 1, &TSSRegion::default())
+        .unwrap();
+
+    let lines: Vec<Vec<&str>> = table.lines().map(|line| line.split('\t').collect()).collect();
+
+    assert_eq!(lines[0][0], "Name");
+    assert_eq!(lines[0][1..], annotatedb.table_columns(1, &TSSRegion::default()));
+    assert_eq!(lines[1][..3], ["peak1", "chr1:15000-15100", "G1"]);
+    // no name so the location is used
+    assert_eq!(lines[2][..2], ["chr1:9000-9100", "chr1:9000-9100"]);
+    assert_eq!(lines[3][..2], ["peak3", "chr1:50000-50100"]);
+}
+
+// Records how much had been written each time it was flushed
+#[cfg(test)]
+#[cfg(feature = "table")]
+struct FlushLog {
+    data: Vec<u8>,
+    flushed: Vec<usize>,
+}
+
+#[cfg(test)]
+#[cfg(feature = "table")]
+impl std::io::Write for FlushLog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.flushed.push(self.data.len());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn test_annotate_reader() {
+    let annotatedb: Annotate = Annotate::new(test_db(&ONE_GENE_ROWS), TSSRegion::default(), 1);
+
+    // more than one batch, with the last line missing its newline
+    let locations: Vec<Location> = (0..1500)
+        .map(|i| Location::new("chr1", 9000 + i * 10, 9000 + i * 10 + 5).unwrap())
+        .collect();
+
+    let mut input: String = String::from("# peaks\n");
+
+    for location in locations.iter() {
+        input.push_str(&format!("{}\n", location));
+    }
+
+    input.pop();
+
+    let mut output: FlushLog = FlushLog {
+        data: vec![],
+        flushed: vec![],
+    };
+
+    annotatedb
+        .annotate_reader(Cursor::new(input), LocationFormat::Locations, 1, &mut output)
+        .unwrap();
+
+    let table: String = String::from_utf8(output.data).unwrap();
+
+    assert_eq!(
+        table,
+        annotatedb.make_gene_table(&locations, 1, &TSSRegion::default()).unwrap()
+    );
+
+    // the first batch was written before the rest was read
+    let first_batch: usize = table.lines().take(1025).map(|line| line.len() + 1).sum();
+
+    assert!(output.flushed.contains(&first_batch));
+
+    // an unreadable line fails with its line number
+    let bad: Cursor<&str> = Cursor::new("chr1:100-200\nchr1:x");
+
+    let err = annotatedb
+        .annotate_reader(bad, LocationFormat::Locations, 1, &mut vec![])
+        .err()
+        .unwrap();
+
+    assert_eq!(err.to_string(), "line 2: invalid location chr1:x");
+
+    // duplicates are only looked for within a batch, so a location
+    // repeated into a second batch is annotated again
+    let queries = |n: usize| -> usize {
+        let deduped: Annotate = Annotate::new(test_db(&ONE_GENE_ROWS), TSSRegion::default(), 1)
+            .with_dedupe_locations(true);
+
+        let input: String = vec!["chr1:15000-15100"; n].join("\n");
+
+        deduped
+            .annotate_reader(Cursor::new(input), LocationFormat::Locations, 1, &mut vec![])
+            .unwrap();
+
+        deduped.genesdb().query_count()
+    };
+
+    assert_eq!(queries(1024), queries(1));
+    assert!(queries(1025) > queries(1024));
+}
+
+#[test]
+fn test_transcript_promoters() {
+    let rows: [TestRow; 3] = [
+        (1, "chr1", 10000, 50000, "+", "G1", "GENE1"),
+        (2, "chr1", 10000, 50000, "+", "G1", "GENE1"),
+        // alternative TSS inside the gene
+        (2, "chr1", 30000, 50000, "+", "G1", "GENE1"),
+    ];
+
+    let loc: Location = Location::parse("chr1:29500-29500").unwrap();
+
+    let annotation: GeneAnnotation =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 1)
+            .annotate(&loc)
+            .unwrap();
+
+    assert_eq!(annotation.closest_genes[0].prom_label, "intronic");
+
+    let annotation: GeneAnnotation =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 1)
+            .with_transcript_promoters(true)
+            .annotate(&loc)
+            .unwrap();
+
+    assert_eq!(annotation.closest_genes[0].prom_label, "promoter,intronic");
+}
+
+#[test]
+fn test_annotate_columns() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (1, "chr2", 1000, 2000, "+", "G2", "GENE2"),
+            ],
+        ),
+        TSSRegion::default(),
+        5,
+    );
+
+    let locations: Vec<Location> = vec![
+        Location::parse("chr1:15000-15100").unwrap(),
+        Location::parse("chr1:50000-50100").unwrap(),
+        // no genes at all
+        Location::parse("chr3:100-200").unwrap(),
+    ];
+
+    let columns: AnnotationColumns = annotatedb.annotate_columns(&locations).unwrap();
+
+    for len in [
+        columns.locations.len(),
+        columns.gene_ids.len(),
+        columns.gene_symbols.len(),
+        columns.prom_labels.len(),
+        columns.tss_dists.len(),
+        columns.closest_gene_ids.len(),
+        columns.closest_gene_symbols.len(),
+        columns.closest_prom_labels.len(),
+        columns.closest_tss_dists.len(),
+    ] {
+        assert_eq!(len, locations.len());
+    }
+
+    assert_eq!(columns.gene_ids, vec!["G1", NA, NA]);
+    assert_eq!(columns.closest_gene_ids, vec!["G1", "G1", NA]);
+    assert_eq!(columns.closest_tss_dists[2], None);
+}
+
+#[test]
+fn test_chr_normalization() {
+    let rows: [TestRow; 3] = [
+        (1, "3", 10000, 20000, "+", "G1", "GENE1"),
+        (1, "3", 30000, 40000, "-", "G2", "GENE2"),
+        (1, "4", 10000, 20000, "+", "G3", "GENE3"),
+    ];
+
+    let prefixed: [TestRow; 3] = [
+        (1, "chr3", 10000, 20000, "+", "G1", "GENE1"),
+        (1, "chr3", 30000, 40000, "-", "G2", "GENE2"),
+        (1, "chr4", 10000, 20000, "+", "G3", "GENE3"),
+    ];
+
+    let location: Location = Location::parse("chr3:15000-35000").unwrap();
+
+    let raw: LoctogeneDb = test_db(&rows);
+
+    // without normalization chr3 does not match 3
+    assert!(raw.get_genes_within(&location, &Level::Gene).unwrap().is_empty());
+    assert!(!raw.has_chr("chr3").unwrap());
+
+    let db: LoctogeneDb = test_db(&rows)
+        .with_chr_normalization(true)
+        .unwrap();
+
+    let reference: LoctogeneDb = test_db(&prefixed);
+
+    let ids = |features: Vec<GenomicFeature>| -> Vec<String> {
+        features.into_iter().map(|f| f.gene_id).collect()
+    };
+
+    assert_eq!(
+        ids(db.get_genes_within(&location, &Level::Gene).unwrap()),
+        ids(reference.get_genes_within(&location, &Level::Gene).unwrap())
+    );
+
+    assert_eq!(
+        ids(db.get_closest_genes(&location, 2, Level::Gene).unwrap()),
+        vec!["G1", "G2"]
+    );
+
+    assert!(db.has_chr("chr3").unwrap());
+    assert!(db.has_chr("3").unwrap());
+
+    // and the other way round, 3 finds chr3
+    let stripped: LoctogeneDb = test_db(&prefixed)
+        .with_chr_normalization(true)
+        .unwrap();
+
+    let location: Location = Location::parse("3:15000-35000").unwrap();
+
+    assert_eq!(
+        ids(stripped.get_genes_within(&location, &Level::Gene).unwrap()),
+        vec!["G1", "G2"]
+    );
+}
+
+#[test]
+fn test_flanking_genes() {
+    let genesdb: LoctogeneDb = test_db(
+        &[
+            (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (1, "chr1", 30000, 40000, "-", "G2", "GENE2"),
+            (1, "chr1", 50000, 60000, "+", "G3", "GENE3"),
+            (1, "chr1", 51000, 52000, "+", "G4", "GENE4"),
+        ],
+    );
+
+    let flanking = |loc: &str| -> (Option<String>, Option<String>) {
+        let (before, after) = genesdb
+            .get_flanking_genes(&Location::parse(loc).unwrap(), Level::Gene)
+            .unwrap();
+
+        (before.map(|g| g.gene_id), after.map(|g| g.gene_id))
+    };
+
+    // both sides, measured from the TSS so G2 starts at 40000
+    assert_eq!(
+        flanking("chr1:44000-44000"),
+        (Some("G2".to_string()), Some("G3".to_string()))
+    );
+
+    // the two closest genes by distance are both after the location, but
+    // G2 is still reported before it
+    let closest: Vec<String> = genesdb
+        .get_closest_genes(&Location::parse("chr1:49000-49000").unwrap(), 2, Level::Gene)
+        .unwrap()
+        .into_iter()
+        .map(|g| g.gene_id)
+        .collect();
+
+    assert_eq!(closest, vec!["G3", "G4"]);
+    assert_eq!(
+        flanking("chr1:49000-49000"),
+        (Some("G2".to_string()), Some("G3".to_string()))
+    );
+
+    // inside G3 with no TSS further along the chromosome
+    assert_eq!(flanking("chr1:58000-58000"), (Some("G4".to_string()), None));
+
+    // before the first TSS of the contig
+    assert_eq!(flanking("chr1:100-200"), (None, Some("G1".to_string())));
+
+    assert_eq!(flanking("chr2:100-200"), (None, None));
+}
+
+#[test]
+fn test_closest_gene_overlap_frac() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (1, "chr1", 10001, 20000, "+", "G1", "GENE1"),
+                (2, "chr1", 10001, 20000, "+", "G1", "GENE1"),
+            ],
+        ),
+        TSSRegion::default(),
+        1,
+    );
+
+    let fracs = |loc: &str| -> (f64, f64) {
+        let annotation: GeneAnnotation =
+            annotatedb.annotate(&Location::parse(loc).unwrap()).unwrap();
+
+        let closest_gene: &ClosestGene = &annotation.closest_genes[0];
+
+        (closest_gene.location_overlap_frac, closest_gene.gene_overlap_frac)
+    };
+
+    // upstream in the promoter, so no overlap with the gene body
+    assert_eq!(fracs("chr1:9001-10000"), (0.0, 0.0));
+
+    // only the last quarter of the peak clips the gene
+    assert_eq!(fracs("chr1:9251-10250"), (0.25, 0.025));
+
+    // entirely inside the gene
+    assert_eq!(fracs("chr1:15001-16000"), (1.0, 0.1));
+
+    // covering the whole gene and more
+    assert_eq!(fracs("chr1:5001-25000"), (0.5, 1.0));
+}
+
+#[test]
+fn test_in_exons() {
+    let rows: [TestRow; 9] = [
+        (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+        (3, "chr1", 14000, 16000, "+", "G1", "GENE1"),
+        (2, "chr1", 12000, 30000, "-", "G2", "GENE2"),
+        (3, "chr1", 14500, 15500, "-", "G2", "GENE2"),
+        (3, "chr1", 25000, 26000, "-", "G2", "GENE2"),
+        (2, "chr1", 13000, 19000, "+", "G3", "GENE3"),
+        (3, "chr1", 13000, 13500, "+", "G3", "GENE3"),
+        (2, "chr1", 14000, 18000, "+", "G4", "GENE4"),
+        (3, "chr1", 17000, 18000, "+", "G4", "GENE4"),
+    ];
+
+    let loc: Location = Location::parse("chr1:15000-15000").unwrap();
+
+    let genesdb: LoctogeneDb = test_db(&rows);
+
+    let exons: HashMap<String, Vec<GenomicFeature>> = genesdb.in_exons(&loc).unwrap();
+
+    // one query finds the same exons as a query per gene
+    for id in ["G1", "G2", "G3", "G4"] {
+        assert_eq!(
+            exons.get(id).cloned().unwrap_or_default(),
+            genesdb.in_exon(&loc, id).unwrap()
+        );
+    }
+
+    let annotatedb: Annotate =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 0);
+
+    let annotation: GeneAnnotation = annotatedb.annotate(&loc).unwrap();
+
+    assert_eq!(annotation.gene_ids, "G4;G3;G1;G2");
+    assert_eq!(annotation.prom_labels, "promoter,intronic;intronic;exonic;exonic");
+
+    // the genes query and one exon query, rather than one per gene
+    assert_eq!(annotatedb.genesdb().query_count(), 2);
+
+    let annotatedb: Annotate =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 0);
+
+    let transcripts: Vec<TranscriptAnnotation> = annotatedb.annotate_transcripts(&loc).unwrap();
+
+    assert_eq!(
+        transcripts.iter().filter(|t| t.prom_label.contains(RegionClass::Exonic)).count(),
+        2
+    );
+    assert_eq!(annotatedb.genesdb().query_count(), 2);
+
+    let annotatedb: Annotate =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 0);
+
+    let features: Vec<GenomicFeature> = genesdb.get_genes_within(&loc, &Level::Transcript).unwrap();
+
+    let labels: Vec<(String, String)> = annotatedb.classify_features(&loc, &features).unwrap();
+
+    assert_eq!(labels.iter().filter(|(_, label)| label == "exonic").count(), 2);
+    assert_eq!(annotatedb.genesdb().query_count(), 1);
+
+    let annotatedb: Annotate =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 0);
+
+    annotatedb
+        .category_diff(
+            std::slice::from_ref(&loc),
+            &TSSRegion::default(),
+            &TSSRegion::new(100, 100).unwrap(),
+        )
+        .unwrap();
+
+    // the features query and one exon query per location
+    assert_eq!(annotatedb.genesdb().query_count(), 2);
+}
+
+#[test]
+fn test_utr_labels() {
+    let mut rows: Vec<TestRow> = Vec::new();
+
+    // the same gene structure on each strand, plus one with no CDS
+    for (chr, strand, id) in [("chr1", "+", "G1"), ("chr2", "-", "G2"), ("chr3", "+", "G3")] {
+        rows.push((2, chr, 10000, 20000, strand, id, id));
+        rows.push((3, chr, 10000, 12000, strand, id, id));
+        rows.push((3, chr, 18000, 20000, strand, id, id));
+
+        if id != "G3" {
+            rows.push((4, chr, 11000, 12000, strand, id, id));
+            rows.push((4, chr, 18000, 19000, strand, id, id));
+        }
+    }
+
+    // an exon with no CDS between the coding exons, as one transcript of
+    // G1 might have
+    rows.push((3, "chr1", 15000, 15500, "+", "G1", "G1"));
+
+    let annotatedb: Annotate =
+        Annotate::new(test_db(&rows), TSSRegion::new(100, 100).unwrap(), 0)
+            .with_coding_level(Some(4));
+
+    let label = |loc: &str| -> String {
+        annotatedb.annotate(&Location::parse(loc).unwrap()).unwrap().prom_labels
+    };
+
+    assert_eq!(label("chr1:10500-10500"), FIVE_PRIME_UTR);
+    assert_eq!(label("chr1:11500-11500"), CDS);
+    assert_eq!(label("chr1:19500-19500"), THREE_PRIME_UTR);
+    assert_eq!(label("chr1:14000-14000"), INTRONIC);
+
+    // the midpoint is in the coding span, but the location only overlaps
+    // the non-coding exon
+    assert_eq!(label("chr1:15400-16600"), EXONIC);
+    assert_eq!(label("chr1:15200-15200"), EXONIC);
+
+    // overlapping a CDS is CDS wherever the midpoint is
+    assert_eq!(label("chr1:11800-14000"), CDS);
+
+    // reading along the - strand the UTRs swap ends
+    assert_eq!(label("chr2:10500-10500"), THREE_PRIME_UTR);
+    assert_eq!(label("chr2:11500-11500"), CDS);
+    assert_eq!(label("chr2:19500-19500"), FIVE_PRIME_UTR);
+
+    // no coding bounds to compare with
+    assert_eq!(label("chr3:10500-10500"), EXONIC);
+
+    let annotatedb: Annotate =
+        Annotate::new(test_db(&rows), TSSRegion::new(100, 100).unwrap(), 0);
+
+    let annotation: GeneAnnotation =
+        annotatedb.annotate(&Location::parse("chr1:11500-11500").unwrap()).unwrap();
+
+    assert_eq!(annotation.prom_labels, EXONIC);
+    assert_eq!(annotation.genes[0].exon_class, Some(RegionClass::Exonic));
+}
+
+#[test]
+fn test_gene_by_symbol_and_id() {
+    let genesdb: LoctogeneDb = test_db(
+        &[
+            (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (2, "chr1", 12000, 20000, "+", "G1", "GENE1"),
+            (1, "chrY", 5000, 6000, "-", "G2_Y", "PAR1"),
+            (1, "chrX", 5000, 6000, "-", "G2", "PAR1"),
+        ],
+    );
+
+    let ids = |features: Vec<GenomicFeature>| -> Vec<(String, u32)> {
+        features.into_iter().map(|f| (f.chr, f.start)).collect()
+    };
+
+    assert_eq!(
+        ids(genesdb.get_gene_by_symbol("GENE1", Level::Transcript).unwrap()),
+        vec![("chr1".to_string(), 10000), ("chr1".to_string(), 12000)]
+    );
+
+    assert_eq!(
+        genesdb.get_gene_by_symbol("gene1", Level::Gene).unwrap()[0].gene_symbol,
+        "GENE1"
+    );
+
+    // the same symbol on two chromosomes
+    assert_eq!(
+        ids(genesdb.get_gene_by_symbol("par1", Level::Gene).unwrap()),
+        vec![("chrX".to_string(), 5000), ("chrY".to_string(), 5000)]
+    );
+
+    assert!(matches!(
+        genesdb.get_gene_by_symbol("GENE3", Level::Gene),
+        Err(GenesError::NotFound(_))
+    ));
+
+    assert_eq!(genesdb.get_gene_by_id("G1", Level::Transcript).unwrap().len(), 2);
+    assert_eq!(genesdb.get_gene_by_id("G2_Y", Level::Gene).unwrap()[0].chr, "chrY");
+
+    // an unknown id is reported as not found rather than as a failed query
+    match genesdb.get_gene_by_id("G3", Level::Gene) {
+        Err(GenesError::NotFound(message)) => assert!(message.contains("G3")),
+        _ => panic!("expected G3 to be not found"),
+    }
+
+    // known, but not at that level
+    assert!(matches!(
+        genesdb.get_gene_by_id("G2", Level::Exon),
+        Err(GenesError::NotFound(_))
+    ));
+}
+
+#[test]
+fn test_annotate_builder() {
+    let loc: Location = Location::parse("chr1:15000-15000").unwrap();
+
+    // defaults match new with the default region and 5 closest genes
+    let built: GeneAnnotation = AnnotateBuilder::new()
+        .build(test_db_with(&BIOTYPE_ROWS, BIOTYPE_SQL))
+        .unwrap()
+        .annotate(&loc)
+        .unwrap();
+
+    let annotation: GeneAnnotation = Annotate::new(
+        test_db_with(&BIOTYPE_ROWS, BIOTYPE_SQL),
+        TSSRegion::default(),
+        5,
+    )
+    .annotate(&loc)
+    .unwrap();
+
+    assert_eq!(built, annotation);
+    assert_eq!(built.closest_genes.len(), 3);
+
+    let built: GeneAnnotation = AnnotateBuilder::new()
+        .tss_region(TSSRegion::symmetric(500).unwrap())
+        .closest_n(1)
+        .level(Level::Gene)
+        .biotypes(Some(vec!["protein_coding".to_string(), "lincRNA".to_string()]))
+        .build(test_db_with(&BIOTYPE_ROWS, BIOTYPE_SQL))
+        .unwrap()
+        .annotate(&loc)
+        .unwrap();
+
+    assert_eq!(built.gene_ids, "G1;G2");
+    assert_eq!(built.prom_labels, "intronic;intronic");
+    assert_eq!(built.closest_genes.len(), 1);
+}
+
+#[test]
+fn test_tss_region_from_kb() {
+    assert_eq!(TSSRegion::from_kb(2.0, 1.0).unwrap(), TSSRegion::new(2000, 1000).unwrap());
+    assert_eq!(TSSRegion::from_kb(2.0, 1.0).unwrap(), TSSRegion::default());
+
+    // rounded to the nearest bp
+    assert_eq!(TSSRegion::from_kb(0.0004, 1.2345).unwrap(), TSSRegion::new(0, 1235).unwrap());
+
+    assert!(TSSRegion::from_kb(-1.0, 1.0).is_err());
+    assert!(TSSRegion::from_kb(f64::NAN, 1.0).is_err());
+    assert!(TSSRegion::from_kb(2000.0, 1.0).is_err());
+
+    assert_eq!(
+        TSSRegion::new(MAX_TSS_OFFSET, 0).unwrap().offset_5p(),
+        MAX_TSS_OFFSET
+    );
+    assert!(TSSRegion::new(0, MAX_TSS_OFFSET + 1).is_err());
+}
+
+#[test]
+fn test_feature_counts() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (2, "chr1", 12000, 18000, "+", "G1", "GENE1"),
+                // starts too far from the location to be within range
+                (2, "chr1", 18000, 30000, "+", "G1", "GENE1"),
+                // in range through its promoter but does not overlap
+                (2, "chr1", 16000, 30000, "+", "G1", "GENE1"),
+                (2, "chr1", 13000, 40000, "-", "G2", "GENE2"),
+                (2, "chr1", 16500, 30000, "+", "G3", "GENE3"),
+            ],
+        ),
+        TSSRegion::default(),
+        0,
+    );
+
+    let annotation: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:15000-15000").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.gene_ids, "G1;G3;G2");
+    assert_eq!(annotation.feature_counts, "2;0;1");
+    assert_eq!(annotation.genes[0].feature_count, 2);
+    // only a promoter hit
+    assert_eq!(annotation.genes[1].feature_count, 0);
+    assert_eq!(annotation.genes[2].feature_count, 1);
+
+    let annotation: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr1:100-200").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.feature_counts, NA);
+}
+
+#[test]
+fn test_nearest_distance() {
+    let rows: [TestRow; 2] = [
+        (1, "chr1", 100000, 120000, "+", "G1", "GENE1"),
+        (2, "chr1", 100000, 120000, "+", "G1", "GENE1"),
+    ];
+
+    let loc: Location = Location::parse("chr1:60000-60000").unwrap();
+
+    let annotation: GeneAnnotation =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 1)
+            .annotate(&loc)
+            .unwrap();
+
+    assert_eq!(annotation.gene_ids, NA);
+    assert!(!annotation.is_genic);
+
+    // 40 kb upstream of the nearest gene
+    assert_eq!(annotation.nearest_distance, Some(-40000));
+    assert_eq!(annotation.closest_genes[0].prom_label, INTERGENIC);
+
+    // found even when no closest genes are reported
+    let annotation: GeneAnnotation =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 0)
+            .annotate(&loc)
+            .unwrap();
+
+    assert!(annotation.closest_genes.is_empty());
+    assert_eq!(annotation.nearest_distance, Some(-40000));
+
+    let annotation: GeneAnnotation =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 1)
+            .annotate(&Location::parse("chr1:110000-110000").unwrap())
+            .unwrap();
+
+    assert_eq!(annotation.nearest_distance, None);
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn test_separators() {
+    let rows: [TestRow; 6] = [
+        (1, "chr1", 14000, 20000, "+", "G1", "GENE1"),
+        (2, "chr1", 14000, 20000, "+", "G1", "GENE1"),
+        (3, "chr1", 14000, 16000, "+", "G1", "GENE1"),
+        (1, "chr1", 12000, 30000, "-", "G2", "GENE2"),
+        (2, "chr1", 12000, 30000, "-", "G2", "GENE2"),
+        (3, "chr1", 14500, 15500, "-", "G2", "GENE2"),
+    ];
+
+    let locations: Vec<Location> = vec![Location::parse("chr1:15000-15000").unwrap()];
+
+    let table = |annotatedb: Annotate| -> Vec<String> {
+        annotatedb
+            .make_gene_table(&locations, 1, &TSSRegion::default())
+            .unwrap()
+            .lines()
+            .nth(1)
+            .unwrap()
+            .split('\t')
+            .map(|cell| cell.to_owned())
+            .collect()
+    };
+
+    let row: Vec<String> = table(Annotate::new(
+        test_db(&rows),
+        TSSRegion::default(),
+        1,
+    ));
+
+    assert_eq!(row[1..5], ["G1;G2", "GENE1;GENE2", "promoter,exonic;exonic", "1000;15000"]);
+    assert_eq!(row[7], "promoter,exonic");
+
+    let row: Vec<String> = table(
+        Annotate::new(test_db(&rows), TSSRegion::default(), 1)
+            .with_separators(Separators::new("|", "/")),
+    );
+
+    assert_eq!(row[1..5], ["G1|G2", "GENE1|GENE2", "promoter/exonic|exonic", "1000|15000"]);
+    assert_eq!(row[7], "promoter/exonic");
+}
+
+#[cfg(feature = "table")]
+#[test]
+fn test_table_delimiter() {
+    let rows: [TestRow; 2] = [
+        (1, "chr1", 14000, 20000, "+", "G1", "GENE,1"),
+        (2, "chr1", 14000, 20000, "+", "G1", "GENE,1"),
+    ];
+
+    let locations: Vec<Location> = vec![Location::parse("chr1:15000-15000").unwrap()];
+
+    let tsv: String =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 1)
+            .make_gene_table(&locations, 1, &TSSRegion::default())
+            .unwrap();
+
+    let line: &str = tsv.lines().nth(1).unwrap();
+
+    assert!(line.starts_with("chr1:15000-15000\tG1\tGENE,1\t"));
+
+    let csv: String =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 1)
+            .with_table_delimiter(b',')
+            .make_gene_table(&locations, 1, &TSSRegion::default())
+            .unwrap();
+
+    let line: &str = csv.lines().nth(1).unwrap();
+
+    // labels with several parts have commas of their own
+    assert!(line.starts_with("chr1:15000-15000,G1,\"GENE,1\",\"promoter,intronic\",1000,"));
+    assert!(csv.lines().next().unwrap().starts_with("Location,ID,Gene Symbol,"));
+
+    // the quoted symbol reads back as one cell
+    let record: csv::StringRecord = csv::Reader::from_reader(csv.as_bytes())
+        .records()
+        .next()
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(&record[2], "GENE,1");
+    assert_eq!(record.len(), tsv.lines().next().unwrap().split('\t').count());
+}
+
+#[test]
+fn test_nearest_exon_distance() {
+    let genesdb: LoctogeneDb = test_db(
+        &[
+            (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+            (3, "chr1", 10000, 11000, "+", "G1", "GENE1"),
+            (3, "chr1", 15000, 16000, "+", "G1", "GENE1"),
+            (3, "chr1", 19000, 20000, "+", "G1", "GENE1"),
+            (2, "chr2", 10000, 20000, "-", "G2", "GENE2"),
+            (3, "chr2", 10000, 11000, "-", "G2", "GENE2"),
+            (3, "chr2", 19000, 20000, "-", "G2", "GENE2"),
+            // no exon records
+            (2, "chr3", 10000, 20000, "+", "G3", "GENE3"),
+        ],
+    );
+
+    let dist = |loc: &str, gene_id: &str| -> Option<i32> {
+        genesdb
+            .nearest_exon_distance(&Location::parse(loc).unwrap(), gene_id)
+            .unwrap()
+    };
+
+    // inside an exon
+    assert_eq!(dist("chr1:15500-15500", "G1"), Some(0));
+
+    // in an intron, nearer the end of the first exon
+    assert_eq!(dist("chr1:12000-12000", "G1"), Some(1000));
+    assert_eq!(dist("chr1:13500-13500", "G1"), Some(1500));
+
+    // upstream of the first exon on each strand
+    assert_eq!(dist("chr1:9000-9000", "G1"), Some(-1000));
+    assert_eq!(dist("chr2:22000-22000", "G2"), Some(-2000));
+
+    // past the last exon of a - strand gene is downstream
+    assert_eq!(dist("chr2:9000-9000", "G2"), Some(1000));
+
+    assert_eq!(dist("chr3:15000-15000", "G3"), None);
+    assert_eq!(dist("chr2:15000-15000", "G1"), None);
+}
+
+#[test]
+fn test_antisense() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (2, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (2, "chr1", 12000, 30000, "-", "G2", "GENE2"),
+            ],
+        ),
+        TSSRegion::default(),
+        0,
+    );
+
+    let loc: Location = Location::parse("chr1:15000-15000").unwrap();
+
+    let annotation: GeneAnnotation = annotatedb.annotate_stranded(&loc, Strand::Plus).unwrap();
+
+    assert_eq!(annotation.gene_ids, "G1;G2");
+    assert_eq!(annotation.antisense, Some("false;true".to_string()));
+    assert_eq!(annotation.genes[0].antisense, Some(false));
+    assert_eq!(annotation.genes[1].antisense, Some(true));
+
+    let annotation: GeneAnnotation = annotatedb.annotate_stranded(&loc, Strand::Neg).unwrap();
+
+    assert_eq!(annotation.antisense, Some("true;false".to_string()));
+
+    // no strand, no flag
+    let annotation: GeneAnnotation = annotatedb.annotate(&loc).unwrap();
+
+    assert_eq!(annotation.antisense, None);
+    assert_eq!(annotation.genes[0].antisense, None);
+    assert!(!serde_json::to_string(&annotation).unwrap().contains("\"antisense\":\""));
+}
+
+#[test]
+fn test_annotation_display() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db(
+            &[
+                (1, "chr1", 10000, 20000, "+", "G1", "GENE1"),
+                (2, "chr1", 14880, 20000, "+", "G1", "GENE1"),
+                (1, "chr1", 12000, 30000, "-", "G2", "GENE2"),
+                (2, "chr1", 12000, 19300, "-", "G2", "GENE2"),
+            ],
+        ),
+        TSSRegion::default(),
+        5,
+    );
+
+    let loc: Location = Location::parse("chr1:15000-15000").unwrap();
+
+    let annotation: GeneAnnotation = annotatedb.annotate(&loc).unwrap();
+
+    assert_eq!(
+        annotation.to_string(),
+        "G1;G2  GENE1;GENE2  promoter,intronic;intronic  120;4300  closest=2"
+    );
+
+    let annotation: GeneAnnotation = annotatedb
+        .annotate(&Location::parse("chr2:15000-15000").unwrap())
+        .unwrap();
+
+    assert_eq!(annotation.to_string(), "n/a  n/a  n/a  n/a  closest=0");
+}
+
+#[test]
+fn test_annotation_cache() {
+    let annotatedb: Annotate = Annotate::new(
+        test_db_with(&BIOTYPE_ROWS, BIOTYPE_SQL),
+        TSSRegion::default(),
+        5,
+    )
+    .with_cache(Some(2));
+
+    let a: Location = Location::parse("chr1:15000-15000").unwrap();
+    let b: Location = Location::parse("chr1:16000-16000").unwrap();
+    let c: Location = Location::parse("chr1:17000-17000").unwrap();
+
+    let first: GeneAnnotation = annotatedb.annotate(&a).unwrap();
+
+    let queries: usize = annotatedb.genesdb().query_count();
+
+    assert!(queries > 0);
+
+    // a hit issues no queries and gives the same annotation
+    let second: GeneAnnotation = annotatedb.annotate(&a).unwrap();
+
+    assert_eq!(annotatedb.genesdb().query_count(), queries);
+    assert_eq!(second.to_string(), first.to_string());
+    assert_eq!(second.genes, first.genes);
+
+    // using a again after b leaves b the least recently used, so c pushes
+    // b out rather than a
+    annotatedb.annotate(&b).unwrap();
+    annotatedb.annotate(&a).unwrap();
+    annotatedb.annotate(&c).unwrap();
+
+    let queries: usize = annotatedb.genesdb().query_count();
+
+    annotatedb.annotate(&a).unwrap();
+
+    assert_eq!(annotatedb.genesdb().query_count(), queries);
+
+    annotatedb.annotate(&b).unwrap();
+
+    assert!(annotatedb.genesdb().query_count() > queries);
+}
+
+#[test]
+fn test_closest_genes_within() {
+    let rows: [TestRow; 4] = [
+        (1, "chr1", 15000, 20000, "+", "G1", "GENE1"),
+        (1, "chr1", 8000, 14000, "-", "G2", "GENE2"),
+        (1, "chr1", 21000, 30000, "+", "G3", "GENE3"),
+        (1, "chr1", 60000, 70000, "+", "G4", "GENE4"),
+    ];
+
+    let loc: Location = Location::parse("chr1:15000-15000").unwrap();
+
+    let ids = |features: Vec<GenomicFeature>| -> Vec<String> {
+        features.into_iter().map(|f| f.gene_id).collect()
+    };
+
+    for genesdb in [
+        test_db(&rows),
+        test_db(&rows).with_chr_cache(true),
+    ] {
+        // unbounded is the same as get_closest_genes
+        assert_eq!(
+            ids(genesdb.get_closest_genes_within(&loc, 3, Level::Gene, None, None).unwrap()),
+            ids(genesdb.get_closest_genes(&loc, 3, Level::Gene).unwrap())
+        );
+
+        assert_eq!(
+            ids(genesdb.get_closest_genes(&loc, 3, Level::Gene).unwrap()),
+            vec!["G1", "G2", "G3"]
+        );
+
+        // G1 starts exactly at the location
+        assert_eq!(
+            ids(genesdb.get_closest_genes_within(&loc, 3, Level::Gene, Some(1), None).unwrap()),
+            vec!["G2", "G3", "G4"]
+        );
+
+        // nothing further than 5 kb
+        assert_eq!(
+            ids(genesdb.get_closest_genes_within(&loc, 3, Level::Gene, None, Some(5000)).unwrap()),
+            vec!["G1", "G2"]
+        );
+
+        assert_eq!(
+            ids(genesdb
+                .get_closest_genes_within(&loc, 3, Level::Gene, Some(1), Some(5000))
+                .unwrap()),
+            vec!["G2"]
+        );
+    }
+}
+
+#[test]
+fn test_closest_genes_dedup() {
+    let rows: [TestRow; 6] = [
+        (1, "chr1", 15000, 20000, "+", "G1", "GENE1"),
+        (1, "chr1", 15100, 20000, "+", "G1", "GENE1"),
+        (1, "chr1", 15200, 20000, "+", "G1", "GENE1"),
+        (1, "chr1", 15300, 20000, "+", "G1", "GENE1"),
+        (1, "chr1", 8000, 14000, "-", "G2", "GENE2"),
+        (1, "chr1", 21000, 30000, "+", "G3", "GENE3"),
+    ];
+
+    let loc: Location = Location::parse("chr1:15000-15000").unwrap();
+
+    for genesdb in [
+        test_db(&rows),
+        test_db(&rows).with_chr_cache(true),
+        test_db(&rows).with_overlap_priority(true),
+    ] {
+        let closest: Vec<GenomicFeature> = genesdb.get_closest_genes(&loc, 3, Level::Gene).unwrap();
+
+        let ids: Vec<&str> = closest.iter().map(|f| f.gene_id.as_str()).collect();
+
+        // the closest row of G1 is kept and the rest make way for other genes
+        assert_eq!(ids, vec!["G1", "G2", "G3"]);
+        assert_eq!(closest[0].start, 15000);
+    }
+}
+
+#[test]
+fn test_annotation_eq() {
+    let annotate: Annotate = Annotate::new(
+        test_db_with(&BIOTYPE_ROWS, BIOTYPE_SQL),
+        TSSRegion::default(),
+        5,
+    );
+
+    let loc: Location = Location::parse("chr1:15000-15000").unwrap();
+
+    let annotation: GeneAnnotation = annotate.annotate(&loc).unwrap();
+
+    assert_eq!(annotate.annotate(&loc).unwrap(), annotation);
+    assert_eq!(annotation.clone(), annotation);
+    assert_eq!(annotation.closest_genes[0].clone(), annotation.closest_genes[0]);
+
+    // a different location gives different genes
+    let other: GeneAnnotation = annotate
+        .annotate(&Location::parse("chr1:100000-100000").unwrap())
+        .unwrap();
+
+    assert_ne!(other, annotation);
+}
+
+#[test]
+fn test_annotate_iter() {
+    let annotate: Annotate = Annotate::new(
+        test_db_with(&BIOTYPE_ROWS, BIOTYPE_SQL),
+        TSSRegion::default(),
+        5,
+    );
+
+    let locations: Vec<Location> = (0..10)
+        .map(|i| Location::new("chr1", i * 5000 + 1, (i + 1) * 5000).unwrap())
+        .collect();
+
+    // a tiling generated on the fly rather than collected first
+    let tiles = (0..10).map(|i| Location::new("chr1", i * 5000 + 1, (i + 1) * 5000).unwrap());
+
+    let annotations: Vec<GeneAnnotation> = annotate
+        .annotate_iter(tiles)
+        .collect::<Result<Vec<GeneAnnotation>, GenesError>>()
+        .unwrap();
+
+    assert_eq!(annotations.len(), 10);
+    assert_eq!(annotations, annotate.annotate_many(&locations).unwrap());
+
+    assert_eq!(annotate.annotate_iter(Vec::new()).count(), 0);
+}
+
+#[test]
+fn test_annotate_with_tss() {
+    let annotate: Annotate =
+        Annotate::new(test_db(&GENE_20KB_ROWS), TSSRegion::default(), 5);
+
+    // 3kb into the gene
+    let loc: Location = Location::parse("chr1:23000-23000").unwrap();
+
+    let narrow: GeneAnnotation = annotate
+        .annotate_with_tss(&loc, &TSSRegion::symmetric(1000).unwrap())
+        .unwrap();
+    let wide: GeneAnnotation = annotate
+        .annotate_with_tss(&loc, &TSSRegion::symmetric(5000).unwrap())
+        .unwrap();
+
+    assert_eq!(narrow.gene_ids, "G1");
+    assert_eq!(narrow.prom_labels, "intronic");
+    assert_eq!(wide.gene_ids, "G1");
+    assert_eq!(wide.prom_labels, "promoter,intronic");
+
+    // the annotator's own region is unchanged
+    assert_eq!(
+        annotate.annotate(&loc).unwrap(),
+        annotate.annotate_with_tss(&loc, &TSSRegion::default()).unwrap()
+    );
+}
+
+#[test]
+fn test_canonical_only() {
+    let rows: [TestRow; 6] = [
+        (1, "chr1", 10000, 30000, "+", "G1", "GENE1"),
+        (2, "chr1", 10000, 30000, "+", "G1", "GENE1"),
+        (2, "chr1", 14000, 30000, "+", "G1", "GENE1"),
+        (1, "chr1", 12000, 40000, "+", "G2", "GENE2"),
+        (2, "chr1", 12000, 40000, "+", "G2", "GENE2"),
+        (2, "chr1", 14200, 40000, "+", "G2", "GENE2"),
+    ];
+
+    let loc: Location = Location::parse("chr1:14500-14500").unwrap();
+
+    // by default the nearest transcript of each gene is reported
+    let annotation: GeneAnnotation =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 0)
+            .annotate(&loc)
+            .unwrap();
+
+    assert_eq!(annotation.tss_dists, "300;500");
+
+    // without a canonical column, the longest transcript is canonical
+    let annotation: GeneAnnotation =
+        Annotate::new(test_db(&rows), TSSRegion::default(), 0)
+            .with_canonical_only(true)
+            .unwrap()
+            .annotate(&loc)
+            .unwrap();
+
+    assert_eq!(annotation.tss_dists, "2500;4500");
+    assert_eq!(annotation.prom_labels, "intronic;intronic");
+
+    // the flagged transcript is used in preference, and a gene with no
+    // flagged transcript keeps its nearest
+    let annotation: GeneAnnotation = Annotate::new(
+        test_db_with(
+            &rows,
+            "ALTER TABLE genes ADD COLUMN canonical INTEGER; UPDATE genes SET canonical = 1 WHERE level = 2 AND gene_id = 'G1' AND start = 14000;",
+        ),
+        TSSRegion::default(),
+        0,
+    )
+    .with_canonical_only(true)
+    .unwrap()
+    .annotate(&loc)
+    .unwrap();
+
+    assert_eq!(annotation.tss_dists, "300;500");
+    assert_eq!(annotation.prom_labels, "promoter,intronic;promoter,intronic");
+}
+
+#[test]
+fn test_canonical_out_of_range() {
+    let rows: [TestRow; 7] = [
+        (1, "chr1", 10000, 80000, "+", "G1", "GENE1"),
+        (2, "chr1", 10000, 30000, "+", "G1", "GENE1"),
+        (2, "chr1", 14000, 30000, "+", "G1", "GENE1"),
+        // the canonical isoform starts far from the location
+        (2, "chr1", 50000, 80000, "+", "G1", "GENE1"),
+        (1, "chr1", 12000, 40000, "+", "G2", "GENE2"),
+        (2, "chr1", 12000, 40000, "+", "G2", "GENE2"),
+        (2, "chr1", 14200, 40000, "+", "G2", "GENE2"),
+    ];
+
+    let loc: Location = Location::parse("chr1:14500-14500").unwrap();
+
+    let nearest: Annotate = Annotate::new(test_db(&rows), TSSRegion::default(), 0);
+
+    let canonical: Annotate = Annotate::new(
+        test_db_with(
+            &rows,
+            "ALTER TABLE genes ADD COLUMN canonical INTEGER; UPDATE genes SET canonical = 1 WHERE level = 2 AND start IN (50000, 12000);",
+        ),
+        TSSRegion::default(),
+        0,
+    )
+    .with_canonical_only(true)
+    .unwrap();
+
+    // checking for the canonical column is not part of annotating
+    let setup: usize = canonical.genesdb().query_count();
+
+    let annotation: GeneAnnotation = canonical.annotate(&loc).unwrap();
+
+    // G1 falls back to its nearest transcript rather than being dropped
+    assert_eq!(annotation.gene_ids, "G1;G2");
+    assert_eq!(annotation.tss_dists, "500;2500");
+
+    nearest.annotate(&loc).unwrap();
+
+    // one query finds the canonical transcripts of both genes
+    assert_eq!(
+        canonical.genesdb().query_count() - setup,
+        nearest.genesdb().query_count() + 1
+    );
+}